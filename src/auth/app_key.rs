@@ -81,7 +81,7 @@ impl AppKeyAuthProvider {
                     }
                 }
             } else {
-                event!(Level::DEBUG, "{:?}", query_pairs);
+                event!(Level::DEBUG, "bad query string {}", crate::redact::query(query));
             }
         }
 
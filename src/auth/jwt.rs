@@ -36,7 +36,7 @@ impl AuthProvider for JWTAuthProvider {
         // check cache
         let mut cache = self.token_cache.lock().unwrap();
         if let Some(cached_key) = cache.get(&token) {
-            event!(Level::DEBUG, "cached data {} {}", cached_key, client.app_key);
+            event!(Level::DEBUG, "cached token of {}", client.client_id);
             if cached_key.eq(&client.app_key) {
                 return Ok((head, AuthResult {client_id: client.client_id.clone(), sla: sla.clone()}))
             } else {
@@ -7,6 +7,15 @@ use crate::auth::{ServiceAuthInfo, AuthProvider, AuthRequest, AppKeyAuthProvider
 use super::authenticator::{AuthResult, AuthResponse, GatewayAuthError};
 
 
+lazy_static::lazy_static! {
+    static ref AUTH_FAILURE_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_auth_failures_total",
+        "Number of requests failed authentication, by reason.",
+        &["reason"]
+    ).unwrap();
+}
+
+
 pub struct AuthService {
     conf_receiver: broadcast::Receiver<ConfigUpdate>,
     auth_receiver: mpsc::Receiver<AuthRequest>,
@@ -47,7 +56,11 @@ impl AuthService {
                 auth_request = self.auth_receiver.recv() => {
                     if let Some(request) = auth_request {
                         let (head, result_ch) = request.into_parts();
-                        let _ = result_ch.send(self.auth_handler(head));
+                        let result = self.auth_handler(head);
+                        if let Err(e) = &result {
+                            AUTH_FAILURE_COUNTER.with_label_values(&[&format!("{:?}", e)]).inc();
+                        }
+                        let _ = result_ch.send(result);
                     }
                 },
             }
@@ -91,7 +104,12 @@ impl AuthService {
             AuthSetting::None(_) => self.authenticators.get("noauth").unwrap(),
         };
 
-        let (head, auth_result) = provider.identify_client(head, service_id)?;
+        // CORS preflight carries no credentials, pass it to CORS middleware anonymously
+        let (head, auth_result) = if Self::is_cors_preflight(&head, service) {
+            (head, AuthResult { client_id: String::from(""), sla: String::from("") })
+        } else {
+            provider.identify_client(head, service_id)?
+        };
 
         let (sf, cf) = Self::get_filters(&auth_result, service)?;
         let resp = AuthResponse {
@@ -116,6 +134,13 @@ impl AuthService {
         }
     }
 
+    fn is_cors_preflight(head: &Parts, service: &ServiceAuthInfo) -> bool {
+        let has_cors = service.filters.iter().any(|f| matches!(f, FilterSetting::CORS(_)));
+        has_cors && head.method == hyper::Method::OPTIONS
+            && head.headers.contains_key(hyper::header::ORIGIN)
+            && head.headers.contains_key(hyper::header::ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
     fn extract_service_path(path: &str) -> Result<String, GatewayAuthError> {
         let path = path.strip_prefix("/").unwrap_or(path);
         let (service_path, _path) = match path.find("/") {
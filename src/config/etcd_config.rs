@@ -79,7 +79,7 @@ fn extract_event(key: &str, val: &str, is_delete: bool) -> Option<ConfigUpdate>
             if entity_type.eq("services") {
                 let data = serde_json::from_str::<ServiceInfo>(val);
                 if let Ok(conf) = data {
-                    return Some(ConfigUpdate::ServiceUpdate(conf));
+                    return Some(ConfigUpdate::ServiceUpdate(Box::new(conf)));
                 }
             } else if entity_type.eq("clients") {
                 let data = serde_json::from_str::<ClientInfo>(val);
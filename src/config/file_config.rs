@@ -16,7 +16,7 @@ pub async fn watch_config(config_file: String, sender: mpsc::Sender<ConfigUpdate
     let content = tokio::fs::read_to_string(&config_file).await.expect("Failed to read config file");
     let mut config = serde_yaml::from_str::<ServiceConfig>(&content).expect("Failed to parse config file");
     for s in config.services.iter() {
-        let _ = sender.send(ConfigUpdate::ServiceUpdate(s.clone())).await;
+        let _ = sender.send(ConfigUpdate::ServiceUpdate(Box::new(s.clone()))).await;
     }
     for c in config.clients.iter() {
         let _ = sender.send(ConfigUpdate::ClientUpdate(c.clone())).await;
@@ -50,7 +50,7 @@ fn config_diff(old: &ServiceConfig, new: &ServiceConfig) -> Vec<ConfigUpdate> {
     let mut exist_service: HashMap<String, bool> = HashMap::new();
     for s in new.services.iter() {
         exist_service.insert(s.service_id.clone(), true);
-        result.push(ConfigUpdate::ServiceUpdate(s.clone()));
+        result.push(ConfigUpdate::ServiceUpdate(Box::new(s.clone())));
     }
     for os in old.services.iter() {
         if let Some(_) = exist_service.get(&os.service_id) {
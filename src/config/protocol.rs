@@ -1,16 +1,18 @@
 use std::collections::HashMap;
 
 use serde::{Serialize, Deserialize};
+use std::net::IpAddr;
 
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag="type", content="data")]
 pub enum ConfigUpdate {
-    ServiceUpdate(ServiceInfo),
+    ServiceUpdate(Box<ServiceInfo>),  // boxed, updates are cloned into every receiver of the config channel
     ServiceRemove(String),
     ClientUpdate(ClientInfo),
     ClientRemove(String),
     ConfigReady(bool),
+    CachePurge(CachePurge),
 }
 
 
@@ -21,7 +23,7 @@ pub struct GatewayConfig {
 }
 
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct ClientInfo {
     pub client_id: String,
     pub app_key: String,
@@ -31,6 +33,20 @@ pub struct ClientInfo {
 }
 
 
+// app key is a credential, keep it out of logs
+impl std::fmt::Debug for ClientInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientInfo")
+            .field("client_id", &self.client_id)
+            .field("app_key", &crate::redact::REDACTED)
+            .field("pub_key", &self.pub_key)
+            .field("ip_whitelist", &self.ip_whitelist)
+            .field("services", &self.services)
+            .finish()
+    }
+}
+
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ServiceInfo {
     pub service_id: String,
@@ -39,9 +55,74 @@ pub struct ServiceInfo {
     pub auth: AuthSetting,
     pub timeout: u32,
     pub load_balance: String,
+    #[serde(default)]
+    pub hash_key: Option<HashKeySetting>,  // key of "hash" load balance, x-lb-hash header if not set
+    #[serde(default)]
+    pub sticky: Option<StickySetting>,  // affinity cookie of "sticky" load balance
     pub filters: Vec<FilterSetting>,
     pub sla: Vec<ServiceLevel>,
     pub upstreams: Vec<Upstream>,
+    #[serde(default)]
+    pub mirror: Option<MirrorSetting>,  // shadow upstream receiving a copy of live traffic
+    #[serde(default)]
+    pub outlier_detection: Option<OutlierSetting>,  // eject misbehaving upstreams from balancers
+    #[serde(default)]
+    pub slow_start: Option<SlowStartSetting>,  // ramp up weight of added and recovered upstreams
+    #[serde(default)]
+    pub failover: Option<FailoverSetting>,  // when upstreams of lower priority take traffic
+    #[serde(default)]
+    pub hedge: Option<HedgeSetting>,  // send slow GET requests to a second upstream
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HedgeSetting {
+    #[serde(default)]
+    pub delay: u64,  // milliseconds before the copy is sent, 0 to use latency percentile
+    #[serde(default)]
+    pub percentile: f64,  // percentile of recent response latencies as delay, 95 if 0
+    #[serde(default)]
+    pub budget_percent: u64,  // hedged requests percent of eligible requests, 10 if 0
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FailoverSetting {
+    #[serde(default)]
+    pub healthy_percent: u64,  // healthy weight percent of a priority level to keep its traffic, 70 if 0
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SlowStartSetting {
+    pub window: u64,  // seconds to reach full weight
+    #[serde(default)]
+    pub curve: String,  // "linear" or "exponential", linear if empty
+    #[serde(default)]
+    pub min_weight_percent: u64,  // weight percent at start of window, 10 if 0
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OutlierSetting {
+    #[serde(default)]
+    pub consecutive_5xx: u64,  // 5xx responses and upstream errors in a row, 0 to disable
+    #[serde(default)]
+    pub consecutive_gateway_failure: u64,  // 502, 503, 504 and upstream errors in a row, 0 to disable
+    #[serde(default)]
+    pub success_rate_stdev_factor: f64,  // eject below mean - factor * stdev of success rates, 0 to disable
+    #[serde(default)]
+    pub success_rate_minimum_hosts: u64,  // 5 if 0
+    #[serde(default)]
+    pub success_rate_request_volume: u64,  // requests of an upstream in an interval to be analyzed, 100 if 0
+    #[serde(default)]
+    pub interval: u64,  // success rate analysis interval in seconds, 10 if 0
+    #[serde(default)]
+    pub base_ejection_time: u64,  // seconds, multiplied by times ejected, 30 if 0
+    #[serde(default)]
+    pub max_ejection_time: u64,  // seconds, 300 if 0
+    #[serde(default)]
+    pub max_ejection_percent: u64,  // 10 if 0, one upstream can always be ejected
 }
 
 
@@ -59,9 +140,93 @@ pub struct Upstream {
     pub max_conn: u64,
     pub weight: u32,
     pub version: String,
+    #[serde(default)]
+    pub priority: u32,  // 0 is highest, lower priorities only take traffic when higher ones are unhealthy
     pub error_threshold: u64,
     pub error_reset: u64,
     pub retry_delay: u64,
+    #[serde(default)]
+    pub error_rate: f64,  // open on error ratio in window of error_reset seconds, error_threshold is then minimum requests
+    #[serde(default)]
+    pub half_open_requests: u64,  // trial requests admitted after retry_delay, 1 if 0
+    #[serde(default)]
+    pub half_open_success_ratio: f64,  // successful trial ratio to close the breaker, 1.0 if 0
+    #[serde(default)]
+    pub breaker_header: bool,  // add circuit-breaker state header to responses
+    #[serde(default)]
+    pub rewrite_host: bool,  // send target authority as Host header instead of client's
+    #[serde(default)]
+    pub tls: Option<UpstreamTls>,  // TLS settings of https targets, native roots if not set
+    #[serde(default)]
+    pub dns: Option<DnsSetting>,  // expand target host into one upstream per DNS record
+    #[serde(default)]
+    pub endpoints_file: Option<EndpointsFileSetting>,  // expand into one upstream per entry of a watched file
+    #[serde(skip)]
+    pub address: Option<IpAddr>,  // resolved address of a discovered upstream, target host is kept for Host and SNI
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UpstreamTls {
+    #[serde(default)]
+    pub ca_file: String,  // PEM CA bundle to verify upstream certificate, native roots if empty
+    #[serde(default)]
+    pub cert_file: String,  // PEM client certificate chain for mTLS
+    #[serde(default)]
+    pub key_file: String,  // PEM client private key, PKCS8 or RSA
+    #[serde(default)]
+    pub sni: String,  // server name to send and verify, target host if empty
+    #[serde(default)]
+    pub insecure_skip_verify: bool,  // accept any upstream certificate, for development only
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EndpointsFileSetting {
+    pub path: String,  // JSON or YAML list of endpoints, written by a sidecar
+    #[serde(default)]
+    pub interval: u64,  // change check interval in seconds, 5 if 0
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DnsSetting {
+    #[serde(default)]
+    pub srv: bool,  // target host is a SRV record name, port and weight come from the records
+    #[serde(default)]
+    pub interval: u64,  // re-resolve interval in seconds, 30 if 0
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HashKeySetting {
+    pub source: String,  // "client", "ip", "header", "cookie" or "path"
+    #[serde(default)]
+    pub name: String,  // header or cookie name
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StickySetting {
+    #[serde(default)]
+    pub cookie: String,  // cookie name, hyperapi_sticky if empty
+    #[serde(default)]
+    pub fallback: String,  // load balance of new clients and unavailable upstreams, random if empty
+    #[serde(default)]
+    pub max_age: u64,  // seconds, 0 for session cookie
+    #[serde(default)]
+    pub secret: String,  // cookie signing key, shared by gateway instances
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MirrorSetting {
+    pub target: String,
+    pub sample_ratio: f64,  // ratio of requests copied to shadow upstream, 0.0 to 1.0
+    pub max_body_size: u64,  // bytes, requests with larger body are not mirrored
+    pub max_conn: u64,  // in flight shadow requests, requests over the limit are not mirrored
+    #[serde(default)]
+    pub rewrite_host: bool,
 }
 
 
@@ -95,12 +260,134 @@ pub struct PathMatcher {
 }
 
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CORSSetting {
+    pub allow_origins: Vec<String>,   // exact match, "*" for any origin
+    #[serde(default)]
+    pub allow_origin_patterns: Vec<String>,   // regex match
+    pub allow_methods: Vec<String>,
+    pub allow_headers: Vec<String>,
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age: u64,  // seconds
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CacheSetting {
+    pub max_size: u64,  // bytes of cached responses per service
+    pub max_entry_size: u64,  // bytes, larger responses are not cached
+    pub default_ttl: u64,  // seconds, used when upstream gives no Cache-Control/Expires, 0 to skip
+    pub stale_while_revalidate: u64,  // seconds
+    pub key_query: bool,
+    pub key_headers: Vec<String>,
+    pub key_client: bool,
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompressionSetting {
+    pub algorithms: Vec<String>,  // in preference order, "br", "zstd", "gzip"
+    pub min_size: u64,  // bytes
+    pub content_types: Vec<String>,  // prefix match, e.g. "application/json", "text/"
+    #[serde(default)]
+    pub decompress_request: bool,
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BodyLimitSetting {
+    pub max_request_body: u64,  // bytes, 0 for unlimited
+    pub max_response_body: u64,  // bytes, 0 for unlimited
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RewriteRule {
+    pub rule_type: String,  // "regex" or "prefix"
+    pub pattern: String,
+    pub replacement: String,  // regex replacement may refer to groups as $1
+    #[serde(default)]
+    pub path_pattern: String,  // ACL style glob to limit the rule, empty for all paths
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RewriteSetting {
+    pub rules: Vec<RewriteRule>,
+    #[serde(default)]
+    pub query_injection: Vec<(String, String)>,
+    #[serde(default)]
+    pub query_removal: Vec<String>,
+    #[serde(default)]
+    pub query_rename: Vec<(String, String)>,
+    #[serde(default)]
+    pub preserve_prefix: bool,  // keep service path in upstream url
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AccessLogSetting {
+    pub sample_ratio: f64,  // ratio of requests written to access log, 0.0 to 1.0
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetricsSetting {
+    pub path_templates: Vec<String>,  // "/users/{id}" style or ACL style glob, first match is the path label
+    #[serde(default)]
+    pub collapse_ids: bool,  // replace numeric, uuid and long hex segments of unmatched paths with {id}
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CanaryRule {
+    pub match_type: String,  // "header", "cookie", "client" or "sla"
+    #[serde(default)]
+    pub name: String,  // header or cookie name
+    pub values: Vec<String>,
+    pub version: String,  // upstream version to pin matching requests to
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VersionSplit {
+    pub version: String,
+    pub weight: u32,
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CanarySetting {
+    #[serde(default)]
+    pub rules: Vec<CanaryRule>,  // first matching rule wins
+    #[serde(default)]
+    pub splits: Vec<VersionSplit>,  // sticky per client split of requests not matching any rule
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CachePurge {
+    pub service_id: String,
+    pub path_prefix: String,  // purge all entries of service if empty
+}
+
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(tag="type", content="setting")]
 pub enum FilterSetting {
     RateLimit(RateLimitSetting),
     Header(HeaderSetting),
     ACL(ACLSetting),
+    CORS(CORSSetting),
+    Cache(CacheSetting),
+    Compression(CompressionSetting),
+    BodyLimit(BodyLimitSetting),
+    Rewrite(RewriteSetting),
+    AccessLog(AccessLogSetting),
+    Metrics(MetricsSetting),
+    Canary(CanarySetting),
 }
 
 
@@ -110,6 +397,14 @@ impl FilterSetting {
             FilterSetting::ACL(_) => "ACL".into(),
             FilterSetting::Header(_) => "Header".into(),
             FilterSetting::RateLimit(_) => "RateLimit".into(),
+            FilterSetting::CORS(_) => "CORS".into(),
+            FilterSetting::Cache(_) => "Cache".into(),
+            FilterSetting::Compression(_) => "Compression".into(),
+            FilterSetting::BodyLimit(_) => "BodyLimit".into(),
+            FilterSetting::Rewrite(_) => "Rewrite".into(),
+            FilterSetting::AccessLog(_) => "Logger".into(),  // handled by LoggerMiddleware
+            FilterSetting::Metrics(_) => "Logger".into(),
+            FilterSetting::Canary(_) => "Upstream".into(),  // handled by UpstreamMiddleware
         }
     }
 }
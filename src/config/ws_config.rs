@@ -1,5 +1,6 @@
 use tokio::sync::mpsc;
 use crate::config::ConfigUpdate;
+use crate::redact;
 use async_tungstenite::tokio::connect_async;
 use async_tungstenite::tungstenite::Message;
 use futures_util::{StreamExt, SinkExt};
@@ -13,11 +14,17 @@ pub async fn watch_config(ws_url: String, sender: mpsc::Sender<ConfigUpdate>) {
             if let Ok(msg) = res {
                 match msg {
                     Message::Text(txt) => {
-                        let update = serde_json::from_str::<ConfigUpdate>(&txt);
-                        if let Ok(up) = update {
-                            let _ = sender.send(up).await;
-                        } else {
-                            event!(Level::ERROR, "bad config update message: {:?}", update);
+                        match serde_json::from_str::<ConfigUpdate>(&txt) {
+                            Ok(up) => {
+                                let _ = sender.send(up).await;
+                            },
+                            Err(e) => {
+                                // serde errors quote offending values, log position and redacted message instead
+                                let message = redact::json_body(txt.as_bytes())
+                                    .map(|body| String::from_utf8_lossy(&body).into_owned())
+                                    .unwrap_or_else(|| String::from(redact::REDACTED));
+                                event!(Level::ERROR, "bad config update message at line {} column {}: {}", e.line(), e.column(), message);
+                            },
                         }
                     },
                     Message::Ping(sn) => {
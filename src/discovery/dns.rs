@@ -0,0 +1,311 @@
+use hyper::Uri;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use std::convert::TryInto;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::RwLock;
+use std::time::Duration;
+use tracing::{event, Level};
+use crate::config::Upstream;
+use super::{EndpointSender, StopHandle};
+
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+const DEFAULT_INTERVAL: u64 = 30;
+
+
+lazy_static::lazy_static! {
+    // nameserver for discovery lookups, first nameserver of /etc/resolv.conf if None
+    static ref DNS_SERVER: RwLock<Option<SocketAddr>> = RwLock::new(None);
+}
+
+
+pub fn init_dns(server: Option<SocketAddr>) {
+    *DNS_SERVER.write().unwrap() = server;
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Record {
+    Addr(IpAddr),
+    Srv { priority: u16, weight: u16, port: u16, target: String },
+}
+
+
+/// Re-resolve a DNS upstream periodically, endpoints are sent when they change.
+/// Failed or empty lookups keep the endpoints found before.
+pub fn watch_dns(upstream: Upstream, endpoints: EndpointSender) -> StopHandle {
+    let (stop, mut stopped) = oneshot::channel();
+    let interval = match upstream.dns.as_ref().map(|d| d.interval).unwrap_or(0) {
+        0 => DEFAULT_INTERVAL,
+        i => i,
+    };
+    tokio::spawn(async move {
+        let mut current = None;
+        loop {
+            match resolve(&upstream).await {
+                Ok(found) if found.is_empty() => {
+                    event!(Level::WARN, "No DNS records of upstream {}, keep current endpoints", upstream.id);
+                },
+                Ok(found) => {
+                    if current.as_ref() != Some(&found) {
+                        if endpoints.send((upstream.clone(), found.clone())).is_err() {
+                            break;
+                        }
+                        current = Some(found);
+                    }
+                },
+                Err(e) => {
+                    event!(Level::WARN, "DNS lookup of upstream {} failed, keep current endpoints: {}", upstream.id, e);
+                },
+            }
+            tokio::select! {
+                _ = &mut stopped => break,
+                _ = tokio::time::sleep(Duration::from_secs(interval)) => {},
+            }
+        }
+    });
+    stop
+}
+
+
+/// Expand a DNS upstream into one upstream per A/AAAA record of its target host,
+/// or per target of the lowest priority SRV records, weighted by SRV weight.
+pub async fn resolve(upstream: &Upstream) -> io::Result<Vec<Upstream>> {
+    let uri: Uri = upstream.target.parse().map_err(|e| invalid(format!("invalid target {}: {}", upstream.target, e)))?;
+    let host = uri.host().ok_or_else(|| invalid(format!("no host in target {}", upstream.target)))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let srv = upstream.dns.as_ref().map(|d| d.srv).unwrap_or(false);
+
+    let mut endpoints = Vec::new();
+    if srv {
+        let records = query(host, TYPE_SRV).await?;
+        let lowest = records.iter().filter_map(|r| match r {
+            Record::Srv { priority, .. } => Some(*priority),
+            _ => None,
+        }).min();
+        for record in records.iter() {
+            if let Record::Srv { priority, weight, port, target } = record {
+                if Some(*priority) != lowest {
+                    continue;
+                }
+                // connect to first address of the target, target name is kept for Host and SNI
+                let address = match lookup_ip(target).await {
+                    Ok(addrs) if !addrs.is_empty() => addrs[0],
+                    Ok(_) => continue,
+                    Err(e) => {
+                        event!(Level::WARN, "DNS lookup of SRV target {} failed: {}", target, e);
+                        continue;
+                    },
+                };
+                let authority = format!("{}:{}", target, port);
+                let path = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+                endpoints.push(Upstream {
+                    id: format!("{}@{}", upstream.id, authority),
+                    target: format!("{}://{}{}", uri.scheme_str().unwrap_or("http"), authority, path),
+                    weight: (*weight as u32).max(1),
+                    dns: None,
+                    endpoints_file: None,
+                    address: Some(address),
+                    ..upstream.clone()
+                });
+            }
+        }
+    } else {
+        for ip in lookup_ip(host).await? {
+            endpoints.push(Upstream {
+                id: format!("{}@{}", upstream.id, ip),
+                dns: None,
+                endpoints_file: None,
+                address: Some(ip),
+                ..upstream.clone()
+            });
+        }
+    }
+    endpoints.sort_by(|a, b| a.id.cmp(&b.id));
+    endpoints.dedup_by(|a, b| a.id == b.id);
+    Ok(endpoints)
+}
+
+
+// addresses of a host, from configured nameserver or system resolver
+async fn lookup_ip(host: &str) -> io::Result<Vec<IpAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![ip]);
+    }
+    let server = *DNS_SERVER.read().unwrap();
+    if server.is_none() {
+        let addrs = tokio::net::lookup_host((host, 0)).await?;
+        return Ok(addrs.map(|a| a.ip()).collect());
+    }
+    let (v4, v6) = tokio::join!(query(host, TYPE_A), query(host, TYPE_AAAA));
+    if let (Err(e), Err(_)) = (&v4, &v6) {
+        return Err(io::Error::new(e.kind(), e.to_string()));
+    }
+    Ok(v4.unwrap_or_default().into_iter().chain(v6.unwrap_or_default())
+        .filter_map(|r| match r {
+            Record::Addr(ip) => Some(ip),
+            _ => None,
+        })
+        .collect())
+}
+
+
+fn nameserver() -> io::Result<SocketAddr> {
+    if let Some(server) = *DNS_SERVER.read().unwrap() {
+        return Ok(server);
+    }
+    let conf = std::fs::read_to_string("/etc/resolv.conf")?;
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|ns| ns.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no nameserver in /etc/resolv.conf"))
+}
+
+
+async fn query(name: &str, qtype: u16) -> io::Result<Vec<Record>> {
+    let server = nameserver()?;
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    let id = rand::random::<u16>();
+    socket.send(&encode_query(id, name, qtype)?).await?;
+
+    let mut buf = vec![0u8; 4096];
+    let len = tokio::time::timeout(QUERY_TIMEOUT, socket.recv(&mut buf)).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("no response from nameserver {}", server)))??;
+    parse_response(id, &buf[..len])
+}
+
+
+fn encode_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(name.len() + 18);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);  // recursion desired, one question
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(invalid(format!("invalid DNS name {}", name)));
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&qtype.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(buf)
+}
+
+
+fn parse_response(id: u16, buf: &[u8]) -> io::Result<Vec<Record>> {
+    let mut r = Reader { buf, pos: 0 };
+    if r.u16()? != id {
+        return Err(invalid("mismatched DNS response id".into()));
+    }
+    match r.u16()? & 0x000f {
+        0 => {},
+        3 => return Err(io::Error::new(io::ErrorKind::NotFound, "DNS name not found")),
+        rcode => return Err(io::Error::other(format!("DNS server error, rcode {}", rcode))),
+    }
+    let questions = r.u16()?;
+    let answers = r.u16()?;
+    r.skip(4)?;  // authority and additional counts
+    for _ in 0..questions {
+        r.name()?;
+        r.skip(4)?;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        r.name()?;
+        let rtype = r.u16()?;
+        r.skip(6)?;  // class and ttl
+        let len = r.u16()? as usize;
+        let end = r.pos + len;
+        match (rtype, len) {
+            (TYPE_A, 4) => {
+                let octets: [u8; 4] = r.bytes(4)?.try_into().unwrap();
+                records.push(Record::Addr(IpAddr::from(octets)));
+            },
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = r.bytes(16)?.try_into().unwrap();
+                records.push(Record::Addr(IpAddr::from(octets)));
+            },
+            (TYPE_SRV, _) => {
+                let priority = r.u16()?;
+                let weight = r.u16()?;
+                let port = r.u16()?;
+                let target = r.name()?;
+                records.push(Record::Srv { priority, weight, port, target });
+            },
+            _ => {},  // CNAME chain, resolved records follow
+        }
+        r.pos = end;
+    }
+    Ok(records)
+}
+
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+
+impl<'a> Reader<'a> {
+
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos + n).ok_or_else(|| invalid("truncated DNS response".into()))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, n: usize) -> io::Result<()> {
+        self.bytes(n).map(|_| ())
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    // domain name with compression pointers, RFC 1035 section 4.1.4
+    fn name(&mut self) -> io::Result<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut jumped = false;
+        for _ in 0..128 {
+            let len = *self.buf.get(pos).ok_or_else(|| invalid("truncated DNS name".into()))? as usize;
+            if len & 0xc0 == 0xc0 {
+                let low = *self.buf.get(pos + 1).ok_or_else(|| invalid("truncated DNS name".into()))? as usize;
+                if !jumped {
+                    self.pos = pos + 2;
+                    jumped = true;
+                }
+                pos = ((len & 0x3f) << 8) | low;
+            } else if len == 0 {
+                if !jumped {
+                    self.pos = pos + 1;
+                }
+                return Ok(labels.join("."));
+            } else {
+                let label = self.buf.get(pos + 1..pos + 1 + len).ok_or_else(|| invalid("truncated DNS name".into()))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len;
+            }
+        }
+        Err(invalid("DNS name compression loop".into()))
+    }
+}
@@ -0,0 +1,106 @@
+use hyper::Uri;
+use serde::{Serialize, Deserialize};
+use tokio::sync::oneshot;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tracing::{event, Level};
+use crate::config::Upstream;
+use super::{EndpointSender, StopHandle};
+
+
+const DEFAULT_INTERVAL: u64 = 5;
+
+
+// entry of an endpoints file, weight and version default to those of the upstream
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Endpoint {
+    address: String,  // ip, ip:port or host:port
+    #[serde(default)]
+    weight: Option<u32>,
+    #[serde(default)]
+    version: Option<String>,
+}
+
+
+/// Check an endpoints file periodically, endpoints are sent when the file content changes.
+/// Unreadable or invalid files keep the endpoints found before, an empty list removes them all.
+pub fn watch_file(upstream: Upstream, endpoints: EndpointSender) -> StopHandle {
+    let (stop, mut stopped) = oneshot::channel();
+    let (path, interval) = match &upstream.endpoints_file {
+        Some(f) if f.interval > 0 => (f.path.clone(), f.interval),
+        Some(f) => (f.path.clone(), DEFAULT_INTERVAL),
+        None => (String::new(), DEFAULT_INTERVAL),
+    };
+    tokio::spawn(async move {
+        let mut current = None;
+        loop {
+            match tokio::fs::read_to_string(&path).await {
+                // sidecar may be rewriting the file, try again later
+                Ok(content) if content.trim().is_empty() => {},
+                Ok(content) if current.as_ref() == Some(&content) => {},
+                Ok(content) => match parse(&upstream, &content) {
+                    Ok(found) => {
+                        if endpoints.send((upstream.clone(), found)).is_err() {
+                            break;
+                        }
+                        current = Some(content);
+                    },
+                    Err(e) => {
+                        // warn once per file change
+                        event!(Level::WARN, "Invalid endpoints file {} of upstream {}, keep current endpoints: {}", path, upstream.id, e);
+                        current = Some(content);
+                    },
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Failed to read endpoints file {} of upstream {}, keep current endpoints: {}", path, upstream.id, e);
+                },
+            }
+            tokio::select! {
+                _ = &mut stopped => break,
+                _ = tokio::time::sleep(Duration::from_secs(interval)) => {},
+            }
+        }
+    });
+    stop
+}
+
+
+// JSON is read as YAML
+fn parse(upstream: &Upstream, content: &str) -> Result<Vec<Upstream>, String> {
+    let entries: Vec<Endpoint> = serde_yaml::from_str(content).map_err(|e| e.to_string())?;
+    let uri: Uri = upstream.target.parse().map_err(|e| format!("invalid target {}: {}", upstream.target, e))?;
+    let mut endpoints = Vec::new();
+    for entry in entries {
+        endpoints.push(endpoint(upstream, &uri, &entry)?);
+    }
+    endpoints.sort_by(|a, b| a.id.cmp(&b.id));
+    endpoints.dedup_by(|a, b| a.id == b.id);
+    Ok(endpoints)
+}
+
+
+// ip addresses are pinned and target host is kept for Host and SNI, host names replace target authority
+fn endpoint(upstream: &Upstream, uri: &Uri, entry: &Endpoint) -> Result<Upstream, String> {
+    let scheme = uri.scheme_str().unwrap_or("http");
+    let host = uri.host().ok_or_else(|| format!("no host in target {}", upstream.target))?;
+    let path = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let (target, address) = if let Ok(addr) = entry.address.parse::<SocketAddr>() {
+        (format!("{}://{}:{}{}", scheme, host, addr.port(), path), Some(addr.ip()))
+    } else if let Ok(ip) = entry.address.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        (upstream.target.clone(), Some(ip))
+    } else if !entry.address.is_empty() {
+        (format!("{}://{}{}", scheme, entry.address, path), None)
+    } else {
+        return Err(String::from("empty endpoint address"));
+    };
+    Ok(Upstream {
+        id: format!("{}@{}", upstream.id, entry.address),
+        target,
+        weight: entry.weight.unwrap_or(upstream.weight),
+        version: entry.version.clone().unwrap_or_else(|| upstream.version.clone()),
+        dns: None,
+        endpoints_file: None,
+        address,
+        ..upstream.clone()
+    })
+}
@@ -0,0 +1,34 @@
+mod dns;
+mod file;
+
+pub use dns::{init_dns, resolve, watch_dns};
+pub use file::watch_file;
+
+use tokio::sync::{mpsc, oneshot};
+use crate::config::Upstream;
+
+
+// endpoints found for an upstream, sent to its service worker whenever they change
+pub type EndpointSender = mpsc::UnboundedSender<(Upstream, Vec<Upstream>)>;
+
+
+// discovery task of an upstream stops when its handle is dropped
+pub type StopHandle = oneshot::Sender<()>;
+
+
+// upstream is replaced by the endpoints discovered for it
+pub fn is_discovered(upstream: &Upstream) -> bool {
+    upstream.endpoints_file.is_some() || upstream.dns.is_some()
+}
+
+
+// start discovery of an upstream, endpoints file takes precedence over DNS
+pub fn watch(upstream: &Upstream, endpoints: EndpointSender) -> Option<StopHandle> {
+    if upstream.endpoints_file.is_some() {
+        Some(watch_file(upstream.clone(), endpoints))
+    } else if upstream.dns.is_some() {
+        Some(watch_dns(upstream.clone(), endpoints))
+    } else {
+        None
+    }
+}
@@ -2,6 +2,9 @@ pub mod proxy;
 pub mod config;
 pub mod middleware;
 pub mod auth;
+pub mod trace;
+pub mod redact;
+pub mod discovery;
 
 
 #[macro_export]
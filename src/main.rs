@@ -4,13 +4,18 @@ use hyper::Server;
 use hyper::service::make_service_fn;
 use std::convert::Infallible;
 use hyperapi::config::ConfigSource;
-use hyperapi::proxy::{GatewayServer, TlsConfigBuilder, TlsAcceptor};
+use hyperapi::proxy::{GatewayServer, ServerSetting, TlsConfigBuilder, TlsAcceptor};
+use hyperapi::proxy::https::{TlsStream, Transport};
+use hyperapi::trace::{TraceSetting, init_tracer};
+use hyperapi::middleware::{AccessLogConfig, AccessLogFormat, init_access_log};
+use hyperapi::redact::{RedactPolicy, init_redact};
+use hyperapi::discovery::init_dns;
 use std::sync::{Arc, Mutex};
 use tracing_log::LogTracer;
 use tracing_subscriber::{Registry, EnvFilter};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_bunyan_formatter::{JsonStorageLayer, BunyanFormattingLayer};
-use hyper::server::conn::AddrIncoming;
+use hyper::server::conn::{AddrIncoming, AddrStream};
 
 
 #[tokio::main]
@@ -46,25 +51,122 @@ async fn main() {
             .long("key_file")
             .default_value("")
             .help("HTTPS private key file"))
+        .arg(Arg::with_name("trusted_proxies").takes_value(true)
+            .long("trusted_proxies")
+            .default_value("")
+            .help("Comma separated CIDRs of proxies trusted to set X-Forwarded-* headers"))
+        .arg(Arg::with_name("request_id_header").takes_value(true)
+            .long("request_id_header")
+            .default_value("X-Request-ID")
+            .help("Request id header name"))
+        .arg(Arg::with_name("otlp_endpoint").takes_value(true)
+            .long("otlp_endpoint")
+            .default_value("")
+            .help("OTLP/HTTP trace collector endpoint, e.g. http://127.0.0.1:4318/v1/traces"))
+        .arg(Arg::with_name("trace_sample_ratio").takes_value(true)
+            .long("trace_sample_ratio")
+            .default_value("1.0")
+            .help("Sampling ratio of traces started by gateway"))
+        .arg(Arg::with_name("access_log").takes_value(true)
+            .long("access_log")
+            .default_value("")
+            .help("Access log output, \"stdout\" or file path, disabled if empty"))
+        .arg(Arg::with_name("access_log_format").takes_value(true)
+            .long("access_log_format")
+            .possible_values(&["json", "combined"])
+            .default_value("json")
+            .help("Access log format"))
+        .arg(Arg::with_name("access_log_rotation").takes_value(true)
+            .long("access_log_rotation")
+            .possible_values(&["daily", "hourly", "never"])
+            .default_value("daily")
+            .help("Access log file rotation"))
+        .arg(Arg::with_name("redact_headers").takes_value(true)
+            .long("redact_headers")
+            .default_value("")
+            .help("Comma separated headers to redact in logs, in addition to Authorization and X-APP-KEY"))
+        .arg(Arg::with_name("redact_query_params").takes_value(true)
+            .long("redact_query_params")
+            .default_value("")
+            .help("Comma separated query params to redact in logs, in addition to _app_key"))
+        .arg(Arg::with_name("redact_body_fields").takes_value(true)
+            .long("redact_body_fields")
+            .default_value("")
+            .help("Comma separated JSON body fields to redact in logs and captured traffic"))
+        .arg(Arg::with_name("dns_server").takes_value(true)
+            .long("dns_server")
+            .default_value("")
+            .help("Nameserver of DNS upstream discovery, e.g. 127.0.0.1:53, first nameserver of /etc/resolv.conf if empty"))
         .get_matches();
     let config = matches.value_of("config").unwrap();
     let listen = matches.value_of("listen").unwrap();
     let cert_file = matches.value_of("cert_file").unwrap();
     let key_file = matches.value_of("key_file").unwrap();
+    let trusted_proxies = matches.value_of("trusted_proxies").unwrap();
+    let request_id_header = matches.value_of("request_id_header").unwrap();
+    let otlp_endpoint = matches.value_of("otlp_endpoint").unwrap();
+    let trace_sample_ratio = matches.value_of("trace_sample_ratio").unwrap();
+    let access_log = matches.value_of("access_log").unwrap();
+    let access_log_format = matches.value_of("access_log_format").unwrap();
+    let access_log_rotation = matches.value_of("access_log_rotation").unwrap();
+    let dns_server = matches.value_of("dns_server").unwrap();
+    let split_list = |name: &str| -> Vec<String> {
+        matches.value_of(name).unwrap().split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect()
+    };
+    init_redact(RedactPolicy::default().extend(
+        &split_list("redact_headers"),
+        &split_list("redact_query_params"),
+        &split_list("redact_body_fields"),
+    ));
+
+    if !dns_server.is_empty() {
+        init_dns(Some(dns_server.parse().expect("Invalid DNS server address")));
+    }
+
+    if !otlp_endpoint.is_empty() {
+        init_tracer(TraceSetting {
+            otlp_endpoint: otlp_endpoint.into(),
+            sample_ratio: trace_sample_ratio.parse().expect("Invalid trace sample ratio"),
+            service_name: env!("CARGO_PKG_NAME").into(),
+        });
+    }
+
+    let _access_log_guard = if !access_log.is_empty() {
+        let format = if access_log_format == "combined" { AccessLogFormat::Combined } else { AccessLogFormat::Json };
+        Some(init_access_log(&AccessLogConfig {
+            output: access_log.into(),
+            format,
+            rotation: access_log_rotation.into(),
+        }))
+    } else {
+        None
+    };
 
     let config_source = ConfigSource::new(config.into());
     let addr = listen.parse().expect("Invalid listen address");
 
-    let server = GatewayServer::new(config_source);
+    let trusted_proxies = trusted_proxies.split(',')
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .map(|p| p.parse().or_else(|_| p.parse::<std::net::IpAddr>().map(ipnet::IpNet::from)).expect("Invalid trusted proxy CIDR"))
+        .collect();
+    let request_id_header = request_id_header.parse().expect("Invalid request id header");
+    let setting = ServerSetting { trusted_proxies, request_id_header };
+
+    let server = GatewayServer::new(config_source, setting);
     let server = Arc::new(Mutex::new(server));
 
     let incoming = AddrIncoming::bind(&addr).unwrap();
     if cert_file != "" && key_file != "" {
         event!(Level::INFO, "Starting https gateway edge server");
-        let make_svc = make_service_fn(|_| {
+        let make_svc = make_service_fn(|conn: &TlsStream| {
             let handler = {
                 let lock = server.lock().expect("GatewayServer status error");
-                lock.make_service()
+                lock.make_service(conn.remote_addr(), "https")
             };
             async move {
                 Ok::<_, Infallible>(handler)
@@ -81,10 +183,10 @@ async fn main() {
         server.await.expect("Server failed to start");
     } else {
         event!(Level::INFO, "Starting http gateway edge server");
-        let make_svc = make_service_fn(|_| {
+        let make_svc = make_service_fn(|conn: &AddrStream| {
             let handler = {
                 let lock = server.lock().expect("GatewayServer status error");
-                lock.make_service()
+                lock.make_service(Some(conn.remote_addr()), "http")
             };
             async move {
                 Ok::<_, Infallible>(handler)
@@ -0,0 +1,132 @@
+use hyper::{Body, HeaderMap};
+use hyper::body::Bytes;
+use hyper::header;
+use futures::{ready, Stream};
+use thiserror::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use crate::middleware::{MwPostRequest, MwPreRequest, MwPreResponse, MwPostResponse, Middleware, MwNextAction, GatewayError};
+use crate::config::{ConfigUpdate, FilterSetting};
+
+
+lazy_static::lazy_static! {
+    static ref BODY_LIMIT_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_body_limit_rejected_total",
+        "Number of requests and responses rejected by body size limit.",
+        &["service", "direction"]
+    ).unwrap();
+}
+
+
+#[derive(Error, Debug)]
+#[error("Body size limit exceeded")]
+pub struct BodyLimitExceeded;
+
+
+#[derive(Debug, Default)]
+pub struct BodyLimitMiddleware {}
+
+
+impl Middleware for BodyLimitMiddleware {
+
+    fn name() -> String {
+        "BodyLimit".into()
+    }
+
+    fn request(&mut self, task: MwPreRequest) -> Pin<Box<dyn Future<Output=()> + Send>> {
+        let MwPreRequest {context, mut request, service_filters, client_filters, result} = task;
+        let (limit, _) = body_limits(&service_filters, &client_filters);
+        if limit > 0 {
+            if content_length(request.headers()).map(|len| len > limit).unwrap_or(false) {
+                BODY_LIMIT_COUNTER.with_label_values(&[&context.service_id, "request"]).inc();
+                let _ = result.send(Err(GatewayError::PayloadTooLarge("Request body too large".into())));
+                return Box::pin(async {});
+            }
+            // chunked body, enforce limit while streaming to upstream
+            let body = std::mem::replace(request.body_mut(), Body::empty());
+            *request.body_mut() = limit_body(body, limit, &context.service_id, "request");
+        }
+        let _ = result.send(Ok(MwPreResponse { context, next: MwNextAction::Next(request) }));
+        Box::pin(async {})
+    }
+
+    fn response(&mut self, task: MwPostRequest) -> Pin<Box<dyn Future<Output=()> + Send>> {
+        let MwPostRequest {context, mut response, service_filters, client_filters, result} = task;
+        let (_, limit) = body_limits(&service_filters, &client_filters);
+        if limit > 0 {
+            if content_length(response.headers()).map(|len| len > limit).unwrap_or(false) {
+                BODY_LIMIT_COUNTER.with_label_values(&[&context.service_id, "response"]).inc();
+                let _ = result.send(Err(GatewayError::UpstreamError("Upstream response too large".into())));
+                return Box::pin(async {});
+            }
+            // response head is already on the way, oversized body is aborted
+            let body = std::mem::replace(response.body_mut(), Body::empty());
+            *response.body_mut() = limit_body(body, limit, &context.service_id, "response");
+        }
+        let _ = result.send(Ok(MwPostResponse { context, response }));
+        Box::pin(async {})
+    }
+
+    fn config_update(&mut self, _update: ConfigUpdate) {}
+}
+
+
+// (max_request_body, max_response_body), the smallest non-zero limit of service and SLA settings wins
+fn body_limits(service_filters: &[FilterSetting], client_filters: &[FilterSetting]) -> (u64, u64) {
+    let min_limit = |a: u64, b: u64| if a == 0 || (b > 0 && b < a) { b } else { a };
+    service_filters.iter().chain(client_filters.iter()).fold((0, 0), |(req, resp), f| {
+        if let FilterSetting::BodyLimit(limit) = f {
+            (min_limit(req, limit.max_request_body), min_limit(resp, limit.max_response_body))
+        } else {
+            (req, resp)
+        }
+    })
+}
+
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+}
+
+
+fn limit_body(body: Body, limit: u64, service_id: &str, direction: &'static str) -> Body {
+    Body::wrap_stream(LimitedBody {
+        inner: body,
+        remaining: limit,
+        service_id: String::from(service_id),
+        direction,
+    })
+}
+
+
+// fails the stream once more than `remaining` bytes pass through
+struct LimitedBody {
+    inner: Body,
+    remaining: u64,
+    service_id: String,
+    direction: &'static str,
+}
+
+
+impl Stream for LimitedBody {
+    type Item = Result<Bytes, Box<dyn std::error::Error + Send + Sync>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+            Some(Ok(chunk)) => {
+                if chunk.len() as u64 > this.remaining {
+                    BODY_LIMIT_COUNTER.with_label_values(&[&this.service_id, this.direction]).inc();
+                    return Poll::Ready(Some(Err(Box::new(BodyLimitExceeded))));
+                }
+                this.remaining -= chunk.len() as u64;
+                Poll::Ready(Some(Ok(chunk)))
+            },
+            Some(Err(e)) => Poll::Ready(Some(Err(Box::new(e)))),
+            None => Poll::Ready(None),
+        }
+    }
+}
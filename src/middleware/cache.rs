@@ -0,0 +1,548 @@
+use hyper::{Request, Response, Body, Method, StatusCode, HeaderMap};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{self, HeaderName, HeaderValue};
+use futures::{ready, Stream};
+use lru::LruCache;
+use tracing::{event, Level};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use crate::middleware::{MwPostRequest, MwPreRequest, MwPreResponse, MwPostResponse, Middleware, MwNextAction, RequestContext};
+use crate::config::{ConfigUpdate, FilterSetting, CacheSetting};
+
+
+#[derive(Debug, Default)]
+pub struct CacheMiddleware {
+    service_cache: HashMap<String, ServiceCache>,   // service_cache[service_id] = ServiceCache
+}
+
+
+#[derive(Debug)]
+struct ServiceCache {
+    setting: CacheSetting,
+    store: Arc<Mutex<CacheStore>>,
+}
+
+
+// carried in RequestContext from pre-filter to post-filter
+#[derive(Debug, Clone)]
+pub struct CacheLookup {
+    pub key: String,
+    pub headers: HeaderMap,
+    pub credentialed: bool,  // request carries client credentials
+}
+
+
+impl Middleware for CacheMiddleware {
+
+    fn name() -> String {
+        "Cache".into()
+    }
+
+    fn request(&mut self, task: MwPreRequest) -> Pin<Box<dyn Future<Output=()> + Send>> {
+        let MwPreRequest {mut context, request, service_filters: _, client_filters: _, result} = task;
+        let next = match self.service_cache.get(&context.service_id) {
+            Some(cache) => cache.lookup(&mut context, request),
+            None => MwNextAction::Next(request),
+        };
+        let _ = result.send(Ok(MwPreResponse { context, next }));
+        Box::pin(async {})
+    }
+
+    fn response(&mut self, task: MwPostRequest) -> Pin<Box<dyn Future<Output=()> + Send>> {
+        let MwPostRequest {context, mut response, service_filters: _, client_filters: _, result} = task;
+        if let (Some(cache), Some(lookup)) = (self.service_cache.get(&context.service_id), &context.cache_lookup) {
+            response = cache.store(lookup, response);
+        }
+        let _ = result.send(Ok(MwPostResponse { context, response }));
+        Box::pin(async {})
+    }
+
+    fn config_update(&mut self, update: ConfigUpdate) {
+        match update {
+            ConfigUpdate::ServiceUpdate(service) => {
+                let setting = service.filters.iter().find_map(|f| {
+                    if let FilterSetting::Cache(cache) = f { Some(cache) } else { None }
+                });
+                if let Some(setting) = setting {
+                    // keep cached entries if setting is not changed
+                    if let Some(cache) = self.service_cache.get(&service.service_id) {
+                        if cache.setting.eq(setting) {
+                            return;
+                        }
+                    }
+                    let cache = ServiceCache {
+                        setting: setting.clone(),
+                        store: Arc::new(Mutex::new(CacheStore::new(setting.max_size))),
+                    };
+                    self.service_cache.insert(service.service_id.clone(), cache);
+                } else {
+                    self.service_cache.remove(&service.service_id);
+                }
+            },
+            ConfigUpdate::ServiceRemove(service_id) => {
+                self.service_cache.remove(&service_id);
+            },
+            ConfigUpdate::CachePurge(purge) => {
+                if let Some(cache) = self.service_cache.get(&purge.service_id) {
+                    let purged = cache.store.lock().unwrap().purge(&purge.path_prefix);
+                    event!(Level::INFO, "purged {} cache entries of {}", purged, purge.service_id);
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+
+impl ServiceCache {
+
+    fn lookup(&self, context: &mut RequestContext, request: Request<Body>) -> MwNextAction {
+        let method = request.method().clone();
+        if method != Method::GET && method != Method::HEAD {
+            return MwNextAction::Next(request);
+        }
+        let directives = cache_control(request.headers());
+        if directives.contains_key("no-store") {
+            return MwNextAction::Next(request);
+        }
+
+        let key = cache_key(&self.setting, context, &request);
+        if !directives.contains_key("no-cache") {
+            let now = SystemTime::now();
+            let mut store = self.store.lock().unwrap();
+            if let Some(entry) = store.get_mut(&key, request.headers()) {
+                if now < entry.fresh_until {
+                    return MwNextAction::Return(entry.to_response(&method, now, "HIT"));
+                }
+                if now < entry.stale_until {
+                    let resp = entry.to_response(&method, now, "STALE");
+                    if entry.revalidating {
+                        return MwNextAction::Return(resp);
+                    }
+                    // serve stale response, refresh entry in background
+                    entry.revalidating = true;
+                    let (parts, _body) = request.into_parts();
+                    let mut revalidate = Request::from_parts(parts, Body::empty());
+                    *revalidate.method_mut() = Method::GET;
+                    let credentialed = credentialed(&revalidate);
+                    context.cache_lookup = Some(CacheLookup { key, headers: revalidate.headers().clone(), credentialed });
+                    return MwNextAction::ReturnAndContinue(resp, revalidate);
+                }
+                store.remove(&key);
+            }
+        }
+
+        // only GET response bodies are stored
+        if method == Method::GET {
+            let credentialed = credentialed(&request);
+            context.cache_lookup = Some(CacheLookup { key, headers: request.headers().clone(), credentialed });
+        }
+        MwNextAction::Next(request)
+    }
+
+    // response is marked MISS when stored, BYPASS when not cacheable
+    fn store(&self, lookup: &CacheLookup, mut response: Response<Body>) -> Response<Body> {
+        let now = SystemTime::now();
+        let (ttl, swr, vary) = match self.storable(lookup, &response, now) {
+            Some(s) => s,
+            None => {
+                response.headers_mut().insert(HeaderName::from_static("x-cache"), HeaderValue::from_static("BYPASS"));
+                return response;
+            },
+        };
+
+        let (parts, body) = response.into_parts();
+        let store = self.store.clone();
+        let key = lookup.key.clone();
+        let status = parts.status;
+        let headers = parts.headers.clone();
+        let tee = TeeBody {
+            inner: body,
+            buf: Some(Vec::new()),
+            limit: self.setting.max_entry_size as usize,
+            on_complete: Some(Box::new(move |body: Bytes| {
+                let entry = CacheEntry {
+                    status,
+                    headers,
+                    body,
+                    vary,
+                    stored_at: now,
+                    fresh_until: now + ttl,
+                    stale_until: now + ttl + swr,
+                    revalidating: false,
+                };
+                store.lock().unwrap().put(key, entry);
+            })),
+        };
+        let mut response = Response::from_parts(parts, Body::wrap_stream(tee));
+        response.headers_mut().insert(HeaderName::from_static("x-cache"), HeaderValue::from_static("MISS"));
+        response
+    }
+
+    // freshness and vary values of a response to be stored, None if it must not be
+    fn storable(&self, lookup: &CacheLookup, response: &Response<Body>, now: SystemTime) -> Option<(Duration, Duration, VaryValues)> {
+        if response.status() != StatusCode::OK {
+            return None;
+        }
+        // cookies are set for one client, replaying them would leak sessions to others
+        if response.headers().contains_key(header::SET_COOKIE) {
+            return None;
+        }
+        // key has no client, only responses upstream marks as shared are served to other clients
+        if lookup.credentialed && !self.setting.key_client {
+            let directives = cache_control(response.headers());
+            if !directives.contains_key("public") && !directives.contains_key("s-maxage") {
+                return None;
+            }
+        }
+        let (ttl, swr) = freshness(response.headers(), now, &self.setting)?;
+        let vary = vary_values(response.headers(), &lookup.headers)?;
+        let length = response.headers().get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        match length {
+            Some(len) if len > self.setting.max_entry_size || len > self.setting.max_size => None,
+            _ => Some((ttl, swr, vary)),
+        }
+    }
+}
+
+
+// client credentials in Authorization or X-APP-KEY header, _app_key query parameter or ~appkey path segment
+fn credentialed<T>(req: &Request<T>) -> bool {
+    let headers = req.headers();
+    if headers.contains_key(header::AUTHORIZATION) || headers.contains_key("x-app-key") {
+        return true;
+    }
+    let in_query = req.uri().query()
+        .map(|q| q.split('&').any(|p| p == "_app_key" || p.starts_with("_app_key=")))
+        .unwrap_or(false);
+    in_query || req.uri().path().split('/').any(|seg| seg.starts_with('~') && seg.len() > 1)
+}
+
+
+pub fn cache_key<T>(setting: &CacheSetting, context: &RequestContext, req: &Request<T>) -> String {
+    let mut key = context.api_path.clone();
+    if setting.key_query {
+        if let Some(query) = req.uri().query() {
+            key.push('?');
+            key.push_str(query);
+        }
+    }
+    for h in &setting.key_headers {
+        let value = req.headers().get(h.as_str()).and_then(|v| v.to_str().ok()).unwrap_or("");
+        key.push_str(&format!("|{}={}", h.to_lowercase(), value));
+    }
+    if setting.key_client {
+        key.push_str(&format!("|client={}", context.client_id));
+    }
+    key
+}
+
+
+// parse Cache-Control header into directive -> value
+pub fn cache_control(headers: &HeaderMap) -> HashMap<String, String> {
+    let mut directives = HashMap::new();
+    for value in headers.get_all(header::CACHE_CONTROL) {
+        if let Ok(v) = value.to_str() {
+            for d in v.split(',') {
+                let mut kv = d.trim().splitn(2, '=');
+                let k = kv.next().unwrap_or("").trim().to_lowercase();
+                let v = kv.next().unwrap_or("").trim().trim_matches('"').to_string();
+                if !k.is_empty() {
+                    directives.insert(k, v);
+                }
+            }
+        }
+    }
+    directives
+}
+
+
+// freshness lifetime and stale-while-revalidate window of a response, None if not cacheable
+pub fn freshness(headers: &HeaderMap, now: SystemTime, setting: &CacheSetting) -> Option<(Duration, Duration)> {
+    let directives = cache_control(headers);
+    if directives.contains_key("no-store") || directives.contains_key("private") || directives.contains_key("no-cache") {
+        return None;
+    }
+    let seconds = |name: &str| directives.get(name).and_then(|v| v.parse::<u64>().ok());
+
+    let ttl = if let Some(ttl) = seconds("s-maxage").or_else(|| seconds("max-age")) {
+        ttl
+    } else if let Some(expires) = headers.get(header::EXPIRES) {
+        let expires = expires.to_str().ok().and_then(|e| httpdate::parse_http_date(e).ok())?;
+        let date = headers.get(header::DATE)
+            .and_then(|d| d.to_str().ok())
+            .and_then(|d| httpdate::parse_http_date(d).ok())
+            .unwrap_or(now);
+        expires.duration_since(date).map(|d| d.as_secs()).unwrap_or(0)
+    } else {
+        setting.default_ttl
+    };
+    if ttl == 0 {
+        return None;
+    }
+    let swr = seconds("stale-while-revalidate").unwrap_or(setting.stale_while_revalidate);
+    Some((Duration::from_secs(ttl), Duration::from_secs(swr)))
+}
+
+
+// name and request value of each header in Vary
+type VaryValues = Vec<(HeaderName, Option<HeaderValue>)>;
+
+
+// request header values named by response Vary header, None if response varies on everything
+fn vary_values(resp_headers: &HeaderMap, req_headers: &HeaderMap) -> Option<VaryValues> {
+    let mut values = Vec::new();
+    for value in resp_headers.get_all(header::VARY) {
+        for name in value.to_str().unwrap_or("").split(',') {
+            let name = name.trim();
+            if name == "*" {
+                return None;
+            }
+            if let Ok(name) = HeaderName::from_bytes(name.to_lowercase().as_bytes()) {
+                let v = req_headers.get(&name).cloned();
+                values.push((name, v));
+            }
+        }
+    }
+    Some(values)
+}
+
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    vary: VaryValues,
+    stored_at: SystemTime,
+    fresh_until: SystemTime,
+    stale_until: SystemTime,
+    revalidating: bool,
+}
+
+
+impl CacheEntry {
+
+    fn size(&self) -> u64 {
+        let headers: usize = self.headers.iter().map(|(k, v)| k.as_str().len() + v.len()).sum();
+        (self.body.len() + headers) as u64
+    }
+
+    fn matches(&self, req_headers: &HeaderMap) -> bool {
+        self.vary.iter().all(|(name, value)| req_headers.get(name) == value.as_ref())
+    }
+
+    fn to_response(&self, method: &Method, now: SystemTime, cache_status: &'static str) -> Response<Body> {
+        let body = if method == Method::HEAD { Body::empty() } else { Body::from(self.body.clone()) };
+        let mut resp = Response::new(body);
+        *resp.status_mut() = self.status;
+        *resp.headers_mut() = self.headers.clone();
+        let age = now.duration_since(self.stored_at).unwrap_or_default().as_secs();
+        let headers = resp.headers_mut();
+        headers.insert(header::AGE, HeaderValue::from(age));
+        headers.insert(HeaderName::from_static("x-cache"), HeaderValue::from_static(cache_status));
+        resp
+    }
+}
+
+
+// LRU store bounded by total size of entries
+#[derive(Debug)]
+struct CacheStore {
+    entries: LruCache<String, CacheEntry>,
+    size: u64,
+    max_size: u64,
+}
+
+
+impl CacheStore {
+
+    fn new(max_size: u64) -> Self {
+        CacheStore { entries: LruCache::unbounded(), size: 0, max_size }
+    }
+
+    fn get_mut(&mut self, key: &String, req_headers: &HeaderMap) -> Option<&mut CacheEntry> {
+        match self.entries.get_mut(key) {
+            Some(entry) if entry.matches(req_headers) => Some(entry),
+            _ => None,
+        }
+    }
+
+    fn put(&mut self, key: String, entry: CacheEntry) {
+        let entry_size = entry.size();
+        if entry_size > self.max_size {
+            return;
+        }
+        self.remove(&key);
+        while self.size + entry_size > self.max_size {
+            match self.entries.pop_lru() {
+                Some((_k, evicted)) => self.size -= evicted.size(),
+                None => break,
+            }
+        }
+        self.size += entry_size;
+        self.entries.put(key, entry);
+    }
+
+    fn remove(&mut self, key: &String) {
+        if let Some(old) = self.entries.pop(key) {
+            self.size -= old.size();
+        }
+    }
+
+    fn purge(&mut self, path_prefix: &str) -> usize {
+        let keys: Vec<String> = self.entries.iter()
+            .filter(|(k, _v)| k.starts_with(path_prefix))
+            .map(|(k, _v)| k.clone())
+            .collect();
+        for k in keys.iter() {
+            self.remove(k);
+        }
+        keys.len()
+    }
+}
+
+
+// pass body through to client, collect a copy for cache store when body is complete
+struct TeeBody {
+    inner: Body,
+    buf: Option<Vec<u8>>,
+    limit: usize,
+    on_complete: Option<Box<dyn FnOnce(Bytes) + Send>>,
+}
+
+
+impl Stream for TeeBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+            Some(Ok(chunk)) => {
+                if let Some(buf) = &mut this.buf {
+                    if buf.len() + chunk.len() > this.limit {
+                        this.buf = None;
+                    } else {
+                        buf.extend_from_slice(&chunk);
+                    }
+                }
+                // body with content-length is not polled again after last chunk
+                if this.inner.is_end_stream() {
+                    this.complete();
+                }
+                Poll::Ready(Some(Ok(chunk)))
+            },
+            Some(Err(e)) => {
+                this.buf = None;
+                Poll::Ready(Some(Err(e)))
+            },
+            None => {
+                this.complete();
+                Poll::Ready(None)
+            },
+        }
+    }
+}
+
+
+impl TeeBody {
+    fn complete(&mut self) {
+        if let (Some(buf), Some(on_complete)) = (self.buf.take(), self.on_complete.take()) {
+            on_complete(Bytes::from(buf));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(key_client: bool) -> ServiceCache {
+        let setting: CacheSetting = serde_yaml::from_str(&format!(r#"
+            {{max_size: 4096, max_entry_size: 1024, default_ttl: 0, stale_while_revalidate: 0,
+              key_query: true, key_headers: [], key_client: {}}}
+        "#, key_client)).unwrap();
+        ServiceCache { store: Arc::new(Mutex::new(CacheStore::new(setting.max_size))), setting }
+    }
+
+    fn lookup(credentialed: bool) -> CacheLookup {
+        CacheLookup { key: String::from("/items"), headers: HeaderMap::new(), credentialed }
+    }
+
+    fn response(headers: &[(&str, &str)]) -> Response<Body> {
+        let mut builder = Response::builder();
+        for (k, v) in headers {
+            builder = builder.header(*k, *v);
+        }
+        builder.body(Body::from("cached")).unwrap()
+    }
+
+    fn x_cache(resp: &Response<Body>) -> &str {
+        resp.headers().get("x-cache").unwrap().to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn credentialed_responses_need_client_key_or_shared_marking() {
+        let private = [("cache-control", "max-age=60")];
+        assert_eq!(x_cache(&cache(false).store(&lookup(true), response(&private))), "BYPASS");
+        assert_eq!(x_cache(&cache(false).store(&lookup(false), response(&private))), "MISS");
+        assert_eq!(x_cache(&cache(true).store(&lookup(true), response(&private))), "MISS");
+        let public = response(&[("cache-control", "public, max-age=60")]);
+        assert_eq!(x_cache(&cache(false).store(&lookup(true), public)), "MISS");
+        let shared = response(&[("cache-control", "s-maxage=60")]);
+        assert_eq!(x_cache(&cache(false).store(&lookup(true), shared)), "MISS");
+    }
+
+    #[tokio::test]
+    async fn only_shared_credentialed_responses_are_stored() {
+        let cache = cache(false);
+        let resp = cache.store(&lookup(true), response(&[("cache-control", "public, max-age=60")]));
+        hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(cache.store.lock().unwrap().entries.len(), 1);
+
+        let resp = cache.store(&lookup(true), response(&[("cache-control", "max-age=60")]));
+        hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(cache.store.lock().unwrap().entries.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn uncacheable_responses_are_bypassed() {
+        let cache = cache(true);
+        let cases = [
+            response(&[("cache-control", "max-age=60"), ("set-cookie", "session=a")]),
+            response(&[("cache-control", "no-store")]),
+            response(&[]),
+            response(&[("cache-control", "max-age=60"), ("content-length", "2048")]),
+        ];
+        for resp in cases {
+            assert_eq!(x_cache(&cache.store(&lookup(false), resp)), "BYPASS");
+        }
+        let mut not_found = response(&[("cache-control", "max-age=60")]);
+        *not_found.status_mut() = StatusCode::NOT_FOUND;
+        assert_eq!(x_cache(&cache.store(&lookup(false), not_found)), "BYPASS");
+    }
+
+    #[test]
+    fn credentials_are_detected() {
+        let req = |uri: &str, header: Option<&str>| {
+            let mut builder = Request::get(uri);
+            if let Some(h) = header {
+                builder = builder.header(h, "secret");
+            }
+            builder.body(()).unwrap()
+        };
+        assert!(!credentialed(&req("/svc/items?page=1", None)));
+        assert!(credentialed(&req("/svc/items", Some("authorization"))));
+        assert!(credentialed(&req("/svc/items", Some("x-app-key"))));
+        assert!(credentialed(&req("/svc/items?page=1&_app_key=secret", None)));
+        assert!(credentialed(&req("/svc/~secret/items", None)));
+        assert!(!credentialed(&req("/svc/items?my_app_key=1", None)));
+    }
+}
@@ -0,0 +1,104 @@
+use hyper::{Body, Request, HeaderMap};
+use hyper::header;
+use crate::config::{CanarySetting, CanaryRule, VersionSplit};
+use crate::middleware::{RequestContext, ForwardInfo};
+
+
+// upstream version picked for a request, attached to request extensions
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamVersion(pub String);
+
+
+#[derive(Debug, Clone)]
+pub struct CanaryRouter {
+    rules: Vec<CanaryRule>,
+    splits: Vec<VersionSplit>,
+    total_weight: u32,
+}
+
+
+impl CanaryRouter {
+
+    pub fn new(setting: &CanarySetting) -> Self {
+        let splits: Vec<VersionSplit> = setting.splits.iter().filter(|s| s.weight > 0).cloned().collect();
+        let total_weight = splits.iter().map(|s| s.weight).sum();
+        CanaryRouter { rules: setting.rules.clone(), splits, total_weight }
+    }
+
+    // version pinned by rules, or split by client, None to use all upstreams
+    pub fn route(&self, context: &RequestContext, request: &Request<Body>) -> Option<String> {
+        if let Some(rule) = self.rules.iter().find(|r| rule_match(r, context, request)) {
+            return Some(rule.version.clone());
+        }
+        if self.total_weight == 0 {
+            return None;
+        }
+        let client_key = if !context.client_id.is_empty() {
+            context.client_id.clone()
+        } else {
+            client_address(request)?
+        };
+        self.split(&format!("{}:{}", context.service_id, client_key)).map(String::from)
+    }
+
+    // sticky assignment, a client keeps its version as long as its bucket stays within the version's share
+    pub fn split(&self, key: &str) -> Option<&str> {
+        let mut bucket = fnv1a(key.as_bytes()) % (self.total_weight as u64);
+        for s in &self.splits {
+            if bucket < s.weight as u64 {
+                return Some(&s.version);
+            }
+            bucket -= s.weight as u64;
+        }
+        None
+    }
+}
+
+
+fn rule_match(rule: &CanaryRule, context: &RequestContext, request: &Request<Body>) -> bool {
+    let value = match rule.match_type.as_str() {
+        "header" => request.headers().get(rule.name.as_str()).and_then(|v| v.to_str().ok()),
+        "cookie" => cookie(request.headers(), &rule.name),
+        "client" => Some(context.client_id.as_str()),
+        "sla" => Some(context.sla.as_str()),
+        _ => None,
+    };
+    value.map(|v| rule.values.iter().any(|r| r == v)).unwrap_or(false)
+}
+
+
+// original client behind trusted proxies, otherwise the peer address
+pub fn client_address(request: &Request<Body>) -> Option<String> {
+    let info = request.extensions().get::<ForwardInfo>()?;
+    if info.trusted {
+        let forwarded = request.headers().get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty());
+        if let Some(addr) = forwarded {
+            return Some(String::from(addr));
+        }
+    }
+    Some(info.client_ip.to_string())
+}
+
+
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get_all(header::COOKIE).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|pair| {
+            let mut kv = pair.trim().splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k == name => Some(v),
+                _ => None,
+            }
+        })
+}
+
+
+// stable across gateway instances and restarts, unlike DefaultHasher
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325u64, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
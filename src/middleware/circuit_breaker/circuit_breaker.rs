@@ -1,33 +1,89 @@
 use futures::ready;
 use hyper::{Body, Request, Response, header::HeaderName, http::HeaderValue};
-use std::time::SystemTime;
 use tower::Service;
-use std::task::{Context, Poll};
+use tower::load_shed::error::Overloaded;
+use std::task::{Context, Poll, Waker};
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use pin_project::pin_project;
+use tracing::{event, Level};
 use super::state::*;
+use crate::middleware::{GatewayError, WarmUp};
 
 
+lazy_static::lazy_static! {
+    static ref CB_TRANSITION_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_circuit_breaker_transitions_total",
+        "Number of circuit breaker state transitions.",
+        &["service", "upstream", "from", "to"]
+    ).unwrap();
+}
+
+
+// breaker state and services waiting for it to admit requests
+#[derive(Debug)]
+struct Breaker {
+    state: CircuitBreakerState,
+    waiting: Vec<Waker>,
+    warm_up: Option<WarmUp>,  // slow start of the upstream, restarted when it recovers
+}
+
+
+// clones share breaker state
+#[derive(Clone)]
 pub struct CircuitBreakerService<S> {
     inner: S,
-    state: Arc<Mutex<CircuitBreakerState>>,
+    state: Arc<Mutex<Breaker>>,
     config: CircuitBreakerConfig,
+    labels: Arc<[String; 2]>,  // service_id, upstream_id
 }
 
 
 impl<S> CircuitBreakerService<S> {
-    pub fn new(inner: S, config: CircuitBreakerConfig) -> Self {
-        let state = CircuitBreakerState::Close(CloseState {
-            errors: 0,
-            last_error: SystemTime::now(),
-        });
-        CircuitBreakerService { inner, config, state: Arc::new(Mutex::new(state)) }
+    pub fn new(inner: S, config: CircuitBreakerConfig, service_id: &str, upstream_id: &str, warm_up: Option<WarmUp>) -> Self {
+        let state = Breaker { state: CircuitBreakerState::new(&config), waiting: Vec::new(), warm_up };
+        let labels = Arc::new([String::from(service_id), String::from(upstream_id)]);
+        CircuitBreakerService { inner, config, state: Arc::new(Mutex::new(state)), labels }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    // breaker admits requests, open breakers do again after retry delay
+    pub fn available(&self) -> bool {
+        self.config.error_threshold == 0 || self.state.lock().unwrap().state.available(&self.config)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn shares_state(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
     }
 }
 
 
+// apply state change, log and count transitions, wake services waiting on the breaker, warm up recovered upstream
+fn transit<T, F>(breaker: &mut Breaker, labels: &[String; 2], f: F) -> T
+    where F: FnOnce(&mut CircuitBreakerState) -> T
+{
+    let from = breaker.state.name();
+    let result = f(&mut breaker.state);
+    let to = breaker.state.name();
+    if from != to {
+        event!(Level::INFO, "Circuit breaker of {}/{} {} -> {}", labels[0], labels[1], from, to);
+        CB_TRANSITION_COUNTER.with_label_values(&[&labels[0], &labels[1], from, to]).inc();
+        for waker in breaker.waiting.drain(..) {
+            waker.wake();
+        }
+        if let (CircuitBreakerState::Close(_), Some(warm_up)) = (&breaker.state, &breaker.warm_up) {
+            warm_up.restart();
+        }
+    }
+    result
+}
+
+
 impl<S> Service<Request<Body>> for CircuitBreakerService<S> 
     where S: Service<Request<Body>, Response=Response<Body>, Error=Box<dyn std::error::Error + Send + Sync>>,
 {
@@ -38,10 +94,24 @@ impl<S> Service<Request<Body>> for CircuitBreakerService<S>
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         if let Poll::Ready(r) = self.inner.poll_ready(cx) {
             if self.config.error_threshold > 0 {
-                let mut stat = self.state.lock().unwrap();
-                if stat.check_state(&self.config) {
+                let mut breaker = self.state.lock().unwrap();
+                let config = &self.config;
+                if transit(&mut breaker, &self.labels, |s| s.check_state(config)) {
                     return Poll::Ready(r)
                 }
+                // balancers poll pending services only when woken, by a state change or the end of retry delay
+                if breaker.waiting.iter().any(|w| w.will_wake(cx.waker())) {
+                    return Poll::Pending;
+                }
+                breaker.waiting.push(cx.waker().clone());
+                if let CircuitBreakerState::Open(open) = &breaker.state {
+                    let delay = self.config.retry_delay.saturating_sub(open.since.elapsed());
+                    let waker = cx.waker().clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(delay).await;
+                        waker.wake();
+                    });
+                }
             } else {  // circurt breaker is off
                 return Poll::Ready(r)
             }
@@ -50,9 +120,12 @@ impl<S> Service<Request<Body>> for CircuitBreakerService<S>
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
+        if self.config.error_threshold > 0 {
+            self.state.lock().unwrap().state.admit();
+        }
         let fut = self.inner.call(req);
         let state = self.state.clone();
-        CBFuture { fut, state, config: self.config.clone() }
+        CBFuture { fut, state, config: self.config.clone(), labels: self.labels.clone() }
     }
 }
 
@@ -63,8 +136,9 @@ pub struct CBFuture<Fut>
 {
     #[pin]
     fut: Fut,
-    state: Arc<Mutex<CircuitBreakerState>>,
+    state: Arc<Mutex<Breaker>>,
     config: CircuitBreakerConfig,
+    labels: Arc<[String; 2]>,
 }
 
 
@@ -81,29 +155,30 @@ impl<Fut> Future for CBFuture<Fut>
         if this.config.error_threshold == 0 {  // circurt breaker is off
             return Poll::Ready(result);
         }
+        let config = *this.config;
 
         // update circuit breaker counter
         if let Ok(mut r) = result {
+            let mut breaker = this.state.lock().unwrap();
             if r.status().as_u16() >= 500 {
-                let mut state = this.state.lock().unwrap();
-                state.error(&this.config);
-                let header = r.headers_mut();
-                let state_value = format!("{:?}", state);
-                let state_value = HeaderValue::from_str(&state_value).unwrap();
-                header.insert(HeaderName::from_static("circuit-breaker"), state_value);
-                Poll::Ready(Ok(r))
+                transit(&mut breaker, this.labels, |s| s.error(&config));
             } else {
-                let mut state = this.state.lock().unwrap();
-                state.success(&this.config);
+                transit(&mut breaker, this.labels, |s| s.success(&config));
+            }
+            if config.state_header {
                 let header = r.headers_mut();
-                let state_value = format!("{:?}", state);
-                let state_value = HeaderValue::from_str(&state_value).unwrap();
-                header.insert(HeaderName::from_static("circuit-breaker"), state_value);
-                Poll::Ready(Ok(r))
+                header.insert(HeaderName::from_static("circuit-breaker"), HeaderValue::from_static(breaker.state.name()));
             }
+            Poll::Ready(Ok(r))
         } else {
-            let mut state = this.state.lock().unwrap();
-            state.error(&this.config);
+            // oversized request body is client's fault, concurrency limit is gateway's, neither is upstream's
+            let not_upstream = result.as_ref().err()
+                .map(|e| e.is::<Overloaded>() || matches!(e.downcast_ref::<GatewayError>(), Some(GatewayError::PayloadTooLarge(_))))
+                .unwrap_or(false);
+            if !not_upstream {
+                let mut breaker = this.state.lock().unwrap();
+                transit(&mut breaker, this.labels, |s| s.error(&config));
+            }
             Poll::Ready(result)
         }
     }
@@ -1,11 +1,18 @@
-use std::time::{SystemTime, Duration};
+use std::time::{Duration, Instant};
+
+
+const WINDOW_BUCKETS: usize = 10;
 
 
 #[derive(Debug, Clone, Copy)]
 pub struct CircuitBreakerConfig {
-    pub error_threshold: u64,
-    pub error_reset: Duration,
+    pub error_threshold: u64,  // errors in window to open, minimum requests in window if error_rate is set
+    pub error_rate: f64,  // error ratio in window to open, 0 to count errors only
+    pub error_window: Duration,
     pub retry_delay: Duration,
+    pub half_open_requests: u64,  // trial requests admitted in half-open
+    pub half_open_success_ratio: f64,  // successful trials ratio to close
+    pub state_header: bool,  // add circuit-breaker state header to responses
 }
 
 #[derive(Debug)]
@@ -17,84 +24,286 @@ pub enum CircuitBreakerState {
 
 #[derive(Debug)]
 pub struct CloseState {
-    pub errors: u64, 
-    pub last_error: SystemTime,
+    pub window: ErrorWindow,
 }
 
 #[derive(Debug)]
 pub struct HalfOpenState {
-    pub last_attempt: SystemTime,
+    pub since: Instant,
+    pub admitted: u64,
+    pub successes: u64,
+    pub failures: u64,
 }
 
 #[derive(Debug)]
 pub struct OpenState {
-    pub last_attempt: SystemTime,
+    pub since: Instant,
+}
+
+
+// request and error counts of a sliding window, in buckets of window / WINDOW_BUCKETS
+#[derive(Debug)]
+pub struct ErrorWindow {
+    width: Duration,
+    start: Instant,  // start of current bucket
+    current: usize,
+    buckets: [(u64, u64); WINDOW_BUCKETS],  // (requests, errors)
+}
+
+
+impl ErrorWindow {
+
+    pub fn new(window: Duration) -> Self {
+        let width = (window / WINDOW_BUCKETS as u32).max(Duration::from_millis(1));
+        ErrorWindow { width, start: Instant::now(), current: 0, buckets: [(0, 0); WINDOW_BUCKETS] }
+    }
+
+    fn advance(&mut self, now: Instant) {
+        let steps = (now.saturating_duration_since(self.start).as_nanos() / self.width.as_nanos()) as usize;
+        if steps == 0 {
+            return;
+        }
+        for _ in 0..steps.min(WINDOW_BUCKETS) {
+            self.current = (self.current + 1) % WINDOW_BUCKETS;
+            self.buckets[self.current] = (0, 0);
+        }
+        self.start = if steps < WINDOW_BUCKETS { self.start + self.width * steps as u32 } else { now };
+    }
+
+    pub fn record(&mut self, error: bool, now: Instant) {
+        self.advance(now);
+        let bucket = &mut self.buckets[self.current];
+        bucket.0 += 1;
+        if error {
+            bucket.1 += 1;
+        }
+    }
+
+    pub fn exceeded(&self, config: &CircuitBreakerConfig) -> bool {
+        let (requests, errors) = self.buckets.iter().fold((0, 0), |(r, e), (br, be)| (r + br, e + be));
+        if config.error_rate > 0.0 {
+            requests >= config.error_threshold.max(1) && errors as f64 >= config.error_rate * requests as f64
+        } else {
+            errors >= config.error_threshold
+        }
+    }
 }
 
 
 impl CircuitBreakerState {
 
+    pub fn new(config: &CircuitBreakerConfig) -> Self {
+        CircuitBreakerState::Close(CloseState { window: ErrorWindow::new(config.error_window) })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CircuitBreakerState::Open(_) => "open",
+            CircuitBreakerState::HalfOpen(_) => "half_open",
+            CircuitBreakerState::Close(_) => "closed",
+        }
+    }
+
+    // request may be sent, open breaker turns half-open after retry delay
     pub fn check_state(&mut self, config: &CircuitBreakerConfig) -> bool {
-        let now = SystemTime::now();
+        let now = Instant::now();
         match self {
             CircuitBreakerState::Open(state) => {
-                if now.duration_since(state.last_attempt).unwrap() >= config.retry_delay {
-                    *self = CircuitBreakerState::HalfOpen(HalfOpenState {last_attempt: now});
-                    return true;
+                if now.duration_since(state.since) >= config.retry_delay {
+                    *self = Self::half_open(now);
+                    true
                 } else {
-                    return false;
+                    false
                 }
             },
-            CircuitBreakerState::Close(_state) => {
-                return true;
+            CircuitBreakerState::Close(_state) => true,
+            CircuitBreakerState::HalfOpen(state) => {
+                // trials lost to dropped requests, start another round
+                if state.admitted >= config.half_open_requests && now.duration_since(state.since) >= config.retry_delay {
+                    *self = Self::half_open(now);
+                    return true;
+                }
+                state.admitted < config.half_open_requests
             },
-            CircuitBreakerState::HalfOpen(_state) => {
-                return false;
+        }
+    }
+
+    // check_state would admit a request, without changing state
+    pub fn available(&self, config: &CircuitBreakerConfig) -> bool {
+        match self {
+            CircuitBreakerState::Open(state) => state.since.elapsed() >= config.retry_delay,
+            CircuitBreakerState::Close(_state) => true,
+            CircuitBreakerState::HalfOpen(state) => {
+                state.admitted < config.half_open_requests || state.since.elapsed() >= config.retry_delay
             },
         }
     }
 
-    pub fn success(&mut self, _config: &CircuitBreakerConfig) {
-        let now = SystemTime::now();
+    // request sent, counts as a trial in half-open
+    pub fn admit(&mut self) {
+        if let CircuitBreakerState::HalfOpen(state) = self {
+            state.admitted += 1;
+        }
+    }
+
+    pub fn success(&mut self, config: &CircuitBreakerConfig) {
+        let now = Instant::now();
         match self {
             CircuitBreakerState::Open(_state) => {
-                *self = CircuitBreakerState::HalfOpen(HalfOpenState {last_attempt: now})
+                // late response of a request sent before opening
             },
-            CircuitBreakerState::Close(_state) => {
-                // pass
+            CircuitBreakerState::Close(state) => {
+                state.window.record(false, now);
             },
-            CircuitBreakerState::HalfOpen(_state) => {
-                *self = CircuitBreakerState::Close(CloseState {
-                    errors: 0,
-                    last_error: now,
-                })
+            CircuitBreakerState::HalfOpen(state) => {
+                state.successes += 1;
+                self.settle(config, now);
             },
         }
     }
 
     pub fn error(&mut self, config: &CircuitBreakerConfig) {
-        let now = SystemTime::now();
+        let now = Instant::now();
         match self {
             CircuitBreakerState::Open(_state) => {
                 // pass
             },
             CircuitBreakerState::Close(state) => {
-                if now.duration_since(state.last_error).unwrap() >= config.error_reset {
-                    *self = CircuitBreakerState::Close(CloseState { errors: 1, last_error: now });
-                } else {
-                    if state.errors >= config.error_threshold {
-                        *self = CircuitBreakerState::Open(OpenState {last_attempt: now})
-                    } else {
-                        *self = CircuitBreakerState::Close(CloseState {
-                            errors: state.errors + 1,
-                            last_error: now,
-                        })
-                    }
+                state.window.record(true, now);
+                if state.window.exceeded(config) {
+                    *self = CircuitBreakerState::Open(OpenState { since: now });
                 }
             },
-            CircuitBreakerState::HalfOpen(_state) => {
-                *self = CircuitBreakerState::Open(OpenState {last_attempt: now});
+            CircuitBreakerState::HalfOpen(state) => {
+                state.failures += 1;
+                self.settle(config, now);
             },
         }
     }
-}
\ No newline at end of file
+
+    // close when enough trials succeeded, open again as soon as that is out of reach
+    fn settle(&mut self, config: &CircuitBreakerConfig, now: Instant) {
+        if let CircuitBreakerState::HalfOpen(state) = self {
+            let trials = config.half_open_requests as f64;
+            if state.successes as f64 >= config.half_open_success_ratio * trials {
+                *self = CircuitBreakerState::Close(CloseState { window: ErrorWindow::new(config.error_window) });
+            } else if (trials - state.failures as f64) < config.half_open_success_ratio * trials {
+                *self = CircuitBreakerState::Open(OpenState { since: now });
+            }
+        }
+    }
+
+    fn half_open(now: Instant) -> Self {
+        CircuitBreakerState::HalfOpen(HalfOpenState { since: now, admitted: 0, successes: 0, failures: 0 })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(half_open_requests: u64, half_open_success_ratio: f64) -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            error_threshold: 3,
+            error_rate: 0.0,
+            error_window: Duration::from_secs(10),
+            retry_delay: Duration::from_secs(10),
+            half_open_requests,
+            half_open_success_ratio,
+            state_header: false,
+        }
+    }
+
+    // breaker whose retry delay has passed
+    fn retrying() -> CircuitBreakerState {
+        CircuitBreakerState::Open(OpenState { since: Instant::now() - Duration::from_secs(11) })
+    }
+
+    #[test]
+    fn window_forgets_old_errors() {
+        let config = config(1, 1.0);
+        let mut window = ErrorWindow::new(config.error_window);
+        let start = Instant::now();
+        window.record(true, start);
+        window.record(true, start + Duration::from_secs(2));
+        assert!(!window.exceeded(&config));
+        window.record(true, start + Duration::from_secs(9));
+        assert!(window.exceeded(&config));
+        // first two errors slid out of the window
+        window.record(true, start + Duration::from_secs(12));
+        assert!(!window.exceeded(&config));
+    }
+
+    #[test]
+    fn window_error_rate_needs_minimum_requests() {
+        let config = CircuitBreakerConfig { error_rate: 0.5, ..config(1, 1.0) };
+        let mut window = ErrorWindow::new(config.error_window);
+        let now = Instant::now();
+        window.record(true, now);
+        window.record(true, now);
+        assert!(!window.exceeded(&config));
+        window.record(false, now);
+        assert!(window.exceeded(&config));
+        window.record(false, now);
+        window.record(false, now);
+        assert!(!window.exceeded(&config));
+    }
+
+    #[test]
+    fn closed_breaker_opens_on_errors() {
+        let config = config(1, 1.0);
+        let mut state = CircuitBreakerState::new(&config);
+        state.error(&config);
+        state.success(&config);
+        state.error(&config);
+        assert_eq!(state.name(), "closed");
+        state.error(&config);
+        assert_eq!(state.name(), "open");
+        assert!(!state.available(&config));
+        assert!(!state.check_state(&config));
+    }
+
+    #[test]
+    fn half_open_admits_limited_trials() {
+        let config = config(2, 1.0);
+        let mut state = retrying();
+        assert!(state.available(&config));
+        assert!(state.check_state(&config));
+        assert_eq!(state.name(), "half_open");
+        state.admit();
+        assert!(state.check_state(&config));
+        state.admit();
+        assert!(!state.available(&config));
+        assert!(!state.check_state(&config));
+    }
+
+    #[test]
+    fn half_open_closes_on_success_ratio() {
+        let config = config(4, 0.5);
+        let mut state = retrying();
+        assert!(state.check_state(&config));
+        state.admit();
+        state.success(&config);
+        state.admit();
+        state.error(&config);
+        assert_eq!(state.name(), "half_open");
+        state.admit();
+        state.success(&config);
+        assert_eq!(state.name(), "closed");
+    }
+
+    #[test]
+    fn half_open_reopens_when_ratio_is_out_of_reach() {
+        let config = config(4, 0.75);
+        let mut state = retrying();
+        assert!(state.check_state(&config));
+        state.admit();
+        state.error(&config);
+        assert_eq!(state.name(), "half_open");
+        state.admit();
+        state.error(&config);
+        assert_eq!(state.name(), "open");
+        assert!(!state.available(&config));
+    }
+}
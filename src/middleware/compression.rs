@@ -0,0 +1,297 @@
+use hyper::{Body, HeaderMap, Method, StatusCode};
+use hyper::header::{self, HeaderValue};
+use async_compression::tokio::bufread::{GzipEncoder, BrotliEncoder, ZstdEncoder, GzipDecoder, BrotliDecoder, ZstdDecoder};
+use tokio_util::io::{ReaderStream, StreamReader};
+use futures::TryStreamExt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use crate::middleware::{MwPostRequest, MwPreRequest, MwPreResponse, MwPostResponse, Middleware, MwNextAction};
+use crate::config::{ConfigUpdate, FilterSetting, CompressionSetting};
+
+
+#[derive(Debug, Default)]
+pub struct CompressionMiddleware {}
+
+
+impl Middleware for CompressionMiddleware {
+
+    fn name() -> String {
+        "Compression".into()
+    }
+
+    fn request(&mut self, task: MwPreRequest) -> Pin<Box<dyn Future<Output=()> + Send>> {
+        let MwPreRequest {mut context, mut request, service_filters, client_filters, result} = task;
+        if let Some(setting) = compression_setting(service_filters, client_filters) {
+            if setting.decompress_request {
+                let encoding = request.headers().get(header::CONTENT_ENCODING)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(Encoding::from_name);
+                if let Some(encoding) = encoding {
+                    let body = std::mem::replace(request.body_mut(), Body::empty());
+                    *request.body_mut() = decode_body(body, encoding);
+                    let headers = request.headers_mut();
+                    headers.remove(header::CONTENT_ENCODING);
+                    headers.remove(header::CONTENT_LENGTH);
+                }
+            }
+            // remember negotiated encoding for post-filter
+            if request.method() != Method::HEAD {
+                let accept = request.headers().get(header::ACCEPT_ENCODING)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("");
+                context.response_encoding = negotiate(accept, &setting.algorithms).map(|e| e.name().to_string());
+            }
+        }
+        let _ = result.send(Ok(MwPreResponse { context, next: MwNextAction::Next(request) }));
+        Box::pin(async {})
+    }
+
+    fn response(&mut self, task: MwPostRequest) -> Pin<Box<dyn Future<Output=()> + Send>> {
+        let MwPostRequest {context, mut response, service_filters, client_filters, result} = task;
+        let encoding = context.response_encoding.as_deref().and_then(Encoding::from_name);
+        if let (Some(setting), Some(encoding)) = (compression_setting(service_filters, client_filters), encoding) {
+            if should_compress(response.status(), response.headers(), &setting) {
+                let body = std::mem::replace(response.body_mut(), Body::empty());
+                *response.body_mut() = encode_body(body, encoding);
+                let headers = response.headers_mut();
+                headers.remove(header::CONTENT_LENGTH);
+                headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+                headers.append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+                weaken_etag(headers);
+            }
+        }
+        let _ = result.send(Ok(MwPostResponse { context, response }));
+        Box::pin(async {})
+    }
+
+    fn config_update(&mut self, _update: ConfigUpdate) {}
+}
+
+
+// SLA setting overrides service setting
+fn compression_setting(service_filters: Vec<FilterSetting>, client_filters: Vec<FilterSetting>) -> Option<CompressionSetting> {
+    client_filters.into_iter().chain(service_filters).find_map(|f| {
+        if let FilterSetting::Compression(c) = f { Some(c) } else { None }
+    })
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+
+impl Encoding {
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "br" => Some(Encoding::Brotli),
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+
+// pick first of configured algorithms accepted by client with non-zero q value
+pub fn negotiate(accept_encoding: &str, algorithms: &[String]) -> Option<Encoding> {
+    let mut accepted: Vec<(String, f32)> = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        let q = parts.find_map(|p| p.trim().strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()))
+            .unwrap_or(1.0);
+        if !name.is_empty() {
+            accepted.push((name, q));
+        }
+    }
+    let quality = |name: &str| {
+        accepted.iter().find(|(n, _q)| n == name)
+            .or_else(|| accepted.iter().find(|(n, _q)| n == "*"))
+            .map(|(_n, q)| *q)
+            .unwrap_or(0.0)
+    };
+    algorithms.iter()
+        .filter_map(|a| Encoding::from_name(a))
+        .find(|e| quality(e.name()) > 0.0)
+}
+
+
+pub fn should_compress(status: StatusCode, headers: &HeaderMap, setting: &CompressionSetting) -> bool {
+    // informational, 204 and 304 responses have no body to encode
+    if status.is_informational() || status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED {
+        return false;
+    }
+    // ranges describe bytes of the identity body, encoding would make them point elsewhere
+    if status == StatusCode::PARTIAL_CONTENT || headers.contains_key(header::CONTENT_RANGE) {
+        return false;
+    }
+    if headers.contains_key(header::CONTENT_ENCODING) {
+        return false;
+    }
+    let no_transform = headers.get_all(header::CACHE_CONTROL).iter()
+        .any(|v| v.to_str().unwrap_or("").to_lowercase().contains("no-transform"));
+    if no_transform {
+        return false;
+    }
+    // chunked bodies of unknown size are always compressed
+    let length = headers.get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(length) = length {
+        if length < setting.min_size {
+            return false;
+        }
+    }
+    let content_type = headers.get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .unwrap_or("")
+        .trim()
+        .to_lowercase();
+    setting.content_types.iter().any(|t| content_type.starts_with(&t.to_lowercase()))
+}
+
+
+// encoded body is not byte-identical to the one a strong ETag names, keep it as weak validator
+pub fn weaken_etag(headers: &mut HeaderMap) {
+    let weak = match headers.get(header::ETAG).and_then(|v| v.to_str().ok()) {
+        Some(etag) if !etag.starts_with("W/") => HeaderValue::from_str(&format!("W/{}", etag)).ok(),
+        _ => None,
+    };
+    if let Some(weak) = weak {
+        headers.insert(header::ETAG, weak);
+    }
+}
+
+
+fn body_reader(body: Body) -> StreamReader<impl futures::Stream<Item=io::Result<hyper::body::Bytes>>, hyper::body::Bytes> {
+    StreamReader::new(body.map_err(io::Error::other))
+}
+
+
+pub fn encode_body(body: Body, encoding: Encoding) -> Body {
+    let reader = body_reader(body);
+    match encoding {
+        Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader))),
+        Encoding::Brotli => Body::wrap_stream(ReaderStream::new(BrotliEncoder::new(reader))),
+        Encoding::Zstd => Body::wrap_stream(ReaderStream::new(ZstdEncoder::new(reader))),
+    }
+}
+
+
+pub fn decode_body(body: Body, encoding: Encoding) -> Body {
+    let reader = body_reader(body);
+    match encoding {
+        Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipDecoder::new(reader))),
+        Encoding::Brotli => Body::wrap_stream(ReaderStream::new(BrotliDecoder::new(reader))),
+        Encoding::Zstd => Body::wrap_stream(ReaderStream::new(ZstdDecoder::new(reader))),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setting() -> CompressionSetting {
+        CompressionSetting {
+            algorithms: vec!["br".into(), "gzip".into()],
+            min_size: 100,
+            content_types: vec!["application/json".into(), "text/".into()],
+            decompress_request: true,
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn negotiate_follows_configured_order() {
+        let algorithms = setting().algorithms;
+        assert_eq!(negotiate("gzip, br", &algorithms), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip", &algorithms), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0, gzip;q=0.5", &algorithms), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*", &algorithms), Some(Encoding::Brotli));
+        assert_eq!(negotiate("deflate", &algorithms), None);
+        assert_eq!(negotiate("", &algorithms), None);
+    }
+
+    #[test]
+    fn small_and_encoded_responses_are_passed_through() {
+        let setting = setting();
+        let json = headers(&[("content-type", "application/json; charset=utf-8"), ("content-length", "1000")]);
+        assert!(should_compress(StatusCode::OK, &json, &setting));
+        let small = headers(&[("content-type", "application/json"), ("content-length", "99")]);
+        assert!(!should_compress(StatusCode::OK, &small, &setting));
+        let chunked = headers(&[("content-type", "text/html")]);
+        assert!(should_compress(StatusCode::OK, &chunked, &setting));
+        let image = headers(&[("content-type", "image/png"), ("content-length", "1000")]);
+        assert!(!should_compress(StatusCode::OK, &image, &setting));
+        let encoded = headers(&[("content-type", "text/html"), ("content-encoding", "gzip")]);
+        assert!(!should_compress(StatusCode::OK, &encoded, &setting));
+        let no_transform = headers(&[("content-type", "text/html"), ("cache-control", "public, no-transform")]);
+        assert!(!should_compress(StatusCode::OK, &no_transform, &setting));
+    }
+
+    #[test]
+    fn bodyless_statuses_are_passed_through() {
+        let setting = setting();
+        let json = headers(&[("content-type", "application/json")]);
+        assert!(!should_compress(StatusCode::NO_CONTENT, &json, &setting));
+        assert!(!should_compress(StatusCode::NOT_MODIFIED, &json, &setting));
+        assert!(!should_compress(StatusCode::CONTINUE, &json, &setting));
+        assert!(should_compress(StatusCode::NOT_FOUND, &json, &setting));
+    }
+
+    #[test]
+    fn ranges_are_passed_through() {
+        let setting = setting();
+        let json = headers(&[("content-type", "application/json"), ("content-length", "1000")]);
+        assert!(!should_compress(StatusCode::PARTIAL_CONTENT, &json, &setting));
+        let range = headers(&[("content-type", "application/json"), ("content-range", "bytes 0-999/5000")]);
+        assert!(!should_compress(StatusCode::OK, &range, &setting));
+    }
+
+    #[test]
+    fn strong_etag_is_weakened() {
+        let mut strong = headers(&[("etag", "\"abc\"")]);
+        weaken_etag(&mut strong);
+        assert_eq!(strong.get("etag").unwrap(), "W/\"abc\"");
+        let mut weak = headers(&[("etag", "W/\"abc\"")]);
+        weaken_etag(&mut weak);
+        assert_eq!(weak.get("etag").unwrap(), "W/\"abc\"");
+        let mut none = headers(&[]);
+        weaken_etag(&mut none);
+        assert!(none.get("etag").is_none());
+    }
+
+    #[tokio::test]
+    async fn encoded_bodies_are_decoded() {
+        let text = "hello compression ".repeat(100);
+        for encoding in [Encoding::Gzip, Encoding::Brotli, Encoding::Zstd] {
+            let encoded = hyper::body::to_bytes(encode_body(Body::from(text.clone()), encoding)).await.unwrap();
+            assert!(encoded.len() < text.len());
+            let decoded = hyper::body::to_bytes(decode_body(Body::from(encoded), encoding)).await.unwrap();
+            assert_eq!(decoded, text.as_bytes());
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use hyper::{Request, Response, Body, Method, HeaderMap};
+use hyper::header::{self, HeaderValue};
+use tracing::{event, Level};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::RwLock;
+use regex::Regex;
+use crate::middleware::{MwPostRequest, MwPreRequest, MwPreResponse, MwPostResponse, Middleware, MwNextAction};
+use crate::config::{ConfigUpdate, FilterSetting, CORSSetting};
+
+
+lazy_static::lazy_static! {
+    // policies by service path, for responses the gateway produces outside of the middleware chain
+    static ref PATH_CORS: RwLock<HashMap<String, CORSPolicy>> = RwLock::new(HashMap::new());
+}
+
+
+#[derive(Debug, Default)]
+pub struct CORSMiddleware {
+    service_cors: HashMap<String, CORSPolicy>,   // service_cors[service_id] = CORSPolicy
+    service_paths: HashMap<String, String>,   // service_paths[service_id] = path in PATH_CORS
+}
+
+
+impl Middleware for CORSMiddleware {
+
+    fn name() -> String {
+        "CORS".into()
+    }
+
+    fn request(&mut self, task: MwPreRequest) -> Pin<Box<dyn Future<Output=()> + Send>> {
+        let MwPreRequest {mut context, request, service_filters: _, client_filters: _, result} = task;
+        let policy = self.service_cors.get(&context.service_id);
+        let origin = request.headers().get(header::ORIGIN).and_then(|o| o.to_str().ok());
+        if let (Some(policy), Some(origin)) = (policy, origin) {
+            if is_preflight(&request) {
+                // answer preflight directly, never reach upstream
+                let resp = policy.preflight(origin, &request);
+                let pre_resp = MwPreResponse { context, next: MwNextAction::Return(resp) };
+                let _ = result.send(Ok(pre_resp));
+                return Box::pin(async {});
+            }
+            context.cors_origin = policy.allowed_origin(origin);
+        }
+        let pre_resp = MwPreResponse { context, next: MwNextAction::Next(request) };
+        let _ = result.send(Ok(pre_resp));
+        Box::pin(async {})
+    }
+
+    fn response(&mut self, task: MwPostRequest) -> Pin<Box<dyn Future<Output=()> + Send>> {
+        let MwPostRequest {context, mut response, service_filters: _, client_filters: _, result} = task;
+        if let (Some(policy), Some(origin)) = (self.service_cors.get(&context.service_id), &context.cors_origin) {
+            policy.decorate(origin, response.headers_mut());
+        }
+        let resp = MwPostResponse { context, response };
+        let _ = result.send(Ok(resp));
+        Box::pin(async {})
+    }
+
+    fn config_update(&mut self, update: ConfigUpdate) {
+        match update {
+            ConfigUpdate::ServiceUpdate(service) => {
+                self.remove(&service.service_id);
+                let setting = service.filters.iter().find_map(|f| {
+                    if let FilterSetting::CORS(cors) = f { Some(cors) } else { None }
+                });
+                if let Some(cors) = setting {
+                    let policy = CORSPolicy::new(cors);
+                    PATH_CORS.write().unwrap().insert(service.path.clone(), policy.clone());
+                    self.service_paths.insert(service.service_id.clone(), service.path.clone());
+                    self.service_cors.insert(service.service_id.clone(), policy);
+                }
+            },
+            ConfigUpdate::ServiceRemove(service_id) => {
+                self.remove(&service_id);
+            },
+            _ => {},
+        }
+    }
+}
+
+
+impl CORSMiddleware {
+    fn remove(&mut self, service_id: &str) {
+        self.service_cors.remove(service_id);
+        if let Some(path) = self.service_paths.remove(service_id) {
+            PATH_CORS.write().unwrap().remove(&path);
+        }
+    }
+}
+
+
+// add CORS headers of the service to auth and gateway error responses, which skip the middleware chain
+pub fn decorate_gateway_response(service_path: &str, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+    let origin = match origin.and_then(|o| o.to_str().ok()) {
+        Some(o) => o,
+        None => return,
+    };
+    if let Some(policy) = PATH_CORS.read().unwrap().get(service_path) {
+        if let Some(allowed_origin) = policy.allowed_origin(origin) {
+            policy.decorate(&allowed_origin, headers);
+        }
+    }
+}
+
+
+// a preflight request is an OPTIONS request carrying Origin and Access-Control-Request-Method
+fn is_preflight<T>(req: &Request<T>) -> bool {
+    req.method() == Method::OPTIONS
+        && req.headers().contains_key(header::ORIGIN)
+        && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+
+#[derive(Debug, Clone)]
+pub struct CORSPolicy {
+    any_origin: bool,
+    origins: Vec<String>,
+    origin_patterns: Vec<Regex>,
+    methods: Vec<String>,
+    any_header: bool,
+    headers: Vec<String>,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: u64,
+}
+
+
+impl CORSPolicy {
+
+    pub fn new(setting: &CORSSetting) -> Self {
+        let mut origin_patterns = Vec::new();
+        for p in &setting.allow_origin_patterns {
+            if let Ok(pattern) = Regex::new(p) {
+                origin_patterns.push(pattern);
+            } else {
+                event!(Level::ERROR, "bad origin regex pattern {}", p);
+            }
+        }
+        CORSPolicy {
+            any_origin: setting.allow_origins.iter().any(|o| o.eq("*")),
+            origins: setting.allow_origins.clone(),
+            origin_patterns,
+            methods: setting.allow_methods.iter().map(|m| m.to_uppercase()).collect(),
+            any_header: setting.allow_headers.iter().any(|h| h.eq("*")),
+            headers: setting.allow_headers.iter().map(|h| h.to_lowercase()).collect(),
+            expose_headers: setting.expose_headers.clone(),
+            credentials: setting.allow_credentials,
+            max_age: setting.max_age,
+        }
+    }
+
+    // value for Access-Control-Allow-Origin, None if origin is not allowed
+    pub fn allowed_origin(&self, origin: &str) -> Option<String> {
+        if self.any_origin {
+            // wildcard is not allowed together with credentials, echo the origin instead
+            if self.credentials {
+                return Some(String::from(origin));
+            } else {
+                return Some(String::from("*"));
+            }
+        }
+        if self.origins.iter().any(|o| o.eq(origin)) || self.origin_patterns.iter().any(|p| p.is_match(origin)) {
+            Some(String::from(origin))
+        } else {
+            None
+        }
+    }
+
+    pub fn preflight<T>(&self, origin: &str, req: &Request<T>) -> Response<Body> {
+        let allowed_origin = self.allowed_origin(origin);
+        let method = req.headers().get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|m| m.to_str().ok())
+            .unwrap_or("");
+        let method_allowed = self.methods.iter().any(|m| m.eq("*") || m.eq_ignore_ascii_case(method));
+        let request_headers = req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("");
+        let headers_allowed = self.any_header || request_headers.split(',')
+            .map(|h| h.trim().to_lowercase())
+            .filter(|h| !h.is_empty())
+            .all(|h| self.headers.contains(&h));
+
+        let mut resp = Response::new(Body::empty());
+        match allowed_origin {
+            Some(allowed_origin) if method_allowed && headers_allowed => {
+                *resp.status_mut() = hyper::StatusCode::NO_CONTENT;
+                let headers = resp.headers_mut();
+                self.decorate(&allowed_origin, headers);
+                if let Ok(v) = HeaderValue::from_str(&self.methods.join(", ")) {
+                    headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, v);
+                }
+                let allow_headers = if self.any_header { request_headers.to_string() } else { self.headers.join(", ") };
+                if !allow_headers.is_empty() {
+                    if let Ok(v) = HeaderValue::from_str(&allow_headers) {
+                        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, v);
+                    }
+                }
+                if self.max_age > 0 {
+                    headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(self.max_age));
+                }
+            },
+            _ => {
+                *resp.status_mut() = hyper::StatusCode::FORBIDDEN;
+            },
+        }
+        resp
+    }
+
+    // add CORS headers to actual response
+    pub fn decorate(&self, allowed_origin: &str, headers: &mut HeaderMap) {
+        if let Ok(v) = HeaderValue::from_str(allowed_origin) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, v);
+        }
+        if allowed_origin != "*" {
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        if self.credentials {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        if !self.expose_headers.is_empty() {
+            if let Ok(v) = HeaderValue::from_str(&self.expose_headers.join(", ")) {
+                headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, v);
+            }
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use futures::Stream;
+use tokio::sync::mpsc;
+use tower::discover::Change;
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+
+// live discover stream of a balancer, fed by the service worker as upstreams change
+#[derive(Debug)]
+pub struct UpstreamDiscover<S> {
+    rx: mpsc::UnboundedReceiver<Change<String, S>>,
+}
+
+
+pub type DiscoverSender<S> = mpsc::UnboundedSender<Change<String, S>>;
+
+
+pub fn discover_channel<S>() -> (DiscoverSender<S>, UpstreamDiscover<S>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (tx, UpstreamDiscover { rx })
+}
+
+
+impl<S> Stream for UpstreamDiscover<S> {
+    type Item = Result<Change<String, S>, Infallible>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().rx.poll_recv(cx).map(|change| change.map(Ok))
+    }
+}
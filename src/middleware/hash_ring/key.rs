@@ -0,0 +1,61 @@
+use hyper::{Body, Request};
+use crate::config::HashKeySetting;
+use crate::middleware::RequestContext;
+use crate::middleware::canary::{client_address, cookie};
+
+
+// hashed balance key of a request, attached to request extensions by service worker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HashKey(pub u64);
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HashKeySource {
+    Client,
+    Ip,
+    Header(String),
+    Cookie(String),
+    Path,
+}
+
+
+impl HashKeySource {
+
+    pub fn new(setting: Option<&HashKeySetting>) -> Self {
+        match setting {
+            Some(s) => match s.source.as_str() {
+                "client" => HashKeySource::Client,
+                "ip" => HashKeySource::Ip,
+                "cookie" => HashKeySource::Cookie(s.name.clone()),
+                "path" => HashKeySource::Path,
+                _ => HashKeySource::Header(s.name.to_lowercase()),
+            },
+            None => HashKeySource::Header(String::from("x-lb-hash")),
+        }
+    }
+
+    // requests without the key value share one ring node
+    pub fn key(&self, context: &RequestContext, request: &Request<Body>) -> HashKey {
+        let value = match self {
+            HashKeySource::Client => Some(context.client_id.clone()),
+            HashKeySource::Ip => client_address(request),
+            HashKeySource::Header(name) => request.headers().get(name.as_str())
+                .and_then(|v| v.to_str().ok())
+                .map(String::from),
+            HashKeySource::Cookie(name) => cookie(request.headers(), name).map(String::from),
+            HashKeySource::Path => Some(context.api_path.clone()),
+        };
+        HashKey(hash(value.unwrap_or_default().as_bytes()))
+    }
+}
+
+
+// fnv-1a with a splitmix64 finalizer, stable across gateway instances and spreads sequential keys
+pub fn hash(data: &[u8]) -> u64 {
+    let mut h = data.iter().fold(0xcbf29ce484222325u64, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3));
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58476d1ce4e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d049bb133111eb);
+    h ^ (h >> 31)
+}
@@ -0,0 +1,5 @@
+mod key;
+mod service;
+
+pub use key::{HashKey, HashKeySource};
+pub use service::HashRingBalance;
@@ -0,0 +1,172 @@
+use tower::discover::{Change, Discover};
+use tower::load::Load;
+use tower::ready_cache::ReadyCache;
+use futures_util::ready;
+use futures_util::future::{self, TryFutureExt};
+use hyper::{Body, Request};
+use std::fmt::Display;
+use std::hash::Hash;
+use std::{pin::Pin, task::{Context, Poll}};
+use tower::Service;
+use tracing::{debug, trace};
+use super::key::{HashKey, hash};
+use crate::middleware::GatewayError;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// ring points of an upstream with average weight
+const POINTS_PER_NODE: u64 = 160;
+
+
+/// Consistent hash ring, use load as service weight.
+/// Requests go to the first ready service clockwise from their key.
+pub struct HashRingBalance<D>
+where
+    D: Discover,
+    D::Key: Hash,
+{
+    discover: D,
+    services: ReadyCache<D::Key, D::Service, Request<Body>>,
+    nodes: Vec<(D::Key, u32)>,
+    ring: Vec<(u64, D::Key)>,
+}
+
+
+impl<D> HashRingBalance<D>
+where
+    D: Discover,
+    D::Key: Hash,
+    D::Service: Service<Request<Body>>,
+{
+    pub fn new(discover: D) -> Self {
+        HashRingBalance {
+            discover,
+            services: ReadyCache::default(),
+            nodes: Vec::new(),
+            ring: Vec::new(),
+        }
+    }
+}
+
+
+impl<D> HashRingBalance<D>
+where
+    D: Discover + Unpin,
+    D::Key: Hash + Clone + Display,
+    D::Error: Into<BoxError>,
+    D::Service: Service<Request<Body>> + Load,
+    <D::Service as Load>::Metric: Into<u32>,
+    <D::Service as Service<Request<Body>>>::Error: Into<BoxError>,
+{
+    fn update_pending_from_discover(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(), BoxError>>> {
+        debug!("updating from discover");
+        loop {
+            match ready!(Pin::new(&mut self.discover).poll_discover(cx))
+                .transpose()
+                .map_err(|e| e.into())?
+            {
+                None => return Poll::Ready(None),
+                Some(Change::Remove(key)) => {
+                    trace!("remove");
+                    self.services.evict(&key);
+                    self.nodes.retain(|(k, _)| k != &key);
+                    self.build_ring();
+                },
+                Some(Change::Insert(key, svc)) => {
+                    trace!("insert");
+                    let weight = svc.load().into();
+                    self.nodes.retain(|(k, _)| k != &key);
+                    self.nodes.push((key.clone(), weight));
+                    self.services.push(key, svc);
+                    self.build_ring();
+                },
+            }
+        }
+    }
+
+    // ketama style ring, points of a node depend on its key and weight only relative to others
+    fn build_ring(&mut self) {
+        let total: u64 = self.nodes.iter().map(|(_, w)| *w as u64).sum();
+        let count = self.nodes.len() as u64;
+        let mut ring = Vec::new();
+        for (key, weight) in &self.nodes {
+            let points = (POINTS_PER_NODE * count * (*weight as u64))
+                .checked_div(total)
+                .map(|p| p.max(1))
+                .unwrap_or(POINTS_PER_NODE);
+            for i in 0..points {
+                ring.push((hash(format!("{}#{}", key, i).as_bytes()), key.clone()));
+            }
+        }
+        ring.sort_by_key(|(point, _)| *point);
+        self.ring = ring;
+    }
+
+    fn promote_pending_to_ready(&mut self, cx: &mut Context<'_>) {
+        loop {
+            match self.services.poll_pending(cx) {
+                Poll::Ready(Ok(())) => break,
+                Poll::Pending => break,
+                Poll::Ready(Err(error)) => {
+                    debug!(%error, "dropping failed endpoint");
+                }
+            }
+        }
+        trace!(
+            ready = %self.services.ready_len(),
+            pending = %self.services.pending_len(),
+            "poll_unready"
+        );
+    }
+
+    // first ready node clockwise from key, skipping unavailable nodes
+    fn ready_index_for(&self, key: u64) -> Option<usize> {
+        if self.ring.is_empty() {
+            return None;
+        }
+        let start = self.ring.partition_point(|(point, _)| *point < key);
+        (0..self.ring.len())
+            .map(|i| &self.ring[(start + i) % self.ring.len()].1)
+            .find_map(|k| self.services.get_ready(k).map(|(index, _, _)| index))
+    }
+}
+
+
+impl<D> Service<Request<Body>> for HashRingBalance<D>
+where
+    D: Discover + Unpin,
+    D::Key: Hash + Clone + Display,
+    D::Error: Into<BoxError>,
+    D::Service: Service<Request<Body>> + Load,
+    <D::Service as Load>::Metric: Into<u32>,
+    <D::Service as Service<Request<Body>>>::Error: Into<BoxError>,
+{
+    type Response = <D::Service as Service<Request<Body>>>::Response;
+    type Error = BoxError;
+    type Future = future::MapErr<
+        <D::Service as Service<Request<Body>>>::Future,
+        fn(<D::Service as Service<Request<Body>>>::Error) -> BoxError,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let _ = self.update_pending_from_discover(cx)?;
+        self.promote_pending_to_ready(cx);
+        if self.services.ready_len() > 0 {
+            Poll::Ready(Ok(()))
+        } else {
+            // waiting would hold the service worker until a breaker's retry delay ends, fail fast instead
+            Poll::Ready(Err(GatewayError::ServiceNotReady("No upstream available".into()).into()))
+        }
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let key = request.extensions().get::<HashKey>().copied().unwrap_or(HashKey(hash(b"")));
+        let index = self.ready_index_for(key.0).expect("called before ready");
+        self.services
+            .call_ready_index(index, request)
+            .map_err(Into::into)
+    }
+}
@@ -0,0 +1,210 @@
+use hyper::{Body, Method, Request, Response, header};
+use tower::{Service, ServiceExt};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::config::HedgeSetting;
+use crate::middleware::ForwardInfo;
+use crate::trace::TraceContext;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+
+lazy_static::lazy_static! {
+    static ref HEDGE_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_hedged_requests_total",
+        "Hedged requests by outcome, sent, won or over budget",
+        &["service", "outcome"]
+    ).unwrap();
+}
+
+
+const BUCKETS: usize = 64;  // upper bound of bucket i is 1.25^i ms, last bucket is about 1.5 minutes
+const BUCKET_GROWTH: f64 = 1.25;
+const HISTORY: Duration = Duration::from_secs(10);  // latencies of last one or two periods are used
+const MIN_SAMPLES: u64 = 20;  // latencies needed before percentile delay applies
+const MAX_BUDGET: f64 = 10.0;  // hedges that can be saved up
+
+
+// upstream that received a request, set by ProxyHandler
+#[derive(Debug, Clone, Default)]
+pub struct HedgeOrigin(Arc<Mutex<Option<String>>>);
+
+
+impl HedgeOrigin {
+
+    pub fn set(&self, upstream_id: &str) {
+        *self.0.lock().unwrap() = Some(String::from(upstream_id));
+    }
+
+    pub fn get(&self) -> Option<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+
+#[derive(Debug)]
+struct HedgeState {
+    current: [u64; BUCKETS],
+    previous: [u64; BUCKETS],
+    rotated: Instant,
+    budget: f64,
+}
+
+
+/// Hedging policy of a service. A copy of a slow idempotent request is sent to another upstream
+/// after a fixed delay or a percentile of recent latencies, hedges are limited to budget_percent of requests.
+#[derive(Debug)]
+pub struct Hedger {
+    service_id: String,
+    delay: Option<Duration>,  // None for percentile delay
+    percentile: f64,
+    ratio: f64,
+    state: Mutex<HedgeState>,
+}
+
+
+impl Hedger {
+
+    pub fn new(service_id: &str, setting: &HedgeSetting) -> Arc<Self> {
+        let percentile = if setting.percentile > 0.0 { setting.percentile.min(100.0) } else { 95.0 };
+        let percent = if setting.budget_percent > 0 { setting.budget_percent.min(100) } else { 10 };
+        Arc::new(Hedger {
+            service_id: String::from(service_id),
+            delay: if setting.delay > 0 { Some(Duration::from_millis(setting.delay)) } else { None },
+            percentile: percentile / 100.0,
+            ratio: percent as f64 / 100.0,
+            state: Mutex::new(HedgeState {
+                current: [0; BUCKETS],
+                previous: [0; BUCKETS],
+                rotated: Instant::now(),
+                budget: MAX_BUDGET,
+            }),
+        })
+    }
+
+    // safe methods without body can be sent twice, each one adds to the hedge budget
+    pub fn eligible(&self, request: &Request<Body>) -> bool {
+        let safe = matches!(*request.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+        let has_body = request.headers().contains_key(header::TRANSFER_ENCODING)
+            || request.headers().get(header::CONTENT_LENGTH).map(|v| v != "0").unwrap_or(false);
+        if safe && !has_body {
+            let mut state = self.state.lock().unwrap();
+            state.budget = (state.budget + self.ratio).min(MAX_BUDGET);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Await primary request, send the copy to backup if primary is slower than hedge delay.
+    /// First response wins and the other request is dropped, an error waits for the other request.
+    pub async fn hedge<F, S>(self: Arc<Self>, primary: F, backup: Option<(S, Request<Body>)>) -> Result<Response<Body>, BoxError>
+        where F: Future<Output=Result<Response<Body>, BoxError>>,
+              S: Service<Request<Body>, Response=Response<Body>, Error=BoxError>,
+    {
+        let start = Instant::now();
+        tokio::pin!(primary);
+        let delay = match (&backup, self.delay()) {
+            (Some(_), Some(delay)) => delay,
+            _ => return self.observe(primary.await, start),
+        };
+        tokio::select! {
+            result = &mut primary => return self.observe(result, start),
+            _ = tokio::time::sleep(delay) => {},
+        }
+        if !self.withdraw() {
+            HEDGE_COUNTER.with_label_values(&[&self.service_id, "over_budget"]).inc();
+            return self.observe(primary.await, start);
+        }
+        HEDGE_COUNTER.with_label_values(&[&self.service_id, "sent"]).inc();
+        let hedge_start = Instant::now();
+        let (svc, copy) = backup.unwrap();
+        let hedged = svc.oneshot(copy);
+        tokio::pin!(hedged);
+        tokio::select! {
+            result = &mut primary => match result {
+                Ok(resp) => self.observe(Ok(resp), start),
+                Err(_) => self.observe(hedged.await, hedge_start),
+            },
+            result = &mut hedged => match result {
+                Ok(resp) => {
+                    HEDGE_COUNTER.with_label_values(&[&self.service_id, "won"]).inc();
+                    self.observe(Ok(resp), hedge_start)
+                },
+                Err(_) => self.observe(primary.await, start),
+            },
+        }
+    }
+
+    fn delay(&self) -> Option<Duration> {
+        if self.delay.is_some() {
+            return self.delay;
+        }
+        let mut state = self.state.lock().unwrap();
+        Self::rotate(&mut state);
+        let counts: Vec<u64> = state.current.iter().zip(state.previous.iter()).map(|(c, p)| c + p).collect();
+        let total: u64 = counts.iter().sum();
+        if total < MIN_SAMPLES {
+            return None;
+        }
+        let rank = (total as f64 * self.percentile).ceil() as u64;
+        let mut seen = 0;
+        for (i, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(Duration::from_secs_f64(BUCKET_GROWTH.powi(i as i32) / 1000.0));
+            }
+        }
+        None
+    }
+
+    fn withdraw(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.budget >= 1.0 {
+            state.budget -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn observe(&self, result: Result<Response<Body>, BoxError>, start: Instant) -> Result<Response<Body>, BoxError> {
+        if result.is_ok() {
+            let ms = start.elapsed().as_secs_f64() * 1000.0;
+            let bucket = if ms <= 1.0 { 0 } else { (ms.ln() / BUCKET_GROWTH.ln()).ceil() as usize };
+            let mut state = self.state.lock().unwrap();
+            Self::rotate(&mut state);
+            state.current[bucket.min(BUCKETS - 1)] += 1;
+        }
+        result
+    }
+
+    fn rotate(state: &mut HedgeState) {
+        let elapsed = state.rotated.elapsed();
+        if elapsed >= HISTORY {
+            state.previous = if elapsed >= HISTORY * 2 { [0; BUCKETS] } else { state.current };
+            state.current = [0; BUCKETS];
+            state.rotated = Instant::now();
+        }
+    }
+}
+
+
+// copy of a request without body for the hedge upstream
+pub fn copy_request(request: &Request<Body>) -> Request<Body> {
+    let mut copy = Request::builder()
+        .method(request.method().clone())
+        .uri(request.uri().clone())
+        .version(request.version())
+        .body(Body::empty())
+        .unwrap();
+    *copy.headers_mut() = request.headers().clone();
+    if let Some(info) = request.extensions().get::<ForwardInfo>() {
+        copy.extensions_mut().insert(info.clone());
+    }
+    if let Some(trace) = request.extensions().get::<TraceContext>() {
+        copy.extensions_mut().insert(trace.clone());
+    }
+    copy
+}
@@ -1,8 +1,27 @@
 use hyper::http::HeaderValue;
+use hyper::{Body, Request, Response, HeaderMap, header};
+use hyper::body::{HttpBody, SizeHint};
+use hyper::body::Bytes;
+use futures::ready;
+use chrono::{DateTime, Local, SecondsFormat};
+use serde_json::json;
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
 use std::{pin::Pin, time::SystemTime};
 use std::future::Future;
-use crate::middleware::{MwPostRequest, MwPreRequest, MwPostResponse, Middleware};
-use crate::config::ConfigUpdate;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tracing::{event, Level};
+use crate::middleware::{MwPostRequest, MwPreRequest, MwPreResponse, MwPostResponse, Middleware, MwNextAction, ForwardInfo, RequestContext};
+use crate::config::{ConfigUpdate, FilterSetting, MetricsSetting};
+use crate::auth::AuthResponse;
+use std::collections::HashMap;
+use glob::Pattern;
+use uuid::Uuid;
+use crate::redact;
 
 
 lazy_static::lazy_static! {
@@ -18,15 +37,25 @@ lazy_static::lazy_static! {
         &["service", "app", "upstream", "version"],
         vec![0.01, 0.02, 0.05, 0.1, 0.25, 0.5, 1.0, 3.0]
     ).unwrap();
+
+    static ref CACHE_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_cache_requests_total",
+        "Number of cacheable requests by cache result.",
+        &["service", "result"]
+    ).unwrap();
+
+    static ref ACCESS_LOG: RwLock<Option<AccessLogWriter>> = RwLock::new(None);
 }
 
 
 #[derive(Debug)]
-pub struct LoggerMiddleware {}
+pub struct LoggerMiddleware {
+    service_paths: HashMap<String, PathTemplates>,  // service_paths[service_id] = PathTemplates
+}
 
 impl Default for LoggerMiddleware {
     fn default() -> Self {
-        LoggerMiddleware {}
+        LoggerMiddleware { service_paths: HashMap::new() }
     }
 }
 
@@ -36,20 +65,24 @@ impl Middleware for LoggerMiddleware {
         "Logger".into()
     }
 
-    fn pre() -> bool {
-        false
-    }
-
     fn require_setting() -> bool {
         false
     }
 
-    fn request(&mut self, _task: MwPreRequest) -> Pin<Box<dyn Future<Output=()> + Send>> {
-        panic!("never got here");
+    fn request(&mut self, task: MwPreRequest) -> Pin<Box<dyn Future<Output=()> + Send>> {
+        let MwPreRequest {mut context, mut request, service_filters, client_filters, result} = task;
+        if access_log_enabled() && sampled(&service_filters, &client_filters) {
+            let entry = AccessLogEntry::new(&context, &request);
+            // request body bytes are counted by proxy while it streams to upstream
+            request.extensions_mut().insert(BodyCounter(entry.bytes_in.clone()));
+            context.access_log = Some(entry);
+        }
+        let _ = result.send(Ok(MwPreResponse { context, next: MwNextAction::Next(request) }));
+        Box::pin(async {})
     }
 
     fn response(&mut self, task: MwPostRequest) -> Pin<Box<dyn Future<Output=()> + Send>> {
-        let MwPostRequest {context, response, service_filters: _, client_filters: _, result} = task;
+        let MwPostRequest {mut context, mut response, service_filters: _, client_filters: _, result} = task;
         let status = response.status().as_u16().to_string();
         let empty_value = HeaderValue::from_static("");
         let upstream = response.headers().get("X-UPSTREAM-ID").unwrap_or(&empty_value).to_str().unwrap();
@@ -63,7 +96,10 @@ impl Middleware for LoggerMiddleware {
             version,
         ]).observe(elapsed.as_secs_f64());
 
-        let path = context.api_path.clone();
+        let path = match self.service_paths.get(&context.service_id) {
+            Some(templates) => templates.label(&context.api_path),
+            None => context.api_path.clone(),
+        };
         HTTP_COUNTER.with_label_values(&[
             &context.service_id, 
             &context.client_id, 
@@ -73,14 +109,423 @@ impl Middleware for LoggerMiddleware {
             &path,
         ]).inc_by(1);
 
+        if let Some(cache_status) = response.headers().get("X-CACHE") {
+            let cache_status = cache_status.to_str().unwrap_or("").to_lowercase();
+            CACHE_COUNTER.with_label_values(&[
+                &context.service_id,
+                &cache_status,
+            ]).inc();
+        }
+
+        // access log is written by RequestHandler when response body is done
+        if let Some(mut entry) = context.access_log.take() {
+            entry.status = response.status().as_u16();
+            entry.total = elapsed;
+            if let Some(info) = response.extensions().get::<UpstreamInfo>() {
+                entry.upstream_id = info.id.clone();
+                entry.upstream_version = info.version.clone();
+                entry.upstream = Some(info.elapsed);
+            }
+            response.extensions_mut().insert(PendingAccessLog(entry));
+        }
+
         let response = MwPostResponse {context: context, response: response };
         let _ = result.send(Ok(response));
         Box::pin(async {})
     }
 
-    fn config_update(&mut self, _update: ConfigUpdate) {}
+    fn config_update(&mut self, update: ConfigUpdate) {
+        match update {
+            ConfigUpdate::ServiceUpdate(service) => {
+                let setting = service.filters.iter().find_map(|f| {
+                    if let FilterSetting::Metrics(m) = f { Some(m) } else { None }
+                });
+                if let Some(setting) = setting {
+                    self.service_paths.insert(service.service_id.clone(), PathTemplates::new(setting));
+                } else {
+                    self.service_paths.remove(&service.service_id);
+                }
+            },
+            ConfigUpdate::ServiceRemove(service_id) => {
+                self.service_paths.remove(&service_id);
+            },
+            _ => {},
+        }
+    }
+
+}
+
+
+#[derive(Debug, Clone)]
+enum PathMatcher {
+    Segments(Vec<Option<String>>),  // None matches any single segment
+    Glob(Pattern),
+}
+
+
+// collapse api paths to bounded set of metric labels
+#[derive(Debug, Clone)]
+pub struct PathTemplates {
+    templates: Vec<(String, PathMatcher)>,
+    collapse_ids: bool,
+}
+
+
+impl PathTemplates {
+
+    pub fn new(setting: &MetricsSetting) -> Self {
+        let mut templates = Vec::new();
+        for t in &setting.path_templates {
+            if t.contains('{') {
+                let segments = t.split('/')
+                    .map(|seg| if seg.starts_with('{') && seg.ends_with('}') { None } else { Some(String::from(seg)) })
+                    .collect();
+                templates.push((t.clone(), PathMatcher::Segments(segments)));
+            } else if let Ok(p) = Pattern::new(t) {
+                templates.push((t.clone(), PathMatcher::Glob(p)));
+            } else {
+                event!(Level::ERROR, "bad path template {}", t);
+            }
+        }
+        PathTemplates { templates, collapse_ids: setting.collapse_ids }
+    }
+
+    pub fn label(&self, path: &str) -> String {
+        for (label, matcher) in &self.templates {
+            let matched = match matcher {
+                PathMatcher::Segments(segments) => {
+                    let parts: Vec<&str> = path.split('/').collect();
+                    parts.len() == segments.len() && parts.iter().zip(segments.iter()).all(|(p, s)| {
+                        s.as_ref().map(|s| s == p).unwrap_or(!p.is_empty())
+                    })
+                },
+                PathMatcher::Glob(pattern) => pattern.matches(path),
+            };
+            if matched {
+                return label.clone();
+            }
+        }
+        if self.collapse_ids {
+            path.split('/')
+                .map(|seg| if is_id(seg) { "{id}" } else { seg })
+                .collect::<Vec<&str>>()
+                .join("/")
+        } else {
+            String::from(path)
+        }
+    }
+}
+
+
+fn is_id(segment: &str) -> bool {
+    let hex = segment.chars().all(|c| c.is_ascii_hexdigit() || c == '-');
+    (!segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit()))
+        || (hex && segment.len() == 36 && segment.matches('-').count() == 4)
+        || (hex && segment.len() >= 16 && !segment.contains('-'))
+}
+
+
+// upstream call info, attached to response extensions by ProxyHandler
+#[derive(Debug, Clone)]
+pub struct UpstreamInfo {
+    pub id: String,
+    pub version: String,
+    pub elapsed: Duration,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessLogFormat {
+    Json,
+    Combined,
+}
+
+
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+    pub output: String,     // "stdout" or file path
+    pub format: AccessLogFormat,
+    pub rotation: String,   // "daily", "hourly" or "never"
+}
+
+
+#[derive(Debug, Clone)]
+struct AccessLogWriter {
+    format: AccessLogFormat,
+    writer: NonBlocking,
+}
+
+
+// setup access log output, the returned guard flushes pending lines on drop
+pub fn init_access_log(config: &AccessLogConfig) -> WorkerGuard {
+    let (writer, guard) = if config.output == "stdout" {
+        tracing_appender::non_blocking(std::io::stdout())
+    } else {
+        let path = Path::new(&config.output);
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let file_name = path.file_name().expect("Invalid access log path");
+        let appender = match config.rotation.as_str() {
+            "hourly" => tracing_appender::rolling::hourly(dir, file_name),
+            "never" => tracing_appender::rolling::never(dir, file_name),
+            _ => tracing_appender::rolling::daily(dir, file_name),
+        };
+        tracing_appender::non_blocking(appender)
+    };
+    *ACCESS_LOG.write().unwrap() = Some(AccessLogWriter { format: config.format, writer });
+    guard
+}
+
+
+fn access_log_enabled() -> bool {
+    ACCESS_LOG.read().unwrap().is_some()
+}
+
+
+// SLA sample ratio overrides service sample ratio, all requests are logged by default
+fn sampled(service_filters: &[FilterSetting], client_filters: &[FilterSetting]) -> bool {
+    let ratio = client_filters.iter().chain(service_filters.iter()).find_map(|f| {
+        if let FilterSetting::AccessLog(s) = f { Some(s.sample_ratio) } else { None }
+    });
+    match ratio {
+        Some(ratio) => ratio >= 1.0 || rand::random::<f64>() < ratio,
+        None => true,
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct AccessLogEntry {
+    timestamp: DateTime<Local>,
+    request_id: String,
+    client_ip: String,
+    client_id: String,
+    service_id: String,
+    sla: String,
+    method: String,
+    path: String,
+    version: String,
+    referer: String,
+    user_agent: String,
+    status: u16,
+    upstream_id: String,
+    upstream_version: String,
+    total: Duration,
+    upstream: Option<Duration>,
+    bytes_in: Arc<AtomicU64>,
+    bytes_out: u64,
+}
+
+
+impl AccessLogEntry {
+
+    fn new(context: &RequestContext, request: &Request<Body>) -> Self {
+        let mut entry = Self::from_request(request, &context.request_id, context.start_time);
+        entry.client_id = context.client_id.clone();
+        entry.service_id = context.service_id.clone();
+        entry.sla = context.sla.clone();
+        entry
+    }
+
+    // entry for error responses generated by RequestHandler, which never pass Logger post filter
+    pub fn gateway_response(request: &Request<Body>, request_id: &Uuid) -> Option<Self> {
+        if access_log_enabled() {
+            Some(Self::from_request(request, request_id, SystemTime::now()))
+        } else {
+            None
+        }
+    }
+
+    fn from_request(request: &Request<Body>, request_id: &Uuid, start_time: SystemTime) -> Self {
+        let header = |name: header::HeaderName| {
+            request.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or("").to_string()
+        };
+        AccessLogEntry {
+            timestamp: DateTime::from(start_time),
+            request_id: request_id.to_string(),
+            client_ip: request.extensions().get::<ForwardInfo>().map(|i| i.client_ip.to_string()).unwrap_or_default(),
+            client_id: String::new(),
+            service_id: String::new(),
+            sla: String::new(),
+            method: request.method().to_string(),
+            path: request.uri().path_and_query().map(|pq| redact::path_and_query(pq.as_str())).unwrap_or_default(),
+            version: format!("{:?}", request.version()),
+            referer: redact::path_and_query(&header(header::REFERER)),
+            user_agent: header(header::USER_AGENT),
+            status: 0,
+            upstream_id: String::new(),
+            upstream_version: String::new(),
+            total: Duration::default(),
+            upstream: None,
+            bytes_in: Arc::new(AtomicU64::new(0)),
+            bytes_out: 0,
+        }
+    }
+
+    pub fn set_client(&mut self, auth: &AuthResponse) {
+        self.client_id = auth.client_id.clone();
+        self.service_id = auth.service_id.clone();
+        self.sla = auth.sla.clone();
+    }
+
+    // write entry for a response without upstream call, body is fully known
+    pub fn finish(mut self, response: &Response<Body>) {
+        self.status = response.status().as_u16();
+        self.total = SystemTime::from(self.timestamp).elapsed().unwrap_or_default();
+        self.bytes_out = HttpBody::size_hint(response.body()).exact().unwrap_or(0);
+        self.write();
+    }
+
+    fn to_json(&self) -> String {
+        let upstream_ms = self.upstream.map(|d| d.as_secs_f64() * 1000.0);
+        json!({
+            "timestamp": self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, false),
+            "request_id": self.request_id,
+            "client_ip": self.client_ip,
+            "client_id": self.client_id,
+            "service": self.service_id,
+            "sla": self.sla,
+            "method": self.method,
+            "path": self.path,
+            "status": self.status,
+            "upstream_id": self.upstream_id,
+            "upstream_version": self.upstream_version,
+            "latency_ms": {
+                "total": self.total.as_secs_f64() * 1000.0,
+                "upstream": upstream_ms,
+                "gateway": self.total.as_secs_f64() * 1000.0 - upstream_ms.unwrap_or(0.0),
+            },
+            "bytes_in": self.bytes_in.load(Ordering::Relaxed),
+            "bytes_out": self.bytes_out,
+        }).to_string()
+    }
+
+    // Apache combined log format, followed by gateway fields
+    fn to_combined(&self) -> String {
+        let dash = |s: &str| if s.is_empty() { String::from("-") } else { String::from(s) };
+        format!("{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {} {} {} {} {:.3} {} {}",
+            dash(&self.client_ip),
+            dash(&self.client_id),
+            self.timestamp.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method, self.path, self.version,
+            self.status,
+            self.bytes_out,
+            dash(&self.referer),
+            dash(&self.user_agent),
+            self.request_id,
+            dash(&self.service_id),
+            dash(&self.sla),
+            dash(&self.upstream_id),
+            self.total.as_secs_f64() * 1000.0,
+            self.upstream.map(|d| format!("{:.3}", d.as_secs_f64() * 1000.0)).unwrap_or_else(|| String::from("-")),
+            self.bytes_in.load(Ordering::Relaxed),
+        )
+    }
+
+    fn write(&self) {
+        if let Some(log) = ACCESS_LOG.read().unwrap().as_ref() {
+            let mut line = match log.format {
+                AccessLogFormat::Json => self.to_json(),
+                AccessLogFormat::Combined => self.to_combined(),
+            };
+            line.push('\n');
+            let _ = log.writer.clone().write_all(line.as_bytes());
+        }
+    }
+}
+
+
+// byte counter of a request body, attached by Logger
+#[derive(Clone)]
+struct BodyCounter(Arc<AtomicU64>);
+
+
+// access log entry of a response, written once its body is sent
+struct PendingAccessLog(AccessLogEntry);
+
+
+// counts body bytes, writes access log entry when body ends or is dropped.
+// Size hint is forwarded, fixed length bodies keep their Content-Length framing.
+pub struct CountingBody {
+    inner: Body,
+    counter: Option<Arc<AtomicU64>>,
+    entry: Option<AccessLogEntry>,
+}
+
+
+impl CountingBody {
+
+    // request sent to upstream, body is counted if Logger sampled it
+    pub fn request(request: Request<Body>) -> Request<CountingBody> {
+        let counter = request.extensions().get::<BodyCounter>().map(|c| c.0.clone());
+        request.map(|inner| CountingBody { inner, counter, entry: None })
+    }
+
+    // response sent to client, pending access log entry is written with its body size
+    pub fn response(mut response: Response<Body>) -> Response<CountingBody> {
+        let entry = response.extensions_mut().remove::<PendingAccessLog>().map(|p| p.0);
+        let counter = entry.as_ref().map(|_| Arc::new(AtomicU64::new(0)));
+        response.map(|inner| CountingBody { inner, counter, entry })
+    }
+}
+
+
+impl HttpBody for CountingBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        let chunk = ready!(Pin::new(&mut this.inner).poll_data(cx));
+        if let (Some(Ok(data)), Some(counter)) = (&chunk, &this.counter) {
+            counter.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        Poll::Ready(chunk)
+    }
+
+    fn poll_trailers(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
 
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
 }
 
 
+impl Drop for CountingBody {
+    fn drop(&mut self) {
+        if let (Some(mut entry), Some(counter)) = (self.entry.take(), &self.counter) {
+            entry.bytes_out = counter.load(Ordering::Relaxed);
+            entry.write();
+        }
+    }
+}
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn counting_body_keeps_size_hint() {
+        let counter = Arc::new(AtomicU64::new(0));
+        let mut request = Request::post("/svc/api").body(Body::from("hello")).unwrap();
+        request.extensions_mut().insert(BodyCounter(counter.clone()));
+        let request = CountingBody::request(request);
+        assert_eq!(request.body().size_hint().exact(), Some(5));
+        hyper::body::to_bytes(request.into_body()).await.unwrap();
+        assert_eq!(counter.load(Ordering::Relaxed), 5);
+
+        let empty = CountingBody::request(Request::get("/svc/api").body(Body::empty()).unwrap());
+        assert!(empty.body().is_end_stream());
+        assert_eq!(empty.body().size_hint().exact(), Some(0));
+
+        let response = CountingBody::response(Response::new(Body::from("response")));
+        assert_eq!(response.body().size_hint().exact(), Some(8));
+        assert!(response.body().counter.is_none());
+    }
+}
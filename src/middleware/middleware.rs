@@ -6,10 +6,21 @@ use tokio::sync::oneshot;
 use std::future::Future;
 use tracing::{span, Level, Instrument};
 use crate::{auth::AuthResponse, config::ConfigUpdate, config::FilterSetting};
+use crate::middleware::{CacheLookup, BodyLimitExceeded, AccessLogEntry};
+use crate::trace::{TraceContext, SpanRecord, SpanKind};
 use uuid::Uuid;
 use thiserror::Error;
 
 
+lazy_static::lazy_static! {
+    static ref MW_QUEUE_DEPTH: prometheus::IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "gateway_middleware_queue_depth",
+        "Number of tasks waiting in middleware channel.",
+        &["middleware"]
+    ).unwrap();
+}
+
+
 #[derive(Error, Debug, Clone)]
 pub enum GatewayError {
     #[error("Upstream request timeout")]
@@ -30,6 +41,9 @@ pub enum GatewayError {
     #[error("URL Access Deny")]
     AccessBlocked(String),
 
+    #[error("Payload too large")]
+    PayloadTooLarge(String),
+
     #[error("Interal server error")]
     GatewayInteralError(String),
 
@@ -42,6 +56,14 @@ pub enum GatewayError {
 
 impl From<hyper::Error> for GatewayError {
     fn from(e: hyper::Error) -> Self {
+        // request body stream aborted by BodyLimit middleware
+        let mut source = std::error::Error::source(&e);
+        while let Some(err) = source {
+            if err.is::<BodyLimitExceeded>() {
+                return GatewayError::PayloadTooLarge("Request body too large".into());
+            }
+            source = err.source();
+        }
         let msg = format!("Upstream service error: {:?}", e);
         GatewayError::UpstreamError(msg.into())
     }
@@ -85,6 +107,8 @@ pub struct MwPreResponse {
 pub enum MwNextAction {
     Next(Request<Body>),
     Return(Response<Body>),
+    // return response immediately, keep running inner chain with request in background
+    ReturnAndContinue(Response<Body>, Request<Body>),
 }
 
 #[derive(Debug)]
@@ -151,6 +175,11 @@ pub struct RequestContext {
     pub service_filters: HashMap<String, Vec<FilterSetting>>,
     pub client_filters: HashMap<String, Vec<FilterSetting>>,
     pub request_id: Uuid,
+    pub trace: TraceContext,
+    pub cors_origin: Option<String>,
+    pub cache_lookup: Option<CacheLookup>,
+    pub response_encoding: Option<String>,
+    pub access_log: Option<AccessLogEntry>,
 }
 
 impl RequestContext {
@@ -167,6 +196,11 @@ impl RequestContext {
             service_filters: HashMap::new(),
             client_filters: HashMap::new(),
             request_id: req_id,
+            trace: req.extensions().get::<TraceContext>().cloned().unwrap_or_else(|| TraceContext::new_root(false)),
+            cors_origin: None,
+            cache_lookup: None,
+            response_encoding: None,
+            access_log: None,
         };
         
         // group FilterSettings by Middlewares
@@ -189,7 +223,7 @@ impl RequestContext {
         context
     }
 
-    fn split_path(path: &str) -> (String, String) {
+    pub fn split_path(path: &str) -> (String, String) {
         let path = path.strip_prefix("/").unwrap_or(path);
         let (service_path, api_path) = match path.find("/") {
             Some(pos) => {
@@ -202,13 +236,9 @@ impl RequestContext {
         (format!("/{}", service_path), String::from(api_path))
     }
 
-    fn extract_request_id(_req: &Request<Body>) -> Uuid {
-        // if let Some(value) = req.headers().get("request_id".into()) {
-        //     if let Ok(id) = Uuid::parse_str(value.to_str().unwrap_or("")) {
-        //         return id
-        //     }
-        // }
-        Uuid::new_v4()
+    // request id is resolved by RequestHandler and attached to request extensions
+    fn extract_request_id(req: &Request<Body>) -> Uuid {
+        req.extensions().get::<Uuid>().cloned().unwrap_or_else(Uuid::new_v4)
     }
 }
 
@@ -217,25 +247,30 @@ pub async fn start_middleware<MW>(mut tasks: mpsc::Receiver<MiddlewareRequest>,
 where MW: Middleware + Default
 {
     let mut mw = MW::default();
+    let queue_depth = MW_QUEUE_DEPTH.with_label_values(&[&MW::name()]);
 
     loop {
         tokio::select! {
             task = tasks.recv() => {
                 match task {
                     Some(MiddlewareRequest::Request(x)) => {
+                        queue_depth.dec();
                         let ctx = x.context.clone();
                         let span = span!(Level::DEBUG, "pre_filter",
                                         service=ctx.service_id.as_str(),
-                                        trace_id=ctx.request_id.to_string().as_str(),
+                                        trace_id=ctx.trace.trace_id_hex().as_str(),
+                                        request_id=ctx.request_id.to_string().as_str(),
                                         app_id=ctx.client_id.as_str(),
                                         middleware=MW::name().as_str());
                         mw.request(x).instrument(span).await;
                     },
                     Some(MiddlewareRequest::Response(x)) => {
+                        queue_depth.dec();
                         let ctx = x.context.clone();
                         let span = span!(Level::DEBUG, "post_filter",
                                         service=ctx.service_id.as_str(),
-                                        trace_id=ctx.request_id.to_string().as_str(),
+                                        trace_id=ctx.trace.trace_id_hex().as_str(),
+                                        request_id=ctx.request_id.to_string().as_str(),
                                         app_id=ctx.client_id.as_str(),
                                         middleware=MW::name().as_str());
                         mw.response(x).instrument(span).await;
@@ -296,6 +331,7 @@ pub fn middleware_chain(req: Request<Body>, context: RequestContext, mut mw_stac
         // request middleware pre-filter
         let pre_resp: Result<MwPreResponse, GatewayError> = {
             if pre {
+                let mut span = SpanRecord::start(&format!("{} pre_filter", name), SpanKind::Internal, &context.trace.child());
                 let (tx, rx) = oneshot::channel();
                 let pre_req = MwPreRequest {
                     context,
@@ -304,10 +340,18 @@ pub fn middleware_chain(req: Request<Body>, context: RequestContext, mut mw_stac
                     client_filters: client_filters,
                     result: tx,
                 };
-                let _ = chan.send(MiddlewareRequest::Request(pre_req)).await;
+                let queue_depth = MW_QUEUE_DEPTH.with_label_values(&[&name]);
+                queue_depth.inc();
+                if chan.send(MiddlewareRequest::Request(pre_req)).await.is_err() {
+                    queue_depth.dec();
+                }
 
-                let result = rx.await??;
-                Ok(result)
+                let result = rx.await?;
+                if result.is_err() {
+                    span.set_error();
+                }
+                span.finish();
+                Ok(result?)
             } else {
                 Ok(MwPreResponse { context, next: MwNextAction::Next(req) })
             }
@@ -323,6 +367,7 @@ pub fn middleware_chain(req: Request<Body>, context: RequestContext, mut mw_stac
 
                 // call middleware post-filter
                 if post {
+                    let span = SpanRecord::start(&format!("{} post_filter", name), SpanKind::Internal, &context_copy.trace.child());
                     let (tx, rx) = oneshot::channel();
                     let post_req = MwPostRequest {
                         context: context_copy,
@@ -331,9 +376,18 @@ pub fn middleware_chain(req: Request<Body>, context: RequestContext, mut mw_stac
                         client_filters: resp_client_filters,
                         result: tx,
                     };
-                    let _ = chan.send(MiddlewareRequest::Response(post_req)).await;
-                    let resp =rx.await??;
-                    Ok(resp.response)
+                    let queue_depth = MW_QUEUE_DEPTH.with_label_values(&[&name]);
+                    queue_depth.inc();
+                    if chan.send(MiddlewareRequest::Response(post_req)).await.is_err() {
+                        queue_depth.dec();
+                    }
+                    let resp = rx.await?;
+                    let mut span = span;
+                    if resp.is_err() {
+                        span.set_error();
+                    }
+                    span.finish();
+                    Ok(resp?.response)
                 } else {
                     Ok(inner_resp)
                 }
@@ -341,7 +395,34 @@ pub fn middleware_chain(req: Request<Body>, context: RequestContext, mut mw_stac
             // if pre-filter returns response, terminate chain and return
             MwNextAction::Return(response) => {
                 Ok(response)
-            }
+            },
+            // return response, inner chain and post-filter run in background, e.g. cache revalidation
+            MwNextAction::ReturnAndContinue(response, request) => {
+                tokio::spawn(async move {
+                    let context_copy = context.clone();
+                    let inner_resp = middleware_chain(request, context, mw_stack).await;
+                    if let (Ok(inner_resp), true) = (inner_resp, post) {
+                        let (tx, rx) = oneshot::channel();
+                        let post_req = MwPostRequest {
+                            context: context_copy,
+                            response: inner_resp,
+                            service_filters: resp_service_filters,
+                            client_filters: resp_client_filters,
+                            result: tx,
+                        };
+                        let queue_depth = MW_QUEUE_DEPTH.with_label_values(&[&name]);
+                        queue_depth.inc();
+                        if chan.send(MiddlewareRequest::Response(post_req)).await.is_err() {
+                            queue_depth.dec();
+                        }
+                        if let Ok(Ok(resp)) = rx.await {
+                            // nobody reads this response, drain body so post-filters see it complete
+                            let _ = hyper::body::to_bytes(resp.response.into_body()).await;
+                        }
+                    }
+                });
+                Ok(response)
+            },
         }
     };
 
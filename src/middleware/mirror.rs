@@ -0,0 +1,225 @@
+use hyper::{Body, HeaderMap, Request, header};
+use hyper::header::HeaderValue;
+use hyper::body::Bytes;
+use futures::{ready, Stream};
+use tokio::sync::oneshot;
+use tower::Service;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use crate::config::{MirrorSetting, Upstream};
+use crate::middleware::ForwardInfo;
+use crate::middleware::proxy::ProxyHandler;
+use crate::trace::{TraceContext, TRACEPARENT, TRACESTATE};
+use crate::redact;
+
+
+lazy_static::lazy_static! {
+
+    static ref MIRROR_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_mirror_requests_total",
+        "Shadow requests by status code, error, skipped or dropped",
+        &["service", "status"]
+    ).unwrap();
+
+    static ref MIRROR_DURATION_HIST: prometheus::HistogramVec = prometheus::register_histogram_vec!(
+        "gateway_mirror_duration_seconds",
+        "Shadow request latency",
+        &["service", "status"]
+    ).unwrap();
+
+}
+
+
+// copies sampled requests to a shadow upstream, responses are discarded
+#[derive(Debug, Clone)]
+pub struct MirrorHandler {
+    service_id: String,
+    setting: MirrorSetting,
+    handler: ProxyHandler,
+    in_flight: Arc<AtomicU64>,
+}
+
+
+impl MirrorHandler {
+
+    pub fn new(service_id: &str, setting: &MirrorSetting, timeout: u32) -> Self {
+        let upstream = Upstream {
+            id: String::from("mirror"),
+            target: setting.target.clone(),
+            max_conn: setting.max_conn,
+            weight: 0,
+            version: String::from("mirror"),
+            priority: 0,
+            error_threshold: 0,
+            error_reset: 0,
+            retry_delay: 0,
+            error_rate: 0.0,
+            half_open_requests: 0,
+            half_open_success_ratio: 0.0,
+            breaker_header: false,
+            rewrite_host: setting.rewrite_host,
+            tls: None,
+            dns: None,
+            endpoints_file: None,
+            address: None,
+        };
+        MirrorHandler {
+            service_id: String::from(service_id),
+            setting: setting.clone(),
+            handler: ProxyHandler::new(service_id, &upstream, timeout),
+            in_flight: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // start shadow request for a sampled request, primary request body is teed into the copy
+    pub fn mirror(&self, request: Request<Body>) -> Request<Body> {
+        if self.setting.sample_ratio <= 0.0 || rand::random::<f64>() >= self.setting.sample_ratio {
+            return request;
+        }
+        if self.in_flight.load(Ordering::Relaxed) >= self.setting.max_conn {
+            self.record("dropped", None);
+            return request;
+        }
+        let content_length = request.headers().get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if content_length.map(|len| len > self.setting.max_body_size).unwrap_or(false) {
+            self.record("skipped", None);
+            return request;
+        }
+        let has_body = content_length.map(|len| len > 0).unwrap_or(false) || request.headers().contains_key(header::TRANSFER_ENCODING);
+
+        let (parts, body) = request.into_parts();
+        let mut shadow = Request::builder()
+            .method(parts.method.clone())
+            .uri(parts.uri.clone())
+            .version(parts.version)
+            .body(())
+            .unwrap();
+        *shadow.headers_mut() = parts.headers.clone();
+        redact::strip_headers(shadow.headers_mut());
+        if let Some(info) = parts.extensions.get::<ForwardInfo>() {
+            shadow.extensions_mut().insert(info.clone());
+        }
+        // shadow calls must not show up as part of the client trace
+        shadow.headers_mut().remove(TRACEPARENT);
+        shadow.headers_mut().remove(TRACESTATE);
+        shadow.extensions_mut().insert(TraceContext::new_root(false));
+
+        let (body, shadow_body) = if has_body {
+            let (tx, rx) = oneshot::channel();
+            let tee = TeeBody { inner: body, buffer: Vec::new(), limit: self.setting.max_body_size, length: content_length, done: Some(tx) };
+            (Body::wrap_stream(tee), Some(rx))
+        } else {
+            (body, None)
+        };
+
+        let this = self.clone();
+        this.in_flight.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(async move {
+            this.send(shadow, shadow_body).await;
+            this.in_flight.fetch_sub(1, Ordering::Relaxed);
+        });
+        Request::from_parts(parts, body)
+    }
+
+    async fn send(&self, shadow: Request<()>, body: Option<oneshot::Receiver<Bytes>>) {
+        // primary body is incomplete or over size limit if sender is dropped
+        let body = match body {
+            Some(rx) => match rx.await {
+                Ok(bytes) => bytes,
+                Err(_) => return self.record("skipped", None),
+            },
+            None => Bytes::new(),
+        };
+        let (mut parts, _) = shadow.into_parts();
+        let body = match Self::redact_body(&mut parts.headers, &body) {
+            Some(redacted) => Body::from(redacted),
+            None => Body::from(body),
+        };
+        let start = Instant::now();
+        let mut handler = self.handler.clone();
+        match handler.call(Request::from_parts(parts, body)).await {
+            Ok(resp) => {
+                let status = resp.status();
+                let _ = hyper::body::to_bytes(resp.into_body()).await;
+                self.record(status.as_str(), Some(start));
+            },
+            Err(_) => self.record("error", Some(start)),
+        }
+    }
+
+    // JSON body fields of redaction policy are replaced, content length follows the new body
+    fn redact_body(headers: &mut HeaderMap, body: &[u8]) -> Option<Vec<u8>> {
+        let is_json = headers.get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.contains("json"))
+            .unwrap_or(false);
+        let encoded = headers.get(header::CONTENT_ENCODING).map(|v| v != "identity").unwrap_or(false);
+        if !is_json || encoded || body.is_empty() {
+            return None;
+        }
+        let redacted = redact::json_body(body)?;
+        headers.remove(header::TRANSFER_ENCODING);
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(redacted.len()));
+        Some(redacted)
+    }
+
+    fn record(&self, status: &str, start: Option<Instant>) {
+        MIRROR_COUNTER.with_label_values(&[&self.service_id, status]).inc();
+        if let Some(start) = start {
+            MIRROR_DURATION_HIST.with_label_values(&[&self.service_id, status]).observe(start.elapsed().as_secs_f64());
+        }
+    }
+}
+
+
+// passes primary body through, keeps a copy up to limit for the shadow request
+struct TeeBody {
+    inner: Body,
+    buffer: Vec<u8>,
+    limit: u64,
+    length: Option<u64>,  // content length, the body stream is not polled to end once it is read
+    done: Option<oneshot::Sender<Bytes>>,
+}
+
+
+impl TeeBody {
+    fn finish(&mut self) {
+        if let Some(tx) = self.done.take() {
+            let _ = tx.send(Bytes::from(std::mem::take(&mut self.buffer)));
+        }
+    }
+}
+
+
+impl Stream for TeeBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let chunk = ready!(Pin::new(&mut this.inner).poll_next(cx));
+        match &chunk {
+            Some(Ok(data)) if this.done.is_some() => {
+                if (this.buffer.len() + data.len()) as u64 > this.limit {
+                    this.done = None;
+                    this.buffer = Vec::new();
+                } else {
+                    this.buffer.extend_from_slice(data);
+                    if this.length == Some(this.buffer.len() as u64) {
+                        this.finish();
+                    }
+                }
+            },
+            Some(Err(_)) => {
+                this.done = None;
+            },
+            None => this.finish(),
+            _ => {},
+        }
+        Poll::Ready(chunk)
+    }
+}
@@ -5,8 +5,23 @@ mod rate_limit;
 mod header;
 mod acl;
 mod logger;
+mod cors;
+mod cache;
+mod compression;
+mod body_limit;
+mod rewrite;
+mod canary;
+mod mirror;
 mod circuit_breaker;
 mod weighted;
+mod hash_ring;
+mod sticky;
+mod outlier;
+mod slow_start;
+mod priority;
+mod hedge;
+mod upstream_tls;
+mod discover;
 
 
 pub use middleware::{Middleware, MiddlewareRequest, MiddlewareHandle, RequestContext, 
@@ -17,8 +32,22 @@ pub use upstream::UpstreamMiddleware;
 pub use rate_limit::RateLimitMiddleware;
 pub use header::HeaderMiddleware;
 pub use acl::ACLMiddleware;
-pub use logger::LoggerMiddleware;
+pub use logger::{LoggerMiddleware, AccessLogEntry, CountingBody, AccessLogConfig, AccessLogFormat, UpstreamInfo, init_access_log};
+pub use cors::{CORSMiddleware, decorate_gateway_response};
+pub use cache::{CacheMiddleware, CacheLookup};
+pub use compression::CompressionMiddleware;
+pub use body_limit::{BodyLimitMiddleware, BodyLimitExceeded};
+pub use rewrite::{RewriteMiddleware, Rewriter};
+pub use canary::{CanaryRouter, UpstreamVersion};
+pub use mirror::MirrorHandler;
+pub use hash_ring::{HashKey, HashKeySource};
+pub use sticky::{StickyBalance, StickyCookie};
+pub use outlier::{OutlierDetector, OutlierService};
+pub use slow_start::{WarmUp, SlowStart, RampedDiscover};
+pub use priority::{PriorityBalance, PriorityLevel};
+pub use hedge::{Hedger, HedgeOrigin, copy_request};
 
 pub use circuit_breaker::{CircuitBreakerConfig, CircuitBreakerService};
+pub use proxy::ForwardInfo;
 
 
@@ -0,0 +1,389 @@
+use futures::ready;
+use hyper::{Body, Request, Response};
+use tower::Service;
+use tower::load_shed::error::Overloaded;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use pin_project::pin_project;
+use tracing::{event, Level};
+use crate::config::OutlierSetting;
+use crate::middleware::GatewayError;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+
+lazy_static::lazy_static! {
+    static ref OUTLIER_EJECTION_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_outlier_ejections_total",
+        "Number of upstream ejections by outlier detection.",
+        &["service", "upstream", "reason"]
+    ).unwrap();
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Success,
+    Error,           // 5xx response
+    GatewayFailure,  // 502, 503, 504 or no response
+}
+
+
+#[derive(Debug, Default)]
+struct HostStats {
+    refs: usize,  // registered services, stats are dropped with the last one
+    consecutive_5xx: u64,
+    consecutive_gateway_failure: u64,
+    success: u64,  // in current interval
+    total: u64,
+    ejections: u64,  // ejection time multiplier, decreased for each healthy interval
+    ejected_until: Option<Instant>,
+    waiting: Vec<Waker>,  // services polled while ejected, woken by a single timer at the end of ejection
+    wake_scheduled: bool,
+}
+
+
+#[derive(Debug)]
+struct DetectorState {
+    hosts: HashMap<String, HostStats>,
+    next_analysis: Instant,
+}
+
+
+/// Outlier detection over the upstreams of a service, shared by their services.
+/// Upstreams are ejected on consecutive errors or low success rate, no more than max_ejection_percent at a time.
+#[derive(Debug)]
+pub struct OutlierDetector {
+    service_id: String,
+    setting: OutlierSetting,
+    interval: Duration,
+    state: Mutex<DetectorState>,
+}
+
+
+impl OutlierDetector {
+
+    pub fn new(service_id: &str, setting: &OutlierSetting) -> Arc<Self> {
+        let interval = Duration::from_secs(if setting.interval > 0 { setting.interval } else { 10 });
+        Arc::new(OutlierDetector {
+            service_id: String::from(service_id),
+            setting: setting.clone(),
+            interval,
+            state: Mutex::new(DetectorState { hosts: HashMap::new(), next_analysis: Instant::now() + interval }),
+        })
+    }
+
+    pub fn layer<S>(self: &Arc<Self>, upstream_id: &str, inner: S) -> OutlierService<S> {
+        self.state.lock().unwrap().hosts.entry(String::from(upstream_id)).or_default().refs += 1;
+        let host = Host { id: String::from(upstream_id), detector: self.clone() };
+        OutlierService { inner, host: Some(Arc::new(host)) }
+    }
+
+    fn base_ejection_time(&self) -> Duration {
+        Duration::from_secs(if self.setting.base_ejection_time > 0 { self.setting.base_ejection_time } else { 30 })
+    }
+
+    fn max_ejection_time(&self) -> Duration {
+        Duration::from_secs(if self.setting.max_ejection_time > 0 { self.setting.max_ejection_time } else { 300 })
+            .max(self.base_ejection_time())
+    }
+
+    // remaining ejection time of an upstream, expired ejections are lifted
+    fn ejected(&self, upstream_id: &str, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        if now >= state.next_analysis {
+            self.analyze(&mut state, now);
+        }
+        let host = state.hosts.get_mut(upstream_id)?;
+        match host.ejected_until {
+            Some(until) if until > now => Some(until - now),
+            Some(_) => {
+                event!(Level::INFO, "Upstream {}/{} returns from ejection", self.service_id, upstream_id);
+                host.ejected_until = None;
+                None
+            },
+            None => None,
+        }
+    }
+
+    // register the waker of a service polled while its upstream is ejected, one timer per ejection wakes them all
+    fn wait(self: &Arc<Self>, upstream_id: &str, remaining: Duration, waker: &Waker) {
+        let mut state = self.state.lock().unwrap();
+        let host = match state.hosts.get_mut(upstream_id) {
+            Some(h) => h,
+            None => return,
+        };
+        host.waiting.retain(|w| !w.will_wake(waker));
+        host.waiting.push(waker.clone());
+        if host.wake_scheduled {
+            return;
+        }
+        host.wake_scheduled = true;
+        let detector = self.clone();
+        let upstream_id = String::from(upstream_id);
+        tokio::spawn(async move {
+            tokio::time::sleep(remaining).await;
+            let mut state = detector.state.lock().unwrap();
+            if let Some(host) = state.hosts.get_mut(&upstream_id) {
+                host.wake_scheduled = false;
+                for waker in host.waiting.drain(..) {
+                    waker.wake();
+                }
+            }
+        });
+    }
+
+    fn record(&self, upstream_id: &str, outcome: Outcome) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let host = match state.hosts.get_mut(upstream_id) {
+            Some(h) => h,
+            None => return,
+        };
+        host.total += 1;
+        let reason = match outcome {
+            Outcome::Success => {
+                host.success += 1;
+                host.consecutive_5xx = 0;
+                host.consecutive_gateway_failure = 0;
+                None
+            },
+            Outcome::Error => {
+                host.consecutive_5xx += 1;
+                host.consecutive_gateway_failure = 0;
+                self.threshold_reached(host)
+            },
+            Outcome::GatewayFailure => {
+                host.consecutive_5xx += 1;
+                host.consecutive_gateway_failure += 1;
+                self.threshold_reached(host)
+            },
+        };
+        if let Some(reason) = reason {
+            self.eject(&mut state, upstream_id, reason, now);
+        }
+    }
+
+    fn threshold_reached(&self, host: &HostStats) -> Option<&'static str> {
+        let reached = |threshold: u64, count: u64| threshold > 0 && count >= threshold;
+        if host.ejected_until.is_some() {
+            None
+        } else if reached(self.setting.consecutive_gateway_failure, host.consecutive_gateway_failure) {
+            Some("consecutive_gateway_failure")
+        } else if reached(self.setting.consecutive_5xx, host.consecutive_5xx) {
+            Some("consecutive_5xx")
+        } else {
+            None
+        }
+    }
+
+    fn eject(&self, state: &mut DetectorState, upstream_id: &str, reason: &str, now: Instant) {
+        let total = state.hosts.len() as u64;
+        let ejected = state.hosts.values().filter(|h| h.ejected_until.map(|t| t > now).unwrap_or(false)).count() as u64;
+        let max_percent = if self.setting.max_ejection_percent > 0 { self.setting.max_ejection_percent } else { 10 };
+        if ejected * 100 >= max_percent * total {
+            event!(Level::WARN, "Upstream {}/{} not ejected ({}), {} of {} upstreams already ejected",
+                self.service_id, upstream_id, reason, ejected, total);
+            if let Some(host) = state.hosts.get_mut(upstream_id) {
+                host.consecutive_5xx = 0;
+                host.consecutive_gateway_failure = 0;
+            }
+            return;
+        }
+        let base = self.base_ejection_time();
+        let max = self.max_ejection_time();
+        if let Some(host) = state.hosts.get_mut(upstream_id) {
+            host.ejections += 1;
+            let duration = base.checked_mul(host.ejections as u32).unwrap_or(max).min(max);
+            host.ejected_until = Some(now + duration);
+            host.consecutive_5xx = 0;
+            host.consecutive_gateway_failure = 0;
+            event!(Level::WARN, "Upstream {}/{} ejected for {:?} ({})", self.service_id, upstream_id, duration, reason);
+            OUTLIER_EJECTION_COUNTER.with_label_values(&[&self.service_id, upstream_id, reason]).inc();
+        }
+    }
+
+    // success rate ejection and multiplier decay, once per interval
+    fn analyze(&self, state: &mut DetectorState, now: Instant) {
+        state.next_analysis = now + self.interval;
+        let factor = self.setting.success_rate_stdev_factor;
+        let minimum_hosts = if self.setting.success_rate_minimum_hosts > 0 { self.setting.success_rate_minimum_hosts } else { 5 };
+        let volume = if self.setting.success_rate_request_volume > 0 { self.setting.success_rate_request_volume } else { 100 };
+
+        let rates: Vec<(String, f64)> = state.hosts.iter()
+            .filter(|(_, h)| h.ejected_until.is_none() && h.total >= volume)
+            .map(|(id, h)| (id.clone(), h.success as f64 / h.total as f64))
+            .collect();
+        for host in state.hosts.values_mut() {
+            if host.ejected_until.is_none() && host.ejections > 0 {
+                host.ejections -= 1;
+            }
+            host.success = 0;
+            host.total = 0;
+        }
+
+        if factor <= 0.0 || (rates.len() as u64) < minimum_hosts {
+            return;
+        }
+        let mean = rates.iter().map(|(_, r)| r).sum::<f64>() / rates.len() as f64;
+        let variance = rates.iter().map(|(_, r)| (r - mean).powi(2)).sum::<f64>() / rates.len() as f64;
+        let threshold = mean - factor * variance.sqrt();
+        for (id, rate) in rates.iter().filter(|(_, r)| *r < threshold) {
+            event!(Level::INFO, "Upstream {}/{} success rate {:.3} below {:.3}", self.service_id, id, rate, threshold);
+            self.eject(state, id, "success_rate", now);
+        }
+    }
+}
+
+
+// registration of an upstream in its detector, removed when the last clone of its service is dropped
+#[derive(Debug)]
+struct Host {
+    id: String,
+    detector: Arc<OutlierDetector>,
+}
+
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        let mut state = self.detector.state.lock().unwrap();
+        if let Some(host) = state.hosts.get_mut(&self.id) {
+            host.refs -= 1;
+            if host.refs == 0 {
+                state.hosts.remove(&self.id);
+            }
+        }
+    }
+}
+
+
+// ejected upstream is not ready until its ejection ends, clones share detector stats
+#[derive(Debug, Clone)]
+pub struct OutlierService<S> {
+    inner: S,
+    host: Option<Arc<Host>>,  // None without outlier detection
+}
+
+
+impl<S> OutlierService<S> {
+    pub fn disabled(inner: S) -> Self {
+        OutlierService { inner, host: None }
+    }
+
+    // upstream is not ejected
+    pub fn available(&self) -> bool {
+        match &self.host {
+            Some(host) => host.detector.ejected(&host.id, Instant::now()).is_none(),
+            None => true,
+        }
+    }
+}
+
+
+impl<S> Service<Request<Body>> for OutlierService<S>
+    where S: Service<Request<Body>, Response=Response<Body>, Error=BoxError>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = OutlierFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let Some(host) = &self.host {
+            if let Some(remaining) = host.detector.ejected(&host.id, Instant::now()) {
+                // balancers poll pending services only when woken
+                host.detector.wait(&host.id, remaining, cx.waker());
+                return Poll::Pending;
+            }
+        }
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        OutlierFuture { fut: self.inner.call(req), host: self.host.clone() }
+    }
+}
+
+
+#[pin_project]
+pub struct OutlierFuture<Fut> {
+    #[pin]
+    fut: Fut,
+    host: Option<Arc<Host>>,
+}
+
+
+impl<Fut> Future for OutlierFuture<Fut>
+    where Fut: Future<Output=Result<Response<Body>, BoxError>>
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let result = ready!(this.fut.poll(cx));
+        if let Some(host) = this.host {
+            let outcome = match &result {
+                Ok(resp) => match resp.status().as_u16() {
+                    502..=504 => Some(Outcome::GatewayFailure),
+                    500..=599 => Some(Outcome::Error),
+                    _ => Some(Outcome::Success),
+                },
+                // oversized request body is client's fault, concurrency limit is gateway's
+                Err(e) if e.is::<Overloaded>() => None,
+                Err(e) => match e.downcast_ref::<GatewayError>() {
+                    Some(GatewayError::PayloadTooLarge(_)) => None,
+                    _ => Some(Outcome::GatewayFailure),
+                },
+            };
+            if let Some(outcome) = outcome {
+                host.detector.record(&host.id, outcome);
+            }
+        }
+        Poll::Ready(result)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::{waker, ArcWake};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counter(AtomicUsize);
+
+    impl ArcWake for Counter {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn ejected_host_schedules_one_wake_up() {
+        let setting: OutlierSetting = serde_yaml::from_str("{consecutive_5xx: 1}").unwrap();
+        let detector = OutlierDetector::new("test/outlier", &setting);
+        let _service = detector.layer("1", ());
+        let first = Arc::new(Counter(AtomicUsize::new(0)));
+        let second = Arc::new(Counter(AtomicUsize::new(0)));
+        for _ in 0..100 {
+            detector.wait("1", Duration::from_millis(20), &waker(first.clone()));
+        }
+        detector.wait("1", Duration::from_millis(20), &waker(second.clone()));
+        {
+            let state = detector.state.lock().unwrap();
+            let host = &state.hosts["1"];
+            assert!(host.wake_scheduled);
+            assert_eq!(host.waiting.len(), 2);
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(first.0.load(Ordering::SeqCst), 1);
+        assert_eq!(second.0.load(Ordering::SeqCst), 1);
+        let state = detector.state.lock().unwrap();
+        assert!(!state.hosts["1"].wake_scheduled);
+        assert!(state.hosts["1"].waiting.is_empty());
+    }
+}
@@ -0,0 +1,89 @@
+use hyper::{Body, Request, Response};
+use tower::Service;
+use tower::util::BoxService;
+use futures::ready;
+use std::task::{Context, Poll};
+use tracing::{event, Level};
+use crate::config::FailoverSetting;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+type BoxedHttpService = BoxService<Request<Body>, Response<Body>, BoxError>;
+
+
+/// Upstreams of one priority, balanced by their own service.
+pub struct PriorityLevel {
+    pub priority: u32,
+    pub service: BoxedHttpService,
+    pub healthy: Box<dyn Fn() -> f64 + Send>,  // healthy share of the level's weight
+}
+
+
+/// Failover between priority levels, requests go to the highest level with enough healthy weight.
+/// When no level has, the level with the largest healthy share takes them.
+pub struct PriorityBalance {
+    service_id: String,
+    levels: Vec<PriorityLevel>,  // highest priority first
+    threshold: f64,
+    current: usize,
+    ready_index: Option<usize>,
+}
+
+
+impl PriorityBalance {
+
+    pub fn new(service_id: &str, mut levels: Vec<PriorityLevel>, setting: Option<&FailoverSetting>) -> Self {
+        levels.sort_by_key(|l| l.priority);
+        let percent = setting.map(|s| s.healthy_percent).filter(|p| *p > 0).unwrap_or(70).min(100);
+        PriorityBalance {
+            service_id: String::from(service_id),
+            levels,
+            threshold: percent as f64 / 100.0,
+            current: 0,
+            ready_index: None,
+        }
+    }
+
+    fn select(&self) -> usize {
+        let mut best = (0, -1.0);
+        for (i, level) in self.levels.iter().enumerate() {
+            let healthy = (level.healthy)();
+            if healthy >= self.threshold {
+                return i;
+            }
+            if healthy > best.1 {
+                best = (i, healthy);
+            }
+        }
+        best.0
+    }
+}
+
+
+impl Service<Request<Body>> for PriorityBalance {
+    type Response = Response<Body>;
+    type Error = BoxError;
+    type Future = <BoxedHttpService as Service<Request<Body>>>::Future;
+
+    // level is selected again on each poll, breakers and ejections wake pending balancers when health changes
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.ready_index = None;
+        let index = self.select();
+        if index > self.current {
+            event!(Level::WARN, "Service {} fails over from priority {} to {} upstreams",
+                self.service_id, self.levels[self.current].priority, self.levels[index].priority);
+        } else if index < self.current {
+            event!(Level::INFO, "Service {} fails back from priority {} to {} upstreams",
+                self.service_id, self.levels[self.current].priority, self.levels[index].priority);
+        }
+        self.current = index;
+        ready!(self.levels[index].service.poll_ready(cx))?;
+        self.ready_index = Some(index);
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let index = self.ready_index.take().expect("called before ready");
+        self.levels[index].service.call(req)
+    }
+}
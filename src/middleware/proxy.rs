@@ -1,15 +1,31 @@
-use hyper::{Body, Request, Response, Uri, header::HeaderValue};
+use hyper::{Body, Request, Response, Uri, HeaderMap};
+use hyper::header::{self, HeaderName, HeaderValue};
 use hyper::client::HttpConnector;
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::client::connect::{Connected, Connection};
 use hyper::client::Client;
-use hyper_rustls::HttpsConnector;
-use rustls::ClientConfig;
+use rustls::{ClientConfig, Session};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::webpki::DNSNameRef;
 use tower::Service;
+use std::io;
+use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Poll, Context};
 use std::future::Future;
 use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
 use tracing::{event, Level};
-use crate::{config::Upstream, middleware::GatewayError};
+use crate::{config::Upstream, middleware::GatewayError, middleware::UpstreamInfo, middleware::HedgeOrigin, middleware::CountingBody};
+use crate::trace::{TraceContext, SpanRecord, SpanKind, tracer_enabled};
+use crate::redact;
+use super::upstream_tls;
 
 
 lazy_static::lazy_static! {
@@ -23,6 +39,22 @@ lazy_static::lazy_static! {
 }
 
 
+// hop-by-hop headers, RFC 7230 section 6.1
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection", "keep-alive", "proxy-authenticate", "proxy-authorization", "proxy-connection",
+    "te", "trailer", "transfer-encoding", "upgrade",
+];
+
+
+// client connection info, attached to request extensions by RequestHandler
+#[derive(Debug, Clone)]
+pub struct ForwardInfo {
+    pub client_ip: IpAddr,
+    pub proto: String,
+    pub trusted: bool,  // peer is a trusted proxy, keep forwarding headers it sent
+}
+
+
 #[derive(Debug, Clone)]
 pub struct ProxyHandler {
     service_id: String,
@@ -30,46 +62,237 @@ pub struct ProxyHandler {
     upstream: String,
     version: String,
     timeout: Duration,
-    client: Client<HttpsConnector<HttpConnector>, Body>,
+    rewrite_host: bool,
+    client: Client<UpstreamConnector, CountingBody>,
+}
+
+
+// connects to the resolved address of a discovered upstream, others resolve target host per connection
+#[derive(Debug, Clone)]
+pub struct PinnedResolver {
+    address: Option<IpAddr>,
+    gai: GaiResolver,
+}
+
+impl Service<Name> for PinnedResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.gai.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        match self.address {
+            // port is set by connector from target uri
+            Some(ip) => Box::pin(futures::future::ready(Ok(vec![SocketAddr::new(ip, 0)].into_iter()))),
+            None => {
+                let addrs = self.gai.call(name);
+                Box::pin(async move {
+                    Ok(addrs.await?.collect::<Vec<_>>().into_iter())
+                })
+            },
+        }
+    }
+}
+
+// connection to an upstream, over TCP with optional TLS or over a unix socket
+// TLS stream carries the session buffers, boxed to keep plain connections small
+pub enum UpstreamStream {
+    Http(TcpStream),
+    Https(Box<TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        match self {
+            UpstreamStream::Http(s) => s.connected(),
+            UpstreamStream::Https(s) => {
+                let (tcp, session) = s.get_ref();
+                if session.get_alpn_protocol() == Some(b"h2") {
+                    tcp.connected().negotiated_h2()
+                } else {
+                    tcp.connected()
+                }
+            },
+            #[cfg(unix)]
+            UpstreamStream::Unix(_) => Connected::new(),
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Http(s) => Pin::new(s).poll_read(cx, buf),
+            UpstreamStream::Https(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            UpstreamStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Http(s) => Pin::new(s).poll_write(cx, buf),
+            UpstreamStream::Https(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            UpstreamStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Http(s) => Pin::new(s).poll_flush(cx),
+            UpstreamStream::Https(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            UpstreamStream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Http(s) => Pin::new(s).poll_shutdown(cx),
+            UpstreamStream::Https(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            UpstreamStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+// https connector of an upstream, server name is the target host unless overridden by TLS settings.
+// Invalid TLS settings fail every https connect of the upstream instead of falling back to defaults.
+// Unix socket upstreams connect to the socket whatever the request uri is.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    http: HttpConnector<PinnedResolver>,
+    tls: Result<Arc<ClientConfig>, String>,
+    sni: Option<String>,
+    unix: Option<Arc<PathBuf>>,
+    timeout: Duration,
+}
+
+impl std::fmt::Debug for UpstreamConnector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UpstreamConnector").field("sni", &self.sni).field("unix", &self.unix).finish()
+    }
+}
+
+impl UpstreamConnector {
+
+    #[cfg(unix)]
+    fn connect_unix(&self, path: Arc<PathBuf>) -> <Self as Service<Uri>>::Future {
+        let timeout = self.timeout;
+        Box::pin(async move {
+            let stream = tokio::time::timeout(timeout, UnixStream::connect(path.as_path())).await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("connect {} timed out", path.display())))??;
+            Ok(UpstreamStream::Unix(stream))
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn connect_unix(&self, path: Arc<PathBuf>) -> <Self as Service<Uri>>::Future {
+        Box::pin(async move {
+            Err(format!("unix socket {} is not supported on this platform", path.display()).into())
+        })
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = UpstreamStream;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        if let Some(path) = self.unix.clone() {
+            return self.connect_unix(path);
+        }
+        if dst.scheme_str() != Some("https") {
+            let connecting = self.http.call(dst);
+            return Box::pin(async move {
+                Ok(UpstreamStream::Http(connecting.await?))
+            });
+        }
+        let connector = match &self.tls {
+            Ok(config) => TlsConnector::from(config.clone()),
+            Err(e) => {
+                let e = e.clone();
+                return Box::pin(async move { Err(e.into()) });
+            },
+        };
+        let server_name = self.sni.clone().unwrap_or_else(|| String::from(dst.host().unwrap_or_default()));
+        let connecting = self.http.call(dst);
+        Box::pin(async move {
+            let tcp = connecting.await?;
+            let name = DNSNameRef::try_from_ascii_str(&server_name)
+                .map_err(|_| format!("invalid TLS server name {}", server_name))?;
+            let tls = connector.connect(name, tcp).await?;
+            Ok(UpstreamStream::Https(Box::new(tls)))
+        })
+    }
+}
+
+
+// unix:///path/to.sock or unix:///path/to.sock:/prefix, socket path and the endpoint requests are sent to
+fn unix_target(target: &str) -> Option<(PathBuf, String)> {
+    let rest = target.strip_prefix("unix://")?;
+    let (socket, prefix) = match rest.find(':') {
+        Some(offset) => (&rest[..offset], &rest[offset + 1..]),
+        None => (rest, ""),
+    };
+    let endpoint = if prefix.is_empty() || prefix.starts_with('/') {
+        format!("http://localhost{}", prefix)
+    } else {
+        format!("http://localhost/{}", prefix)
+    };
+    Some((PathBuf::from(socket), endpoint))
 }
 
 impl ProxyHandler {
 
     pub fn new(service_id: &str, upstream: &Upstream, timeout: u32) -> Self {
-        let mut connector = HttpConnector::new();
+        let resolver = PinnedResolver { address: upstream.address, gai: GaiResolver::new() };
+        let mut http = HttpConnector::new_with_resolver(resolver);
         let timeout = Duration::from_secs(timeout as u64);
-        connector.set_connect_timeout(Some(timeout));
-        connector.set_keepalive(Some(Duration::from_secs(30)));
-
-        let mut tls_config = ClientConfig::new();
-        tls_config.root_store = match rustls_native_certs::load_native_certs() {
-            Ok(store) => store,
-            Err((Some(store), err)) => {
-                log::warn!("Could not load all certificates: {:?}", err);
-                store
-            }
-            Err((None, err)) => Err(err).expect("cannot access native cert store"),
-        };
-        if tls_config.root_store.is_empty() {
-            panic!("no CA certificates found");
-        }
+        http.set_connect_timeout(Some(timeout));
+        http.set_keepalive(Some(Duration::from_secs(30)));
+        http.enforce_http(false);
 
-        let tls = HttpsConnector::from((connector, tls_config));
+        let (endpoint, unix) = match unix_target(&upstream.target) {
+            Some((socket, endpoint)) => (endpoint, Some(Arc::new(socket))),
+            None => (upstream.target.clone(), None),
+        };
+        let connector = UpstreamConnector {
+            http,
+            tls: upstream_tls::client_config(&upstream.id, upstream.tls.as_ref()),
+            sni: upstream.tls.as_ref().map(|t| t.sni.clone()).filter(|s| !s.is_empty()),
+            unix,
+            timeout,
+        };
         let client = Client::builder()
             .pool_idle_timeout(timeout)
-            .build::<_, Body>(tls);
+            .build::<_, CountingBody>(connector);
 
         ProxyHandler { 
             service_id: String::from(service_id), 
             client, 
             timeout,
-            upstream: upstream.target.clone(), 
+            upstream: endpoint,
             upstream_id: upstream.id.clone(),
             version: upstream.version.clone(),
+            rewrite_host: upstream.rewrite_host,
         }
     }
 
-    fn alter_request(req: Request<Body>, endpoint: &str) -> Request<Body> {
+    fn alter_request(req: Request<Body>, endpoint: &str, rewrite_host: bool) -> Request<Body> {
         let (mut parts, body) = req.into_parts();
         parts.version = hyper::http::Version::HTTP_11;
         let path_and_query = parts.uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
@@ -84,6 +307,18 @@ impl ProxyHandler {
         new_uri.push_str(path_left);
 
         parts.uri = new_uri.parse::<Uri>().unwrap();
+
+        strip_hop_by_hop(&mut parts.headers);
+        if let Some(info) = parts.extensions.get::<ForwardInfo>() {
+            set_forward_headers(&mut parts.headers, info);
+        }
+        if rewrite_host {
+            if let Some(authority) = parts.uri.authority() {
+                if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+                    parts.headers.insert(header::HOST, host);
+                }
+            }
+        }
         Request::from_parts(parts, body)
     }
 }
@@ -99,8 +334,28 @@ impl Service<Request<Body>> for ProxyHandler
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        let req = ProxyHandler::alter_request(req, &self.upstream);
-        event!(Level::DEBUG, "{:?}", req.uri());
+        if let Some(origin) = req.extensions().get::<HedgeOrigin>() {
+            origin.set(&self.upstream_id);
+        }
+        let mut req = ProxyHandler::alter_request(req, &self.upstream, self.rewrite_host);
+        event!(Level::DEBUG, "{}", redact::uri(req.uri()));
+        // client span for upstream call, upstream sees it as remote parent
+        // without exporter incoming traceparent is forwarded unchanged, a span nobody records would break the chain
+        let trace = if tracer_enabled() {
+            req.extensions().get::<TraceContext>().map(|t| t.child())
+        } else {
+            None
+        };
+        let mut span = trace.as_ref().map(|t| {
+            let mut span = SpanRecord::start(&format!("HTTP {}", req.method()), SpanKind::Client, t);
+            span.attr("http.method", req.method().as_str());
+            span.attr("http.url", redact::uri(req.uri()));
+            span.attr("upstream_id", self.upstream_id.as_str());
+            span
+        });
+        if let Some(t) = &trace {
+            t.inject(req.headers_mut());
+        }
         let upstream_id = self.upstream_id.to_string();
         let version = self.version.to_string();
         let service_id = self.service_id.clone();
@@ -110,12 +365,13 @@ impl Service<Request<Body>> for ProxyHandler
             &version,
         ]).inc();
 
+        let start = std::time::Instant::now();
         let sleep = tokio::time::sleep(self.timeout.clone());
-        let fut = self.client.request(req);
+        let fut = self.client.request(CountingBody::request(req));
         Box::pin(async move {
             let result: Result<Response<Body>, GatewayError> = tokio::select! {
                 resp = fut => {
-                    Ok(resp?)
+                    resp.map_err(GatewayError::from)
                 },
                 _ = sleep => {
                     Err(GatewayError::TimeoutError)
@@ -128,14 +384,189 @@ impl Service<Request<Body>> for ProxyHandler
                 &version,
             ]).dec();
 
+            if let Some(mut span) = span.take() {
+                match &result {
+                    Ok(resp) => {
+                        span.attr("http.status_code", resp.status().as_u16());
+                        if resp.status().is_server_error() {
+                            span.set_error();
+                        }
+                    },
+                    Err(_e) => span.set_error(),
+                }
+                span.finish();
+            }
+
             let mut resp = result?;
+            strip_hop_by_hop(resp.headers_mut());
             let header = resp.headers_mut();
             let us_id = HeaderValue::from_str(&upstream_id).unwrap();
             let us_version = HeaderValue::from_str(&version).unwrap();
             header.append("X-UPSTREAM-ID", us_id);
             header.append("X-UPSTREAM-VERSION", us_version);
+            resp.extensions_mut().insert(UpstreamInfo { id: upstream_id, version, elapsed: start.elapsed() });
             Ok(resp)
         })
     }
 }
 
+
+// remove hop-by-hop headers, including those listed in Connection header
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers.get_all(header::CONNECTION).iter()
+        .flat_map(|v| v.to_str().unwrap_or("").split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().to_lowercase().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS.iter() {
+        headers.remove(*name);
+    }
+}
+
+
+// set X-Forwarded-For/Proto/Host and Forwarded headers, append to existing ones only for trusted peers
+pub fn set_forward_headers(headers: &mut HeaderMap, info: &ForwardInfo) {
+    let host = headers.get(header::HOST).and_then(|h| h.to_str().ok()).map(String::from);
+    let existing = |headers: &HeaderMap, name: &str| -> Option<String> {
+        let values: Vec<&str> = headers.get_all(name).iter().filter_map(|v| v.to_str().ok()).collect();
+        if info.trusted && !values.is_empty() { Some(values.join(", ")) } else { None }
+    };
+
+    let client_ip = info.client_ip.to_string();
+    let xff = match existing(headers, "x-forwarded-for") {
+        Some(prior) => format!("{}, {}", prior, client_ip),
+        None => client_ip,
+    };
+    let proto = existing(headers, "x-forwarded-proto").unwrap_or_else(|| info.proto.clone());
+    let forwarded_host = existing(headers, "x-forwarded-host").or_else(|| host.clone());
+
+    // RFC 7239, IPv6 addresses are quoted and bracketed
+    let node = match info.client_ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    };
+    let mut element = format!("for={};proto={}", node, info.proto);
+    if let Some(h) = &host {
+        element.push_str(&format!(";host=\"{}\"", h));
+    }
+    let forwarded = match existing(headers, "forwarded") {
+        Some(prior) => format!("{}, {}", prior, element),
+        None => element,
+    };
+
+    let mut set = |name: &'static str, value: &str| {
+        headers.remove(name);
+        if let Ok(v) = HeaderValue::from_str(value) {
+            headers.insert(name, v);
+        }
+    };
+    set("x-forwarded-for", &xff);
+    set("x-forwarded-proto", &proto);
+    if let Some(h) = forwarded_host {
+        set("x-forwarded-host", &h);
+    }
+    set("forwarded", &forwarded);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use rustls::{AllowAnyAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig};
+    use rustls::internal::pemfile;
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn reader(name: &str) -> std::io::BufReader<std::fs::File> {
+        std::io::BufReader::new(std::fs::File::open(fixture(name)).unwrap())
+    }
+
+    // https server with certificate of upstream.test, requires client certificate if mtls
+    async fn serve(mtls: bool) -> SocketAddr {
+        let mut config = if mtls {
+            let mut roots = RootCertStore::empty();
+            roots.add_pem_file(&mut reader("ca.pem")).unwrap();
+            ServerConfig::new(AllowAnyAuthenticatedClient::new(roots))
+        } else {
+            ServerConfig::new(NoClientAuth::new())
+        };
+        let certs = pemfile::certs(&mut reader("server.pem")).unwrap();
+        let key = pemfile::pkcs8_private_keys(&mut reader("server-key.pem")).unwrap().remove(0);
+        config.set_single_cert(certs, key).unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(tls) = acceptor.accept(tcp).await {
+                        let service = service_fn(|_| async { Ok::<_, Infallible>(Response::new(Body::from("ok"))) });
+                        let _ = Http::new().serve_connection(tls, service).await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    async fn get(addr: SocketAddr, tls: &str) -> Result<Response<Body>, String> {
+        let upstream: Upstream = serde_yaml::from_str(&format!(r#"
+            {{id: "tls", target: "https://127.0.0.1:{}/", max_conn: 10, version: "1.0", weight: 100,
+              error_threshold: 3, error_reset: 60, retry_delay: 10, tls: {}}}
+        "#, addr.port(), tls)).unwrap();
+        let mut handler = ProxyHandler::new("test/tls", &upstream, 3);
+        let req = Request::get("/test/ping").body(Body::empty()).unwrap();
+        handler.call(req).await.map_err(|e| format!("{:?}", e))
+    }
+
+    #[tokio::test]
+    async fn ca_bundle_and_sni_override() {
+        let addr = serve(false).await;
+        let ca = fixture("ca.pem");
+        let resp = get(addr, &format!("{{ca_file: '{}', sni: upstream.test}}", ca)).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        // certificate is issued for upstream.test only
+        assert!(get(addr, &format!("{{ca_file: '{}', sni: other.test}}", ca)).await.is_err());
+        // target is an IP address, not a valid server name without override
+        let err = get(addr, &format!("{{ca_file: '{}'}}", ca)).await.unwrap_err();
+        assert!(err.contains("invalid TLS server name 127.0.0.1"), "{}", err);
+        // native roots do not trust the test CA
+        assert!(get(addr, "{sni: upstream.test}").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn client_certificate() {
+        let addr = serve(true).await;
+        let ca = fixture("ca.pem");
+        let with_cert = format!("{{ca_file: '{}', cert_file: '{}', key_file: '{}', sni: upstream.test}}",
+                                ca, fixture("client.pem"), fixture("client-key.pem"));
+        assert_eq!(get(addr, &with_cert).await.unwrap().status(), 200);
+        assert!(get(addr, &format!("{{ca_file: '{}', sni: upstream.test}}", ca)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn insecure_skip_verify() {
+        let addr = serve(false).await;
+        let resp = get(addr, "{insecure_skip_verify: true, sni: other.test}").await.unwrap();
+        assert_eq!(resp.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn invalid_settings_fail_requests() {
+        let addr = serve(false).await;
+        let err = get(addr, "{ca_file: /nonexistent/ca.pem, sni: upstream.test}").await.unwrap_err();
+        assert!(err.contains("Invalid TLS settings of upstream tls: cannot open /nonexistent/ca.pem"), "{}", err);
+        let err = get(addr, &format!("{{ca_file: '{}', sni: upstream.test}}", fixture("client-key.pem"))).await.unwrap_err();
+        assert!(err.contains("no CA certificate in"), "{}", err);
+    }
+}
@@ -6,6 +6,15 @@ use crate::middleware::{Middleware, MwPreRequest, MwPreResponse, MwPostRequest,
 use crate::config::{ConfigUpdate, FilterSetting, RateLimitSetting};
 
 
+lazy_static::lazy_static! {
+    static ref RATELIMIT_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_ratelimit_rejected_total",
+        "Number of requests rejected by rate limit, by service or client level limit.",
+        &["service", "app", "scope"]
+    ).unwrap();
+}
+
+
 #[derive(Debug)]
 pub struct RateLimitMiddleware {
     service_limit: HashMap<String, Vec<TokenBucket>>,  // service_limit[service_id] = Vec<TokenBucket>
@@ -40,10 +49,12 @@ impl Middleware for RateLimitMiddleware {
         let now = Instant::now();
         let MwPreRequest { context, request, service_filters: _, client_filters: _, result} = task;
         let mut pass = true;
+        let mut scope = "";
         if let Some(service_limits) = self.service_limit.get_mut(&context.service_id) {
             for limit in service_limits {
                 if !limit.check(now) {
                     pass = false;
+                    scope = "service";
                 }
             }
         }
@@ -52,12 +63,14 @@ impl Middleware for RateLimitMiddleware {
                 for limit in client_limits {
                     if !limit.check(now) {
                         pass = false;
+                        scope = "client";
                     }
                 }
             }
         }
         
         if !pass {  // return error response
+            RATELIMIT_COUNTER.with_label_values(&[&context.service_id, &context.client_id, scope]).inc();
             let _ = result.send(Err(GatewayError::RateLimited("Rate Limit".into())));
             Box::pin(async {})
         } else {
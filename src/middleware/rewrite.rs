@@ -0,0 +1,332 @@
+use hyper::{Request, Body, Uri};
+use tracing::{event, Level};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use regex::Regex;
+use glob::Pattern;
+use crate::middleware::{MwPostRequest, MwPreRequest, MwPreResponse, Middleware, MwNextAction};
+use crate::config::{ConfigUpdate, FilterSetting, RewriteSetting, RewriteRule};
+
+
+#[derive(Debug, Default)]
+pub struct RewriteMiddleware {
+    service_rewrite: HashMap<String, Vec<Rewriter>>,   // service_rewrite[service_id] = Vec<Rewriter>
+    sla_rewrite: HashMap<String, HashMap<String, Vec<Rewriter>>>,   // sla_rewrite[service_id][sla] = Vec<Rewriter>
+}
+
+
+impl Middleware for RewriteMiddleware {
+
+    fn name() -> String {
+        "Rewrite".into()
+    }
+
+    fn post() -> bool {
+        false
+    }
+
+    fn request(&mut self, task: MwPreRequest) -> Pin<Box<dyn Future<Output=()> + Send>> {
+        let MwPreRequest {context, mut request, service_filters: _, client_filters: _, result} = task;
+        let mut rewriters: Vec<&Rewriter> = Vec::new();
+        if let Some(rs) = self.service_rewrite.get(&context.service_id) {
+            rewriters.extend(rs.iter());
+        }
+        if let Some(rs) = self.sla_rewrite.get(&context.service_id).and_then(|s| s.get(&context.sla)) {
+            rewriters.extend(rs.iter());
+        }
+        if !rewriters.is_empty() {
+            rewrite_request(&rewriters, &mut request);
+        }
+        let _ = result.send(Ok(MwPreResponse { context, next: MwNextAction::Next(request) }));
+        Box::pin(async {})
+    }
+
+    fn response(&mut self, _task: MwPostRequest) -> Pin<Box<dyn Future<Output=()> + Send>> {
+        panic!("never got here")
+    }
+
+    fn config_update(&mut self, update: ConfigUpdate) {
+        match update {
+            ConfigUpdate::ServiceUpdate(service) => {
+                let compile = |filters: &Vec<FilterSetting>| -> Vec<Rewriter> {
+                    filters.iter().filter_map(|f| {
+                        if let FilterSetting::Rewrite(r) = f { Some(Rewriter::new(r)) } else { None }
+                    }).collect()
+                };
+                let mut sla_rewrite = HashMap::new();
+                for sla in &service.sla {
+                    sla_rewrite.insert(sla.name.clone(), compile(&sla.filters));
+                }
+                self.service_rewrite.insert(service.service_id.clone(), compile(&service.filters));
+                self.sla_rewrite.insert(service.service_id.clone(), sla_rewrite);
+            },
+            ConfigUpdate::ServiceRemove(service_id) => {
+                self.service_rewrite.remove(&service_id);
+                self.sla_rewrite.remove(&service_id);
+            },
+            _ => {},
+        }
+    }
+}
+
+
+// rewrite request uri in place, service path segment is kept for ProxyHandler to strip
+fn rewrite_request(rewriters: &[&Rewriter], request: &mut Request<Body>) {
+    let uri = request.uri();
+    let (service_path, mut path) = split_service_path(uri.path());
+    let mut query = uri.query().map(String::from);
+    for r in rewriters {
+        path = r.rewrite_path(&path);
+        query = r.rewrite_query(query.as_deref());
+    }
+    if rewriters.iter().any(|r| r.preserve_prefix) {
+        path = format!("{}{}", service_path, path);
+    }
+
+    let mut new_uri = format!("{}{}", service_path, path);
+    if let Some(q) = query {
+        new_uri.push('?');
+        new_uri.push_str(&q);
+    }
+    match new_uri.parse::<Uri>() {
+        Ok(u) => *request.uri_mut() = u,
+        Err(e) => event!(Level::ERROR, "bad rewritten uri {}: {:?}", crate::redact::path_and_query(&new_uri), e),
+    }
+}
+
+
+fn split_service_path(path: &str) -> (String, String) {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    let (service_path, api_path) = match path.find('/') {
+        Some(pos) => path.split_at(pos),
+        None => (path, ""),
+    };
+    (format!("/{}", service_path), String::from(api_path))
+}
+
+
+#[derive(Debug, Clone)]
+enum PathRule {
+    Regex(Regex, String),
+    Prefix(String, String),
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Rewriter {
+    rules: Vec<(Option<Pattern>, PathRule)>,
+    query_injection: Vec<(String, String)>,
+    query_removal: Vec<String>,
+    query_rename: Vec<(String, String)>,
+    preserve_prefix: bool,
+}
+
+
+impl Rewriter {
+
+    pub fn new(setting: &RewriteSetting) -> Self {
+        let mut rules = Vec::new();
+        for rule in &setting.rules {
+            if let Some(r) = Self::compile_rule(rule) {
+                rules.push(r);
+            }
+        }
+        Rewriter {
+            rules,
+            query_injection: setting.query_injection.clone(),
+            query_removal: setting.query_removal.clone(),
+            query_rename: setting.query_rename.clone(),
+            preserve_prefix: setting.preserve_prefix,
+        }
+    }
+
+    fn compile_rule(rule: &RewriteRule) -> Option<(Option<Pattern>, PathRule)> {
+        let scope = if rule.path_pattern.is_empty() {
+            None
+        } else if let Ok(p) = Pattern::new(&rule.path_pattern) {
+            Some(p)
+        } else {
+            event!(Level::ERROR, "bad path glob pattern {}", rule.path_pattern);
+            return None;
+        };
+        let path_rule = match rule.rule_type.as_str() {
+            "regex" => {
+                if let Ok(re) = Regex::new(&rule.pattern) {
+                    PathRule::Regex(re, rule.replacement.clone())
+                } else {
+                    event!(Level::ERROR, "bad rewrite regex pattern {}", rule.pattern);
+                    return None;
+                }
+            },
+            "prefix" => PathRule::Prefix(rule.pattern.clone(), rule.replacement.clone()),
+            _ => {
+                event!(Level::ERROR, "unknown rewrite rule type {}", rule.rule_type);
+                return None;
+            },
+        };
+        Some((scope, path_rule))
+    }
+
+    // apply first matching rule, path is returned unchanged if none matches
+    pub fn rewrite_path(&self, path: &str) -> String {
+        for (scope, rule) in &self.rules {
+            if let Some(pattern) = scope {
+                if !pattern.matches(path) {
+                    continue;
+                }
+            }
+            match rule {
+                PathRule::Regex(re, replacement) => {
+                    if re.is_match(path) {
+                        return re.replace(path, replacement.as_str()).into_owned();
+                    }
+                },
+                PathRule::Prefix(prefix, replacement) => {
+                    if let Some(rest) = path.strip_prefix(prefix.as_str()) {
+                        return format!("{}{}", replacement, rest);
+                    }
+                },
+            }
+        }
+        String::from(path)
+    }
+
+    // remove, rename, then inject query parameters. Untouched parameters keep their original encoding,
+    // a query that can't be parsed is passed through unchanged
+    pub fn rewrite_query(&self, query: Option<&str>) -> Option<String> {
+        if self.query_injection.is_empty() && self.query_removal.is_empty() && self.query_rename.is_empty() {
+            return query.map(String::from);
+        }
+        let mut params: Vec<(String, String)> = Vec::new();  // decoded name, raw parameter
+        for raw in query.unwrap_or("").split('&').filter(|p| !p.is_empty()) {
+            match serde_urlencoded::from_str::<Vec<(String, String)>>(raw) {
+                Ok(mut pair) if pair.len() == 1 => params.push((pair.remove(0).0, String::from(raw))),
+                _ => return query.map(String::from),
+            }
+        }
+        params.retain(|(name, _raw)| !self.query_removal.contains(name));
+        for (name, raw) in params.iter_mut() {
+            if let Some((_from, to)) = self.query_rename.iter().find(|(from, _to)| from == name) {
+                let value = raw.find('=').map(|pos| &raw[pos..]).unwrap_or("");
+                *raw = format!("{}{}", encode_param(to, None), value);
+                *name = to.clone();
+            }
+        }
+        for (k, v) in &self.query_injection {
+            params.retain(|(name, _raw)| name != k);
+            params.push((k.clone(), encode_param(k, Some(v))));
+        }
+        if params.is_empty() {
+            None
+        } else {
+            Some(params.into_iter().map(|(_name, raw)| raw).collect::<Vec<_>>().join("&"))
+        }
+    }
+}
+
+
+fn encode_param(name: &str, value: Option<&str>) -> String {
+    let encoded = serde_urlencoded::to_string([(name, value.unwrap_or(""))]).unwrap_or_default();
+    match value {
+        Some(_) => encoded,
+        None => String::from(encoded.trim_end_matches('=')),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(rule_type: &str, pattern: &str, replacement: &str) -> RewriteRule {
+        RewriteRule {
+            rule_type: rule_type.into(),
+            pattern: pattern.into(),
+            replacement: replacement.into(),
+            path_pattern: String::new(),
+        }
+    }
+
+    fn rewriter(rules: Vec<RewriteRule>) -> Rewriter {
+        Rewriter::new(&RewriteSetting {
+            rules,
+            query_injection: vec![],
+            query_removal: vec![],
+            query_rename: vec![],
+            preserve_prefix: false,
+        })
+    }
+
+    fn query_rewriter() -> Rewriter {
+        Rewriter::new(&RewriteSetting {
+            rules: vec![],
+            query_injection: vec![("source".into(), "gateway api".into())],
+            query_removal: vec!["debug".into()],
+            query_rename: vec![("uid".into(), "user_id".into())],
+            preserve_prefix: false,
+        })
+    }
+
+    #[test]
+    fn regex_rules_substitute_capture_groups() {
+        let r = rewriter(vec![rule("regex", r"^/users/(\d+)$", "/v2/accounts/$1")]);
+        assert_eq!(r.rewrite_path("/users/42"), "/v2/accounts/42");
+        assert_eq!(r.rewrite_path("/users/abc"), "/users/abc");
+    }
+
+    #[test]
+    fn prefix_rules_replace_prefix_only() {
+        let r = rewriter(vec![rule("prefix", "/old/", "/new/"), rule("prefix", "/", "/fallback/")]);
+        assert_eq!(r.rewrite_path("/old/items/1"), "/new/items/1");
+        assert_eq!(r.rewrite_path("/other"), "/fallback/other");
+    }
+
+    #[test]
+    fn rules_are_limited_by_path_pattern() {
+        let mut scoped = rule("prefix", "/api/", "/internal/");
+        scoped.path_pattern = String::from("/api/admin/*");
+        let r = rewriter(vec![scoped]);
+        assert_eq!(r.rewrite_path("/api/admin/users"), "/internal/admin/users");
+        assert_eq!(r.rewrite_path("/api/public/users"), "/api/public/users");
+    }
+
+    #[test]
+    fn service_path_is_preserved_on_request() {
+        let mut setting = RewriteSetting {
+            rules: vec![rule("prefix", "/v1/", "/v2/")],
+            query_injection: vec![],
+            query_removal: vec![],
+            query_rename: vec![],
+            preserve_prefix: false,
+        };
+        let mut request = Request::get("/orders/v1/items?id=1").body(Body::empty()).unwrap();
+        rewrite_request(&[&Rewriter::new(&setting)], &mut request);
+        assert_eq!(request.uri(), "/orders/v2/items?id=1");
+
+        // ProxyHandler strips the first segment, upstream sees /orders/v2/items
+        setting.preserve_prefix = true;
+        let mut request = Request::get("/orders/v1/items?id=1").body(Body::empty()).unwrap();
+        rewrite_request(&[&Rewriter::new(&setting)], &mut request);
+        assert_eq!(request.uri(), "/orders/orders/v2/items?id=1");
+    }
+
+    #[test]
+    fn query_parameters_are_added_removed_and_renamed() {
+        let r = query_rewriter();
+        assert_eq!(r.rewrite_query(Some("uid=7&debug=1&page=2")).as_deref(), Some("user_id=7&page=2&source=gateway+api"));
+        assert_eq!(r.rewrite_query(None).as_deref(), Some("source=gateway+api"));
+        assert_eq!(r.rewrite_query(Some("source=client")).as_deref(), Some("source=gateway+api"));
+        assert_eq!(rewriter(vec![]).rewrite_query(Some("a=1")).as_deref(), Some("a=1"));
+    }
+
+    #[test]
+    fn untouched_parameters_keep_their_encoding() {
+        let r = query_rewriter();
+        assert_eq!(
+            r.rewrite_query(Some("tag=a&tag=b&q=x%20y&p=x+y&flag&debug")).as_deref(),
+            Some("tag=a&tag=b&q=x%20y&p=x+y&flag&source=gateway+api"),
+        );
+        assert_eq!(r.rewrite_query(Some("uid=a%2Fb")).as_deref(), Some("user_id=a%2Fb&source=gateway+api"));
+    }
+}
@@ -0,0 +1,233 @@
+use futures::{ready, Stream, TryStream};
+use pin_project::pin_project;
+use rand::Rng;
+use tower::Service;
+use tower::discover::Change;
+use tower::load::Load;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tracing::{event, Level};
+use crate::config::SlowStartSetting;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Curve {
+    Linear,
+    Exponential,
+}
+
+
+/// Warm-up window of an upstream, shared by clones of its service.
+/// Weight factor grows from min_weight_percent to 1 over the window after each restart.
+#[derive(Debug, Clone)]
+pub struct WarmUp {
+    window: Duration,
+    curve: Curve,
+    min_factor: f64,
+    since: Arc<Mutex<Option<Instant>>>,  // None when warm
+}
+
+
+impl WarmUp {
+
+    pub fn new(setting: &SlowStartSetting) -> Self {
+        let curve = match setting.curve.as_str() {
+            "" | "linear" => Curve::Linear,
+            "exponential" => Curve::Exponential,
+            other => {
+                event!(Level::WARN, "Unknown slow start curve {}, use linear", other);
+                Curve::Linear
+            },
+        };
+        let percent = if setting.min_weight_percent > 0 { setting.min_weight_percent.min(100) } else { 10 };
+        WarmUp {
+            window: Duration::from_secs(setting.window),
+            curve,
+            min_factor: percent as f64 / 100.0,
+            since: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn restart(&self) {
+        *self.since.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn factor(&self) -> f64 {
+        let mut since = self.since.lock().unwrap();
+        let elapsed = match *since {
+            Some(t) => t.elapsed(),
+            None => return 1.0,
+        };
+        if elapsed >= self.window {
+            *since = None;
+            return 1.0;
+        }
+        let progress = elapsed.as_secs_f64() / self.window.as_secs_f64();
+        match self.curve {
+            Curve::Linear => self.min_factor + (1.0 - self.min_factor) * progress,
+            Curve::Exponential => self.min_factor.powf(1.0 - progress),
+        }
+    }
+}
+
+
+/// Weight of an upstream, configured weight for hash ring points, ramped weight for weighted balance.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Weight {
+    configured: u32,
+    effective: f64,
+}
+
+
+impl From<Weight> for u32 {
+    fn from(w: Weight) -> u32 {
+        w.configured
+    }
+}
+
+
+impl From<Weight> for f64 {
+    fn from(w: Weight) -> f64 {
+        w.effective
+    }
+}
+
+
+// upstream service with its weight, load is the weight ramped by warm-up
+#[derive(Debug, Clone)]
+pub struct SlowStart<S> {
+    inner: S,
+    weight: u32,
+    warm_up: Option<WarmUp>,  // None without slow start
+}
+
+
+impl<S> SlowStart<S> {
+
+    pub fn new(inner: S, weight: u32, warm_up: Option<WarmUp>) -> Self {
+        SlowStart { inner, weight, warm_up }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn warm_up(&self) -> Option<&WarmUp> {
+        self.warm_up.as_ref()
+    }
+}
+
+
+impl<S> Load for SlowStart<S> {
+    type Metric = Weight;
+
+    fn load(&self) -> Self::Metric {
+        let factor = self.warm_up.as_ref().map(|w| w.factor()).unwrap_or(1.0);
+        Weight { configured: self.weight, effective: self.weight as f64 * factor }
+    }
+}
+
+
+impl<S, Req> Service<Req> for SlowStart<S>
+    where S: Service<Req>
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        self.inner.call(req)
+    }
+}
+
+
+/// Load of p2c balancers. A warming upstream counts as more loaded than any warm one
+/// with probability 1 - factor, so it wins about factor of the picks it would win when warm.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct RampedLoad<M> {
+    cold: bool,  // field order makes it compare first
+    load: M,
+}
+
+
+// load measured by the inner service, ramped by warm-up of the upstream
+#[derive(Debug)]
+pub struct Ramped<S> {
+    inner: S,
+    warm_up: Option<WarmUp>,
+}
+
+
+impl<S: Load> Load for Ramped<S> {
+    type Metric = RampedLoad<S::Metric>;
+
+    fn load(&self) -> Self::Metric {
+        let cold = match &self.warm_up {
+            Some(w) => rand::thread_rng().gen::<f64>() >= w.factor(),
+            None => false,
+        };
+        RampedLoad { cold, load: self.inner.load() }
+    }
+}
+
+
+impl<S, Req> Service<Req> for Ramped<S>
+    where S: Service<Req>
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        self.inner.call(req)
+    }
+}
+
+
+/// Discover of p2c balancers, services of upstreams are wrapped by a load measure and ramped.
+#[pin_project]
+#[derive(Debug)]
+pub struct RampedDiscover<D, F> {
+    #[pin]
+    discover: D,
+    measure: F,
+}
+
+
+impl<D, F> RampedDiscover<D, F> {
+    pub fn new(discover: D, measure: F) -> Self {
+        RampedDiscover { discover, measure }
+    }
+}
+
+
+impl<D, F, K, S, T> Stream for RampedDiscover<D, F>
+    where D: TryStream<Ok=Change<K, SlowStart<S>>>,
+          F: Fn(S) -> T,
+{
+    type Item = Result<Change<K, Ramped<T>>, D::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let change = match ready!(this.discover.try_poll_next(cx)) {
+            None => return Poll::Ready(None),
+            Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+            Some(Ok(Change::Remove(key))) => Change::Remove(key),
+            Some(Ok(Change::Insert(key, svc))) => {
+                let SlowStart { inner, warm_up, .. } = svc;
+                Change::Insert(key, Ramped { inner: (this.measure)(inner), warm_up })
+            },
+        };
+        Poll::Ready(Some(Ok(change)))
+    }
+}
@@ -0,0 +1,199 @@
+use hyper::{Body, Request, Response, header};
+use hyper::header::HeaderValue;
+use ring::hmac;
+use rand::RngCore;
+use tower::Service;
+use tower::discover::{Change, Discover};
+use tower::ready_cache::ReadyCache;
+use tower::util::BoxService;
+use futures::future::TryFutureExt;
+use futures::ready;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tracing::{event, Level, debug, trace};
+use crate::config::StickySetting;
+use crate::middleware::canary::cookie;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+type BoxedHttpService = BoxService<Request<Body>, Response<Body>, BoxError>;
+
+
+const DEFAULT_COOKIE: &str = "hyperapi_sticky";
+
+
+lazy_static::lazy_static! {
+    // signing key of services without a secret, cookies survive config reloads but not restarts
+    static ref PROCESS_SECRET: Vec<u8> = {
+        let mut secret = vec![0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        secret
+    };
+}
+
+
+// signed upstream affinity cookie, {base64 upstream id}.{base64 hmac}
+#[derive(Debug, Clone)]
+pub struct StickyCookie {
+    name: String,
+    path: String,
+    max_age: u64,
+    service_id: String,
+    key: hmac::Key,
+}
+
+
+impl StickyCookie {
+
+    pub fn new(service_id: &str, service_path: &str, setting: Option<&StickySetting>) -> Self {
+        let secret = match setting {
+            Some(s) if !s.secret.is_empty() => s.secret.as_bytes().to_vec(),
+            _ => {
+                event!(Level::WARN, "No sticky secret for {}, affinity cookies are only valid for this gateway instance", service_id);
+                PROCESS_SECRET.clone()
+            },
+        };
+        StickyCookie {
+            name: setting.map(|s| s.cookie.clone()).filter(|c| !c.is_empty()).unwrap_or_else(|| String::from(DEFAULT_COOKIE)),
+            path: format!("/{}", service_path.trim_matches('/')),
+            max_age: setting.map(|s| s.max_age).unwrap_or(0),
+            service_id: String::from(service_id),
+            key: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+        }
+    }
+
+    fn sign(&self, upstream_id: &str) -> String {
+        let message = format!("{}|{}", self.service_id, upstream_id);
+        let tag = hmac::sign(&self.key, message.as_bytes());
+        base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD)
+    }
+
+    // upstream id of a valid cookie
+    pub fn upstream(&self, request: &Request<Body>) -> Option<String> {
+        let value = cookie(request.headers(), &self.name)?;
+        let (id, tag) = value.split_once('.')?;
+        let id = String::from_utf8(base64::decode_config(id, base64::URL_SAFE_NO_PAD).ok()?).ok()?;
+        let tag = base64::decode_config(tag, base64::URL_SAFE_NO_PAD).ok()?;
+        let message = format!("{}|{}", self.service_id, id);
+        hmac::verify(&self.key, message.as_bytes(), &tag).ok()?;
+        Some(id)
+    }
+
+    pub fn set_cookie(&self, upstream_id: &str) -> Option<HeaderValue> {
+        let mut value = format!("{}={}.{}; Path={}; HttpOnly",
+            self.name,
+            base64::encode_config(upstream_id, base64::URL_SAFE_NO_PAD),
+            self.sign(upstream_id),
+            self.path,
+        );
+        if self.max_age > 0 {
+            value.push_str(&format!("; Max-Age={}", self.max_age));
+        }
+        HeaderValue::from_str(&value).ok()
+    }
+}
+
+
+/// Route requests with a valid affinity cookie to their upstream while it is ready,
+/// others go to the fallback balancer and get a cookie for the upstream that served them.
+pub struct StickyBalance<D>
+where
+    D: Discover<Key = String>,
+    D::Service: Service<Request<Body>>,
+{
+    discover: D,
+    upstreams: ReadyCache<String, D::Service, Request<Body>>,
+    fallback: BoxedHttpService,
+    cookie: StickyCookie,
+}
+
+
+impl<D> StickyBalance<D>
+where
+    D: Discover<Key = String> + Unpin,
+    D::Error: Into<BoxError>,
+    D::Service: Service<Request<Body>, Response = Response<Body>>,
+    <D::Service as Service<Request<Body>>>::Error: Into<BoxError>,
+{
+    pub fn new(discover: D, fallback: BoxedHttpService, cookie: StickyCookie) -> Self {
+        StickyBalance { discover, upstreams: ReadyCache::default(), fallback, cookie }
+    }
+
+    fn update_pending_from_discover(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(), BoxError>>> {
+        loop {
+            match ready!(Pin::new(&mut self.discover).poll_discover(cx))
+                .transpose()
+                .map_err(|e| e.into())?
+            {
+                None => return Poll::Ready(None),
+                Some(Change::Remove(key)) => {
+                    trace!("remove");
+                    self.upstreams.evict(&key);
+                },
+                Some(Change::Insert(key, svc)) => {
+                    trace!("insert");
+                    self.upstreams.push(key, svc);
+                },
+            }
+        }
+    }
+
+    fn promote_pending_to_ready(&mut self, cx: &mut Context<'_>) {
+        loop {
+            match self.upstreams.poll_pending(cx) {
+                Poll::Ready(Ok(())) => break,
+                Poll::Pending => break,
+                Poll::Ready(Err(error)) => {
+                    debug!(%error, "dropping failed endpoint");
+                }
+            }
+        }
+    }
+}
+
+
+impl<D> Service<Request<Body>> for StickyBalance<D>
+where
+    D: Discover<Key = String> + Unpin,
+    D::Error: Into<BoxError>,
+    D::Service: Service<Request<Body>, Response = Response<Body>>,
+    <D::Service as Service<Request<Body>>>::Error: Into<BoxError> + 'static,
+    <D::Service as Service<Request<Body>>>::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let _ = self.update_pending_from_discover(cx)?;
+        self.promote_pending_to_ready(cx);
+        self.fallback.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        // pinned upstream is skipped while its circuit breaker is open
+        let pinned = self.cookie.upstream(&request)
+            .filter(|id| self.upstreams.get_ready(id).is_some());
+        match pinned {
+            Some(id) => Box::pin(self.upstreams.call_ready(&id, request).map_err(Into::into)),
+            None => {
+                let cookie = self.cookie.clone();
+                let fut = self.fallback.call(request);
+                Box::pin(async move {
+                    let mut resp = fut.await?;
+                    let set_cookie = resp.headers().get("X-UPSTREAM-ID")
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|id| cookie.set_cookie(id));
+                    if let Some(value) = set_cookie {
+                        resp.headers_mut().append(header::SET_COOKIE, value);
+                    }
+                    Ok(resp)
+                })
+            },
+        }
+    }
+}
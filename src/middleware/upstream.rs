@@ -1,140 +1,558 @@
 use crate::middleware::weighted::WeightedBalance;
+use crate::middleware::hash_ring::HashRingBalance;
+use crate::middleware::discover::{discover_channel, DiscoverSender};
 use hyper::{Request, Response, Body};
-use hyper::header::HeaderValue;
 use tokio::sync::mpsc;
 use std::time::Duration;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use rand::Rng;
 use tower::Service;
-use tower::steer::Steer;
-use tower::discover::ServiceList;
-use tower::load::{PeakEwmaDiscover, PendingRequestsDiscover, CompleteOnResponse, Constant};
+use tower::discover::Change;
+use tower::load::{PeakEwma, PendingRequests, CompleteOnResponse};
 use tower::balance::p2c::Balance;
 use tower::limit::concurrency::ConcurrencyLimit;
 use tower::load_shed::LoadShed;
-use tower::util::{BoxService, ServiceExt};
+use tower::util::BoxService;
 use std::future::Future;
 use std::pin::Pin;
-use std::hash::{Hash, Hasher};
-use std::collections::hash_map::DefaultHasher;
-use crate::config::{ConfigUpdate, ServiceInfo};
+use std::task::Poll;
+use crate::config::{ConfigUpdate, ServiceInfo, FilterSetting, Upstream};
+use crate::middleware::{CanaryRouter, UpstreamVersion, MirrorHandler, HashKeySource, StickyBalance, StickyCookie};
+use crate::middleware::{OutlierDetector, OutlierService, WarmUp, SlowStart, RampedDiscover};
+use crate::middleware::{PriorityBalance, PriorityLevel, Hedger, HedgeOrigin, copy_request};
 use crate::middleware::{Middleware, MwPreRequest, MwPreResponse, MwPostRequest, MwNextAction, GatewayError};
 use crate::middleware::proxy::ProxyHandler;
+use crate::discovery::{self, EndpointSender, StopHandle};
 use tracing::{event, Level};
 use crate::middleware::{CircuitBreakerConfig, CircuitBreakerService};
 
 
+lazy_static::lazy_static! {
+    static ref VERSION_MISSING_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_canary_version_missing_total",
+        "Requests routed to a version without upstreams, balanced over all upstreams",
+        &["service", "version"]
+    ).unwrap();
+}
+
+
 #[derive(Debug)]
 pub struct UpstreamMiddleware {
     pub worker_queues: HashMap<String, mpsc::Sender<MwPreRequest>>,
-    
+    pub routers: HashMap<String, CanaryRouter>,
+    worker_updates: HashMap<String, mpsc::UnboundedSender<ServiceInfo>>,  // config updates of running service workers
 }
 
 impl Default for UpstreamMiddleware {
     fn default() -> Self {
-        UpstreamMiddleware { worker_queues: HashMap::new() }
+        UpstreamMiddleware { worker_queues: HashMap::new(), routers: HashMap::new(), worker_updates: HashMap::new() }
     }
 }
 
 
 type BoxedHttpService = BoxService<Request<Body>, Response<Body>, Box<dyn std::error::Error + Send + Sync>>;
+type UpstreamService = SlowStart<CircuitBreakerService<OutlierService<LoadShed<ConcurrencyLimit<ProxyHandler>>>>>;
+type UpstreamServices = HashMap<String, UpstreamService>;
 
 
-impl UpstreamMiddleware {
+// balanced service over a set of upstreams, upstream changes are fed to its discover streams
+struct BalancedSet {
+    service: BoxedHttpService,
+    balancer: Option<DiscoverSender<UpstreamService>>,  // None for single upstream and priority levels
+    pinned: Option<DiscoverSender<UpstreamService>>,  // upstreams of sticky balance
+}
 
-    async fn service_worker(mut rx: mpsc::Receiver<MwPreRequest>, conf: ServiceInfo) {
 
-        let mut service = Self::build_service(&conf);
+impl BalancedSet {
 
-        while let Some(MwPreRequest {context, request, result, .. }) = rx.recv().await {
-            event!(Level::DEBUG, "request {:?}", request.uri());
-            if let Ok(px) = service.ready().await {
-                let f = px.call(request);
-                tokio::spawn(async move {
-                    let proxy_resp: Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>>  = f.await;
-                    match proxy_resp {
-                        Ok(resp) => {
-                            let response = MwPreResponse { context, next: MwNextAction::Return(resp) };
-                            let _ = result.send(Ok(response));
-                        },
-                        Err(e) => {
-                            if let Some(err) = e.downcast_ref::<GatewayError>() {
-                                let _ = result.send(Err(err.clone()));
-                            } else {
-                                let msg = format!("Upstream error\n{:?}", e);
-                                let _ = result.send(Err(GatewayError::UpstreamError(msg)));
-                            }
-                        },
-                    }
-                });
-            } else {
-                let _ = result.send(Err(GatewayError::ServiceNotReady("Service not ready".into())));
-            }
+    fn new(conf: &ServiceInfo, upstreams: &UpstreamServices, members: &[&Upstream]) -> Self {
+        let priorities = Self::priorities(members);
+        if priorities.len() > 1 {
+            // one set per priority level, levels are rebuilt on any upstream change
+            let levels = priorities.iter().map(|p| {
+                let level: Vec<&Upstream> = members.iter().filter(|u| u.priority == *p).cloned().collect();
+                let weighted: Vec<(u32, UpstreamService)> = level.iter()
+                    .map(|u| (u.weight.max(1), upstreams[&u.id].clone()))
+                    .collect();
+                PriorityLevel {
+                    priority: *p,
+                    service: BalancedSet::new(conf, upstreams, &level).service,
+                    healthy: Box::new(move || {
+                        let total: u32 = weighted.iter().map(|(w, _)| w).sum();
+                        let healthy: u32 = weighted.iter().filter(|(_, svc)| Self::healthy(svc)).map(|(w, _)| w).sum();
+                        healthy as f64 / total as f64
+                    }),
+                }
+            }).collect();
+            let service = PriorityBalance::new(&conf.service_id, levels, conf.failover.as_ref());
+            return BalancedSet { service: BoxService::new(service), balancer: None, pinned: None };
         }
-    }
-
-    fn build_service(conf: &ServiceInfo) -> BoxedHttpService {
-
-        match conf.upstreams.len() {
+        match members.len() {
             0 => {
-                panic!("Invalid upstream config");
+                // discovered upstreams not found yet, set is rebuilt on next update
+                let service = tower::service_fn(|_: Request<Body>| async {
+                    Err(GatewayError::ServiceNotReady("No upstream available".into()).into())
+                });
+                BalancedSet { service: BoxService::new(service), balancer: None, pinned: None }
             },
             1 => {
-                let u = conf.upstreams.get(0).unwrap();
-                let cb_config = CircuitBreakerConfig {
-                    error_threshold: u.error_threshold,    
-                    error_reset: Duration::from_secs(u.error_reset),
-                    retry_delay: Duration::from_secs(u.retry_delay),
-                };
-                let us = ProxyHandler::new(&conf.service_id, u, conf.timeout);
-                let limit = ConcurrencyLimit::new(us, u.max_conn as usize);
-                let cb = CircuitBreakerService::new(LoadShed::new(limit), cb_config);
-                BoxService::new(LoadShed::new(cb))
+                let svc = upstreams[&members[0].id].clone();
+                BalancedSet { service: BoxService::new(LoadShed::new(svc)), balancer: None, pinned: None }
             },
             _ => {
-                let u = conf.upstreams.get(0).unwrap();
-                let cb_config = CircuitBreakerConfig {
-                    error_threshold: u.error_threshold,    
-                    error_reset: Duration::from_secs(u.error_reset),
-                    retry_delay: Duration::from_secs(u.retry_delay),
+                let set = if conf.load_balance.eq("sticky") {
+                    let (pinned, discover) = discover_channel();
+                    let setting = conf.sticky.as_ref();
+                    let fallback = setting.map(|s| s.fallback.as_str()).unwrap_or("");
+                    let (service, balancer) = Self::balance(fallback);
+                    let cookie = StickyCookie::new(&conf.service_id, &conf.path, setting);
+                    BalancedSet {
+                        service: BoxService::new(StickyBalance::new(discover, service, cookie)),
+                        balancer: Some(balancer),
+                        pinned: Some(pinned),
+                    }
+                } else {
+                    let (service, balancer) = Self::balance(&conf.load_balance);
+                    BalancedSet { service, balancer: Some(balancer), pinned: None }
                 };
-                let list: Vec<Constant<CircuitBreakerService<LoadShed<ConcurrencyLimit<ProxyHandler>>>, u32>> = conf.upstreams.iter().map(|u| {
-                    let us = ProxyHandler::new(&conf.service_id, u, conf.timeout);
-                    let limit = ConcurrencyLimit::new(us, u.max_conn as usize);
-                    let cb = CircuitBreakerService::new(LoadShed::new(limit), cb_config);
-                    Constant::new(cb, u.weight)
-                }).collect();
-
-                if conf.load_balance.eq("hash") {
-                    let list: Vec<LoadShed<Constant<CircuitBreakerService<LoadShed<ConcurrencyLimit<ProxyHandler>>>, u32>>> = list.into_iter().map(|s| LoadShed::new(s)).collect();
-                    let balance = Steer::new(list, |req: &Request<_>, s: &[_]| {
-                        let total = s.len();
-                        let default = HeaderValue::from_static("empty");
-                        let client_id = req.headers().get("x-lb-hash")
-                                    .unwrap_or(&default)
-                                    .as_bytes();
-                        let mut hasher = DefaultHasher::new();
-                        Hash::hash_slice(&client_id, &mut hasher);
-                        (hasher.finish() as usize) % total
-                    });
-                    BoxService::new(balance)
-                } else if conf.load_balance.eq("load") {
-                    let discover = ServiceList::new(list);
-                    let load = PeakEwmaDiscover::new(discover, Duration::from_millis(50), Duration::from_secs(1), CompleteOnResponse::default());
-                    let balance = Balance::new(load);
-                    BoxService::new(balance)
-                } else if conf.load_balance.eq("conn") {
-                    let discover = ServiceList::new(list);
-                    let load = PendingRequestsDiscover::new(discover, CompleteOnResponse::default());
-                    let balance = Balance::new(load);
-                    BoxService::new(balance)
-                } else {  // weighted random
-                    let discover = ServiceList::new(list);
-                    let balance = WeightedBalance::new(discover);
-                    BoxService::new(balance)
+                for u in members {
+                    set.insert(u, &upstreams[&u.id]);
                 }
+                set
             },
         }
     }
+
+    fn balance(load_balance: &str) -> (BoxedHttpService, DiscoverSender<UpstreamService>) {
+        let (tx, discover) = discover_channel();
+        let service = if load_balance.eq("hash") {
+            // keyed by upstream id, ring points stay put when other upstreams come and go
+            BoxService::new(HashRingBalance::new(discover))
+        } else if load_balance.eq("load") {
+            let decay = Duration::from_secs(1).as_nanos() as f64;
+            let load = RampedDiscover::new(discover, move |svc| {
+                PeakEwma::new(svc, Duration::from_millis(50), decay, CompleteOnResponse::default())
+            });
+            let balance = Balance::new(load);
+            BoxService::new(balance)
+        } else if load_balance.eq("conn") {
+            let load = RampedDiscover::new(discover, |svc| PendingRequests::new(svc, CompleteOnResponse::default()));
+            let balance = Balance::new(load);
+            BoxService::new(balance)
+        } else {  // weighted random
+            let balance = WeightedBalance::new(discover);
+            BoxService::new(balance)
+        };
+        (service, tx)
+    }
+
+    // feed removed and changed upstreams to balancer, sets without balancer are rebuilt
+    fn update(&mut self, conf: &ServiceInfo, upstreams: &UpstreamServices, old_members: &[&Upstream], new_members: &[&Upstream]) {
+        if self.balancer.is_none() || new_members.len() <= 1 || Self::priorities(new_members).len() > 1 {
+            *self = BalancedSet::new(conf, upstreams, new_members);
+            return;
+        }
+        for u in old_members.iter().filter(|u| !new_members.contains(u)) {
+            self.remove(&u.id);
+        }
+        for u in new_members.iter().filter(|u| !old_members.contains(u)) {
+            self.insert(u, &upstreams[&u.id]);
+        }
+    }
+
+    fn insert(&self, upstream: &Upstream, svc: &UpstreamService) {
+        if let Some(pinned) = &self.pinned {
+            let _ = pinned.send(Change::Insert(upstream.id.clone(), svc.clone()));
+        }
+        if let Some(balancer) = &self.balancer {
+            let _ = balancer.send(Change::Insert(upstream.id.clone(), svc.clone()));
+        }
+    }
+
+    fn priorities(members: &[&Upstream]) -> Vec<u32> {
+        let mut priorities: Vec<u32> = members.iter().map(|u| u.priority).collect();
+        priorities.sort_unstable();
+        priorities.dedup();
+        priorities
+    }
+
+    // breaker admits requests and upstream is not ejected
+    fn healthy(svc: &UpstreamService) -> bool {
+        let breaker = svc.get_ref();
+        breaker.available() && breaker.get_ref().available()
+    }
+
+    fn remove(&self, upstream_id: &str) {
+        if let Some(pinned) = &self.pinned {
+            let _ = pinned.send(Change::Remove(String::from(upstream_id)));
+        }
+        if let Some(balancer) = &self.balancer {
+            let _ = balancer.send(Change::Remove(String::from(upstream_id)));
+        }
+    }
+}
+
+
+// what a request waiting for a ready upstream was woken by
+enum Waiting {
+    Ready(Option<String>),  // version of the ready set
+    NotReady,  // set failed or wait timed out
+    Update(ServiceInfo),
+    Discovered(Upstream, Vec<Upstream>),
+}
+
+
+// per service state, kept across config updates of the service
+struct ServiceWorker {
+    conf: ServiceInfo,
+    members: Vec<Upstream>,  // static upstreams and endpoints discovered for DNS or file upstreams
+    discovered: HashMap<String, Vec<Upstream>>,
+    watchers: HashMap<String, StopHandle>,  // discovery task of each discovered upstream
+    endpoints: EndpointSender,
+    upstreams: UpstreamServices,  // shared by balanced sets, clones share connections, limits and breaker
+    outlier: Option<Arc<OutlierDetector>>,
+    default_set: BalancedSet,
+    version_sets: HashMap<String, BalancedSet>,  // one per upstream version, for canary routing
+    mirror: Option<MirrorHandler>,
+    hash_key: Option<HashKeySource>,
+    hedge: Option<Arc<Hedger>>,
+    missing_versions: HashSet<String>,  // requested versions without upstreams, warned once until config or members change
+}
+
+
+impl ServiceWorker {
+
+    fn new(conf: ServiceInfo, endpoints: EndpointSender) -> Self {
+        let members = Self::members(&conf, &HashMap::new());
+        let outlier = Self::outlier(&conf);
+        let upstreams = Self::upstream_services(&conf, outlier.as_ref(), &members);
+        let refs: Vec<&Upstream> = members.iter().collect();
+        let mut worker = ServiceWorker {
+            default_set: BalancedSet::new(&conf, &upstreams, &refs),
+            version_sets: Self::version_sets(&conf, &upstreams, &members),
+            mirror: Self::mirror(&conf),
+            hash_key: Self::hash_key(&conf),
+            hedge: Self::hedge(&conf),
+            discovered: HashMap::new(),
+            watchers: HashMap::new(),
+            missing_versions: HashSet::new(),
+            endpoints,
+            upstreams,
+            outlier,
+            members,
+            conf,
+        };
+        worker.watch(&[]);
+        worker
+    }
+
+    async fn run(
+        mut self,
+        mut rx: mpsc::Receiver<MwPreRequest>,
+        mut updates: mpsc::UnboundedReceiver<ServiceInfo>,
+        mut discovered: mpsc::UnboundedReceiver<(Upstream, Vec<Upstream>)>,
+    ) {
+        loop {
+            tokio::select! {
+                Some(conf) = updates.recv() => self.update(conf),
+                Some((source, endpoints)) = discovered.recv() => self.discovered(source, endpoints),
+                task = rx.recv() => match task {
+                    Some(task) => self.handle(task, &mut updates, &mut discovered).await,
+                    None => break,
+                },
+            }
+        }
+    }
+
+    async fn handle(
+        &mut self,
+        task: MwPreRequest,
+        updates: &mut mpsc::UnboundedReceiver<ServiceInfo>,
+        discovered: &mut mpsc::UnboundedReceiver<(Upstream, Vec<Upstream>)>,
+    ) {
+        let MwPreRequest {context, mut request, result, .. } = task;
+        event!(Level::DEBUG, "request {}", crate::redact::uri(request.uri()));
+        if let Some(source) = &self.hash_key {
+            let key = source.key(&context, &request);
+            request.extensions_mut().insert(key);
+        }
+        if let Some(mirror) = &self.mirror {
+            request = mirror.mirror(request);
+        }
+        // hedge copy is taken before the request is sent, its upstream is recorded to send the copy elsewhere
+        let hedger = self.hedge.clone().filter(|h| h.eligible(&request));
+        let origin = HedgeOrigin::default();
+        let copy = hedger.as_ref().map(|_| {
+            request.extensions_mut().insert(origin.clone());
+            copy_request(&request)
+        });
+        // requests without a known upstream version are balanced over all upstreams
+        let requested = request.extensions().get::<UpstreamVersion>().map(|v| v.0.clone());
+        if let Some(v) = requested.as_ref().filter(|v| !self.members.iter().any(|u| &u.version == *v)) {
+            VERSION_MISSING_COUNTER.with_label_values(&[&self.conf.service_id, v]).inc();
+            if self.missing_versions.insert(v.clone()) {
+                event!(Level::WARN, "No upstream of version {} in {}, requests are balanced over all upstreams", v, self.conf.service_id);
+            }
+        }
+        // updates are applied while waiting, they may be what brings upstreams back, give up after service timeout
+        let deadline = tokio::time::sleep(Duration::from_secs(self.conf.timeout as u64));
+        tokio::pin!(deadline);
+        let ready = loop {
+            let version = requested.clone().filter(|v| self.version_sets.contains_key(v));
+            let set = self.balanced_set(version.as_ref());
+            let event = futures::future::poll_fn(|cx| {
+                if let Poll::Ready(r) = set.service.poll_ready(cx) {
+                    return Poll::Ready(if r.is_ok() { Waiting::Ready(version.clone()) } else { Waiting::NotReady });
+                }
+                if let Poll::Ready(Some(conf)) = updates.poll_recv(cx) {
+                    return Poll::Ready(Waiting::Update(conf));
+                }
+                if let Poll::Ready(Some((source, endpoints))) = discovered.poll_recv(cx) {
+                    return Poll::Ready(Waiting::Discovered(source, endpoints));
+                }
+                if deadline.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(Waiting::NotReady);
+                }
+                Poll::Pending
+            }).await;
+            match event {
+                Waiting::Ready(version) => break Some(version),
+                Waiting::NotReady => break None,
+                Waiting::Update(conf) => self.update(conf),
+                Waiting::Discovered(source, endpoints) => self.discovered(source, endpoints),
+            }
+        };
+        if let Some(version) = ready {
+            let f = self.balanced_set(version.as_ref()).service.call(request);
+            let backup = copy.and_then(|copy| {
+                let target = origin.get().and_then(|id| self.hedge_target(&id, version.as_ref()))?;
+                Some((target, copy))
+            });
+            tokio::spawn(async move {
+                let proxy_resp: Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>> = match hedger {
+                    Some(hedger) => hedger.hedge(f, backup).await,
+                    None => f.await,
+                };
+                match proxy_resp {
+                    Ok(resp) => {
+                        let response = MwPreResponse { context, next: MwNextAction::Return(resp) };
+                        let _ = result.send(Ok(response));
+                    },
+                    Err(e) => {
+                        if let Some(err) = e.downcast_ref::<GatewayError>() {
+                            let _ = result.send(Err(err.clone()));
+                        } else {
+                            let msg = format!("Upstream error\n{:?}", e);
+                            let _ = result.send(Err(GatewayError::UpstreamError(msg)));
+                        }
+                    },
+                }
+            });
+        } else {
+            let _ = result.send(Err(GatewayError::ServiceNotReady("Service not ready".into())));
+        }
+    }
+
+    fn balanced_set(&mut self, version: Option<&String>) -> &mut BalancedSet {
+        match version {
+            Some(v) => self.version_sets.get_mut(v).unwrap(),
+            None => &mut self.default_set,
+        }
+    }
+
+    // apply new service config, only added, removed or changed upstreams are replaced in balancers
+    fn update(&mut self, conf: ServiceInfo) {
+        let old = std::mem::replace(&mut self.conf, conf);
+        self.missing_versions.clear();
+        self.watch(&old.upstreams);
+        let conf = &self.conf;
+        let rebuild = old.load_balance != conf.load_balance
+            || old.hash_key != conf.hash_key
+            || old.sticky != conf.sticky
+            || old.timeout != conf.timeout
+            || old.protocol != conf.protocol
+            || old.outlier_detection != conf.outlier_detection
+            || old.slow_start != conf.slow_start
+            || old.failover != conf.failover
+            || old.path != conf.path;  // path of sticky cookies
+
+        let members = Self::members(conf, &self.discovered);
+        if rebuild {
+            event!(Level::INFO, "Rebuild upstreams of {}", conf.service_id);
+            self.outlier = Self::outlier(conf);
+            self.upstreams = Self::upstream_services(conf, self.outlier.as_ref(), &members);
+            let refs: Vec<&Upstream> = members.iter().collect();
+            self.default_set = BalancedSet::new(conf, &self.upstreams, &refs);
+            self.version_sets = Self::version_sets(conf, &self.upstreams, &members);
+            self.members = members;
+        } else if members != self.members {
+            event!(Level::INFO, "Update upstreams of {}", conf.service_id);
+            self.apply(members);
+        }
+
+        if old.mirror != self.conf.mirror {
+            self.mirror = Self::mirror(&self.conf);
+        }
+        if old.hedge != self.conf.hedge {
+            self.hedge = Self::hedge(&self.conf);
+        }
+        self.hash_key = Self::hash_key(&self.conf);
+    }
+
+    // endpoints of a discovered upstream changed, results of replaced upstream configs are dropped
+    fn discovered(&mut self, source: Upstream, endpoints: Vec<Upstream>) {
+        if !self.conf.upstreams.contains(&source) {
+            return;
+        }
+        event!(Level::INFO, "Discovered {} endpoints of upstream {}/{}", endpoints.len(), self.conf.service_id, source.id);
+        self.discovered.insert(source.id, endpoints);
+        let members = Self::members(&self.conf, &self.discovered);
+        self.apply(members);
+    }
+
+    // start discovery of new or changed upstreams, endpoints found before are kept until replaced
+    fn watch(&mut self, old: &[Upstream]) {
+        let conf = &self.conf;
+        let is_discovered = |id: &String| conf.upstreams.iter().any(|u| &u.id == id && discovery::is_discovered(u));
+        self.watchers.retain(|id, _| is_discovered(id));
+        self.discovered.retain(|id, _| is_discovered(id));
+        for u in conf.upstreams.iter().filter(|u| !old.contains(u)) {
+            // replaced handle stops discovery of the old config
+            if let Some(stop) = discovery::watch(u, self.endpoints.clone()) {
+                self.watchers.insert(u.id.clone(), stop);
+            }
+        }
+    }
+
+    // feed member changes to balanced sets, in-flight requests of removed upstreams complete
+    fn apply(&mut self, members: Vec<Upstream>) {
+        let conf = &self.conf;
+        let old = std::mem::replace(&mut self.members, members);
+        let members = &self.members;
+        self.missing_versions.clear();
+        self.upstreams.retain(|id, _| members.iter().any(|u| &u.id == id));
+        for u in members.iter().filter(|u| !old.contains(u)) {
+            let svc = Self::upstream_service(conf, self.outlier.as_ref(), u);
+            // upstreams joining a serving set warm up, changed ones keep their pace
+            if !old.is_empty() && !old.iter().any(|o| o.id == u.id) {
+                if let Some(warm_up) = svc.warm_up() {
+                    event!(Level::INFO, "Upstream {}/{} warming up", conf.service_id, u.id);
+                    warm_up.restart();
+                }
+            }
+            self.upstreams.insert(u.id.clone(), svc);
+        }
+
+        let old_members: Vec<&Upstream> = old.iter().collect();
+        let new_members: Vec<&Upstream> = members.iter().collect();
+        self.default_set.update(conf, &self.upstreams, &old_members, &new_members);
+
+        if Self::versions(&old) != Self::versions(members) {
+            self.version_sets = Self::version_sets(conf, &self.upstreams, members);
+        } else {
+            for (version, set) in self.version_sets.iter_mut() {
+                let old_members: Vec<&Upstream> = old.iter().filter(|u| &u.version == version).collect();
+                let new_members: Vec<&Upstream> = members.iter().filter(|u| &u.version == version).collect();
+                set.update(conf, &self.upstreams, &old_members, &new_members);
+            }
+        }
+    }
+
+    // DNS and file upstreams are replaced by their discovered endpoints
+    fn members(conf: &ServiceInfo, discovered: &HashMap<String, Vec<Upstream>>) -> Vec<Upstream> {
+        conf.upstreams.iter()
+            .flat_map(|u| if discovery::is_discovered(u) {
+                discovered.get(&u.id).cloned().unwrap_or_default()
+            } else {
+                vec![u.clone()]
+            })
+            .collect()
+    }
+
+    // breaker of each upstream uses its own settings, outlier detection is shared by upstreams of the service,
+    // slow start warms an upstream again when its breaker closes
+    fn upstream_service(conf: &ServiceInfo, outlier: Option<&Arc<OutlierDetector>>, u: &Upstream) -> UpstreamService {
+        let cb_config = CircuitBreakerConfig {
+            error_threshold: u.error_threshold,
+            error_rate: u.error_rate,
+            error_window: Duration::from_secs(u.error_reset),
+            retry_delay: Duration::from_secs(u.retry_delay),
+            half_open_requests: u.half_open_requests.max(1),
+            half_open_success_ratio: if u.half_open_success_ratio > 0.0 { u.half_open_success_ratio.min(1.0) } else { 1.0 },
+            state_header: u.breaker_header,
+        };
+        let us = ProxyHandler::new(&conf.service_id, u, conf.timeout);
+        let limit = LoadShed::new(ConcurrencyLimit::new(us, u.max_conn as usize));
+        let limit = match outlier {
+            Some(detector) => detector.layer(&u.id, limit),
+            None => OutlierService::disabled(limit),
+        };
+        let warm_up = conf.slow_start.as_ref().map(WarmUp::new);
+        let breaker = CircuitBreakerService::new(limit, cb_config, &conf.service_id, &u.id, warm_up.clone());
+        SlowStart::new(breaker, u.weight, warm_up)
+    }
+
+    fn upstream_services(conf: &ServiceInfo, outlier: Option<&Arc<OutlierDetector>>, members: &[Upstream]) -> UpstreamServices {
+        members.iter().map(|u| (u.id.clone(), Self::upstream_service(conf, outlier, u))).collect()
+    }
+
+    fn outlier(conf: &ServiceInfo) -> Option<Arc<OutlierDetector>> {
+        conf.outlier_detection.as_ref().map(|o| OutlierDetector::new(&conf.service_id, o))
+    }
+
+    fn versions(members: &[Upstream]) -> Vec<&String> {
+        let mut versions: Vec<&String> = members.iter().map(|u| &u.version).collect();
+        versions.sort();
+        versions.dedup();
+        versions
+    }
+
+    fn version_sets(conf: &ServiceInfo, upstreams: &UpstreamServices, members: &[Upstream]) -> HashMap<String, BalancedSet> {
+        let versions = Self::versions(members);
+        let mut sets = HashMap::new();
+        if versions.len() > 1 {
+            for v in versions {
+                let set: Vec<&Upstream> = members.iter().filter(|u| &u.version == v).collect();
+                sets.insert(v.clone(), BalancedSet::new(conf, upstreams, &set));
+            }
+        }
+        sets
+    }
+
+    // weighted random healthy upstream of the same priority and version, other than the one the request went to
+    fn hedge_target(&self, origin: &str, version: Option<&String>) -> Option<UpstreamService> {
+        let priority = self.members.iter().find(|u| u.id == origin)?.priority;
+        let candidates: Vec<&Upstream> = self.members.iter()
+            .filter(|u| u.id != origin && u.priority == priority)
+            .filter(|u| version.map(|v| &u.version == v).unwrap_or(true))
+            .filter(|u| BalancedSet::healthy(&self.upstreams[&u.id]))
+            .collect();
+        let total: u32 = candidates.iter().map(|u| u.weight.max(1)).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut point = rand::thread_rng().gen_range(0..total);
+        for u in candidates {
+            if point < u.weight.max(1) {
+                return Some(self.upstreams[&u.id].clone());
+            }
+            point -= u.weight.max(1);
+        }
+        None
+    }
+
+    fn hedge(conf: &ServiceInfo) -> Option<Arc<Hedger>> {
+        conf.hedge.as_ref().map(|h| Hedger::new(&conf.service_id, h))
+    }
+
+    fn mirror(conf: &ServiceInfo) -> Option<MirrorHandler> {
+        conf.mirror.as_ref().map(|m| MirrorHandler::new(&conf.service_id, m, conf.timeout))
+    }
+
+    fn hash_key(conf: &ServiceInfo) -> Option<HashKeySource> {
+        if conf.load_balance.eq("hash") { Some(HashKeySource::new(conf.hash_key.as_ref())) } else { None }
+    }
 }
 
 
@@ -152,7 +570,12 @@ impl Middleware for UpstreamMiddleware {
         false
     }
 
-    fn request(&mut self, task: MwPreRequest) -> Pin<Box<dyn Future<Output=()> + Send>> {
+    fn request(&mut self, mut task: MwPreRequest) -> Pin<Box<dyn Future<Output=()> + Send>> {
+        if let Some(router) = self.routers.get(&task.context.service_id) {
+            if let Some(version) = router.route(&task.context, &task.request) {
+                task.request.extensions_mut().insert(UpstreamVersion(version));
+            }
+        }
         if let Some(ch) = self.worker_queues.get_mut(&task.context.service_id) {
             let task_ch = ch.clone();
             Box::pin(async move {
@@ -172,19 +595,42 @@ impl Middleware for UpstreamMiddleware {
     fn config_update(&mut self, update: ConfigUpdate) {
         match update {
             ConfigUpdate::ServiceUpdate(conf) => {
-                let (tx, rx) = mpsc::channel(10);
                 let service_id = conf.service_id.clone();
-                if (&conf.upstreams).len() > 0 {
-                    tokio::spawn(async move {
-                        Self::service_worker(rx, conf).await;
-                    });
-                    self.worker_queues.insert(service_id, tx);
-                } else {
+                // canary rules and splits are switched in place, the service worker keeps running
+                let canary = conf.filters.iter().find_map(|f| match f {
+                    FilterSetting::Canary(setting) => Some(CanaryRouter::new(setting)),
+                    _ => None,
+                });
+                match canary {
+                    Some(router) => { self.routers.insert(service_id.clone(), router); },
+                    None => { self.routers.remove(&service_id); },
+                }
+                if conf.upstreams.is_empty() {
                     self.worker_queues.remove(&service_id);
+                    self.worker_updates.remove(&service_id);
+                    return;
                 }
+                // running worker diffs upstreams itself, connections, load and breaker state are kept
+                let conf = match self.worker_updates.get(&service_id) {
+                    Some(updates) => match updates.send(*conf) {
+                        Ok(_) => return,
+                        Err(mpsc::error::SendError(conf)) => conf,
+                    },
+                    None => *conf,
+                };
+                let (tx, rx) = mpsc::channel(10);
+                let (update_tx, update_rx) = mpsc::unbounded_channel();
+                let (endpoint_tx, endpoint_rx) = mpsc::unbounded_channel();
+                tokio::spawn(async move {
+                    ServiceWorker::new(conf, endpoint_tx).run(rx, update_rx, endpoint_rx).await;
+                });
+                self.worker_queues.insert(service_id.clone(), tx);
+                self.worker_updates.insert(service_id, update_tx);
             },
             ConfigUpdate::ServiceRemove(sid) => {
                 self.worker_queues.remove(&sid);
+                self.worker_updates.remove(&sid);
+                self.routers.remove(&sid);
             },
             _ => {},
         }
@@ -192,3 +638,77 @@ impl Middleware for UpstreamMiddleware {
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service() -> ServiceInfo {
+        serde_yaml::from_str(r#"
+            service_id: test/update
+            path: /update
+            protocol: http
+            auth: {type: AppKey}
+            timeout: 3
+            load_balance: random
+            filters: []
+            sla:
+              - name: Default
+                filters: []
+            upstreams:
+              - {id: "1", target: "http://127.0.0.1:54320/", max_conn: 10, version: "1.0", weight: 100, error_threshold: 3, error_reset: 60, retry_delay: 10}
+              - {id: "2", target: "http://127.0.0.1:54322/", max_conn: 10, version: "2.0", weight: 100, error_threshold: 3, error_reset: 60, retry_delay: 10}
+            outlier_detection: {consecutive_5xx: 5}
+        "#).unwrap()
+    }
+
+    fn kept(before: &UpstreamServices, worker: &ServiceWorker, id: &str) -> bool {
+        before[id].get_ref().shares_state(worker.upstreams[id].get_ref())
+    }
+
+    #[tokio::test]
+    async fn update_without_upstream_changes_keeps_state() {
+        let (endpoints, _rx) = mpsc::unbounded_channel();
+        let mut worker = ServiceWorker::new(service(), endpoints);
+        let before = worker.upstreams.clone();
+        let outlier = worker.outlier.clone().unwrap();
+
+        let mut conf = service();
+        conf.filters = serde_yaml::from_str("[{type: Header, setting: {operate_on: request, injection: [[X-TEST, test]], removal: []}}]").unwrap();
+        conf.sla[0].name = String::from("Gold");
+        worker.update(conf.clone());
+        assert!(kept(&before, &worker, "1"));
+        assert!(kept(&before, &worker, "2"));
+        assert!(Arc::ptr_eq(&outlier, worker.outlier.as_ref().unwrap()));
+        assert_eq!(worker.version_sets.len(), 2);
+
+        // only the changed upstream is replaced
+        conf.upstreams[1].weight = 50;
+        worker.update(conf.clone());
+        assert!(kept(&before, &worker, "1"));
+        assert!(!kept(&before, &worker, "2"));
+
+        // balancing changes rebuild all upstreams
+        let before = worker.upstreams.clone();
+        conf.load_balance = String::from("conn");
+        worker.update(conf.clone());
+        assert!(!kept(&before, &worker, "1"));
+        assert!(!kept(&before, &worker, "2"));
+    }
+
+    #[tokio::test]
+    async fn failover_and_path_changes_rebuild() {
+        let (endpoints, _rx) = mpsc::unbounded_channel();
+        let mut worker = ServiceWorker::new(service(), endpoints);
+        let mut conf = service();
+
+        let before = worker.upstreams.clone();
+        conf.failover = serde_yaml::from_str("{healthy_percent: 50}").unwrap();
+        worker.update(conf.clone());
+        assert!(!kept(&before, &worker, "1"));
+
+        let before = worker.upstreams.clone();
+        conf.path = String::from("/moved");
+        worker.update(conf);
+        assert!(!kept(&before, &worker, "1"));
+    }
+}
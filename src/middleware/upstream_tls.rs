@@ -0,0 +1,154 @@
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError};
+use rustls::internal::pemfile;
+use tokio_rustls::webpki::DNSNameRef;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, Mutex, Weak};
+use tracing::{event, Level};
+use crate::config::UpstreamTls;
+
+
+lazy_static::lazy_static! {
+
+    // native roots are loaded once, upstreams without CA bundle share the default config
+    static ref NATIVE_ROOTS: RootCertStore = match rustls_native_certs::load_native_certs() {
+        Ok(store) => store,
+        Err((Some(store), err)) => {
+            event!(Level::WARN, "Could not load all native certificates: {:?}", err);
+            store
+        },
+        Err((None, err)) => {
+            event!(Level::ERROR, "Cannot access native cert store, https upstreams need a ca_file: {:?}", err);
+            RootCertStore::empty()
+        },
+    };
+
+    static ref DEFAULT_CONFIG: Arc<ClientConfig> = {
+        let mut config = ClientConfig::new();
+        config.root_store = NATIVE_ROOTS.clone();
+        Arc::new(config)
+    };
+
+    // configs of upstream TLS settings in use, files are read again once all users are dropped
+    static ref CONFIGS: Mutex<HashMap<UpstreamTls, Weak<ClientConfig>>> = Mutex::new(HashMap::new());
+}
+
+
+/// Client config of an upstream, shared by upstreams with the same settings.
+/// Invalid settings are logged and returned as error, https requests to the upstream fail with it.
+pub fn client_config(upstream_id: &str, tls: Option<&UpstreamTls>) -> Result<Arc<ClientConfig>, String> {
+    let tls = match tls {
+        Some(t) if !t.ca_file.is_empty() || !t.cert_file.is_empty() || t.insecure_skip_verify => t,
+        _ => return Ok(DEFAULT_CONFIG.clone()),
+    };
+    let mut configs = CONFIGS.lock().unwrap();
+    if let Some(config) = configs.get(tls).and_then(|c| c.upgrade()) {
+        return Ok(config);
+    }
+    match build(tls) {
+        Ok(config) => {
+            let config = Arc::new(config);
+            configs.retain(|_, c| c.strong_count() > 0);
+            configs.insert(tls.clone(), Arc::downgrade(&config));
+            Ok(config)
+        },
+        Err(e) => {
+            let msg = format!("Invalid TLS settings of upstream {}: {}", upstream_id, e);
+            event!(Level::ERROR, "{}", msg);
+            Err(msg)
+        },
+    }
+}
+
+
+fn build(tls: &UpstreamTls) -> Result<ClientConfig, String> {
+    let mut config = ClientConfig::new();
+    config.root_store = if tls.ca_file.is_empty() {
+        NATIVE_ROOTS.clone()
+    } else {
+        let mut store = RootCertStore::empty();
+        match store.add_pem_file(&mut open(&tls.ca_file)?) {
+            Ok((0, _)) | Err(()) => return Err(format!("no CA certificate in {}", tls.ca_file)),
+            Ok(_) => store,
+        }
+    };
+    if !tls.cert_file.is_empty() {
+        let certs = pemfile::certs(&mut open(&tls.cert_file)?)
+            .map_err(|_| format!("invalid certificate file {}", tls.cert_file))?;
+        let key = private_key(&tls.key_file)?;
+        config.set_single_client_cert(certs, key).map_err(|e| e.to_string())?;
+    }
+    if tls.insecure_skip_verify {
+        event!(Level::WARN, "Upstream certificate verification is disabled");
+        config.dangerous().set_certificate_verifier(Arc::new(NoVerification));
+    }
+    Ok(config)
+}
+
+
+fn private_key(path: &str) -> Result<rustls::PrivateKey, String> {
+    let invalid = || format!("invalid private key file {}", path);
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?).map_err(|_| invalid())?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?).map_err(|_| invalid())?;
+    }
+    keys.into_iter().next().ok_or_else(|| format!("no private key in {}", path))
+}
+
+
+fn open(path: &str) -> Result<BufReader<File>, String> {
+    File::open(path).map(BufReader::new).map_err(|e| format!("cannot open {}: {}", path, e))
+}
+
+
+struct NoVerification;
+
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(&self, _roots: &RootCertStore, _presented_certs: &[Certificate],
+                          _dns_name: DNSNameRef<'_>, _ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn settings(ca_file: &str, cert_file: &str, key_file: &str) -> UpstreamTls {
+        UpstreamTls {
+            ca_file: ca_file.into(),
+            cert_file: cert_file.into(),
+            key_file: key_file.into(),
+            sni: String::new(),
+            insecure_skip_verify: false,
+        }
+    }
+
+    #[test]
+    fn same_settings_share_config() {
+        let tls = settings(&fixture("ca.pem"), &fixture("client.pem"), &fixture("client-key.pem"));
+        let first = client_config("a", Some(&tls)).unwrap();
+        let second = client_config("b", Some(&tls)).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(Arc::ptr_eq(&client_config("c", None).unwrap(), &DEFAULT_CONFIG));
+    }
+
+    #[test]
+    fn invalid_settings_are_errors() {
+        let ca = fixture("ca.pem");
+        let err = client_config("a", Some(&settings("/nonexistent/ca.pem", "", ""))).err().unwrap();
+        assert!(err.starts_with("Invalid TLS settings of upstream a: cannot open /nonexistent/ca.pem"), "{}", err);
+        let err = client_config("a", Some(&settings(&fixture("client-key.pem"), "", ""))).err().unwrap();
+        assert!(err.contains("no CA certificate in"), "{}", err);
+        let err = client_config("a", Some(&settings(&ca, &fixture("client.pem"), &ca))).err().unwrap();
+        assert!(err.contains("no private key in"), "{}", err);
+        assert!(client_config("a", Some(&settings(&ca, &fixture("client.pem"), ""))).is_err());
+    }
+}
@@ -12,7 +12,7 @@ use tracing::{debug, trace};
 
 pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
-/// Random load balance, use load as service weight
+/// Random load balance, use load as service weight, read on each pick
 pub struct WeightedBalance<D, Req>
 where
     D: Discover,
@@ -57,7 +57,7 @@ where
     D::Key: Hash + Clone,
     D::Error: Into<BoxError>,
     D::Service: Service<Req> + Load,
-    <D::Service as Load>::Metric: std::fmt::Debug + Into<u32>,
+    <D::Service as Load>::Metric: std::fmt::Debug + Into<f64>,
     <D::Service as Service<Req>>::Error: Into<BoxError>,
 {
     fn update_pending_from_discover(
@@ -115,15 +115,18 @@ where
             0 => None,
             1 => Some(0),
             len => {
-                let mut weights: Vec<u32> = Vec::new();
+                let mut weights: Vec<f64> = Vec::new();
                 for i in 0..len {
                     let (_, svc) = self.services.get_ready_index(i).expect("invalid index");
                     weights.push(svc.load().into())
                 }
-                let total: u32 = weights.iter().sum();
-                let mut point = self.rng.gen_range(0..total);
+                let total: f64 = weights.iter().sum();
+                if total <= 0.0 {
+                    return Some(self.rng.gen_range(0..len));
+                }
+                let mut point = self.rng.gen_range(0.0..total);
                 for i in 0..weights.len() {
-                    if point <= weights[i] {
+                    if point < weights[i] {
                         return Some(i)
                     } else {
                         point = point - weights[i] 
@@ -142,7 +145,7 @@ where
     D::Key: Hash + Clone,
     D::Error: Into<BoxError>,
     D::Service: Service<Req> + Load,
-    <D::Service as Load>::Metric: std::fmt::Debug + Into<u32>,
+    <D::Service as Load>::Metric: std::fmt::Debug + Into<f64>,
     <D::Service as Service<Req>>::Error: Into<BoxError>,
 {
     type Response = <D::Service as Service<Req>>::Response;
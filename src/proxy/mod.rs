@@ -2,7 +2,7 @@ mod server;
 mod request_handler;
 pub mod https;
 
-pub use server::GatewayServer;
+pub use server::{GatewayServer, ServerSetting};
 pub use request_handler::RequestHandler;
 pub use https::{TlsAcceptor, TlsConfigBuilder};
 
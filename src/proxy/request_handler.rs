@@ -1,11 +1,18 @@
 use hyper::{Request, Response, Body};
+use hyper::header::{self, HeaderValue};
+use uuid::Uuid;
 use tokio::sync::{mpsc, oneshot};
 use tower::Service;
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Poll, Context};
+use std::net::SocketAddr;
+use std::sync::Arc;
 use crate::auth::AuthRequest;
-use crate::middleware::{MiddlewareHandle, RequestContext, GatewayError, middleware_chain};
+use crate::middleware::{MiddlewareHandle, RequestContext, GatewayError, ForwardInfo, AccessLogEntry, CountingBody, middleware_chain, decorate_gateway_response};
+use crate::trace::{TraceContext, SpanRecord, SpanKind, should_sample};
+use crate::redact;
+use super::ServerSetting;
 use tracing::{event, span, Level, Instrument};
 use prometheus::{Encoder, TextEncoder};
 
@@ -14,6 +21,9 @@ pub struct RequestHandler {
     pub stack: Vec<MiddlewareHandle>,
     pub auth: mpsc::Sender<AuthRequest>,
     pub ready: u8,
+    pub setting: Arc<ServerSetting>,
+    pub remote_addr: Option<SocketAddr>,
+    pub scheme: &'static str,
 }
 
 impl RequestHandler {
@@ -30,10 +40,34 @@ impl RequestHandler {
             .unwrap();
         response
     }
+
+    fn forward_info(&self) -> Option<ForwardInfo> {
+        let client_ip = self.remote_addr?.ip();
+        let trusted = self.setting.trusted_proxies.iter().any(|net| net.contains(&client_ip));
+        Some(ForwardInfo { client_ip, proto: String::from(self.scheme), trusted })
+    }
+
+    // accept request id from trusted proxies if it is a valid uuid, otherwise generate a new one
+    fn request_id(&self, req: &Request<Body>, info: Option<&ForwardInfo>) -> Uuid {
+        if info.map(|i| i.trusted).unwrap_or(false) {
+            let incoming = req.headers().get(&self.setting.request_id_header)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| Uuid::parse_str(v.trim()).ok());
+            if let Some(id) = incoming {
+                return id;
+            }
+        }
+        Uuid::new_v4()
+    }
+
+    pub fn error_response(status: u16, msg: &str, request_id: &Uuid) -> Response<Body> {
+        let body = format!("{} (request id: {})", msg, request_id);
+        Response::builder().status(status).body(body.into()).unwrap()
+    }
 }
 
 impl Service<Request<Body>> for RequestHandler {
-    type Response = Response<Body>;
+    type Response = Response<CountingBody>;
     type Error = Box<dyn std::error::Error + Send + Sync>;
     type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send + 'static>>;
 
@@ -42,15 +76,43 @@ impl Service<Request<Body>> for RequestHandler {
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let mut req = req;
+        let info = self.forward_info();
+        let request_id = self.request_id(&req, info.as_ref());
+        let id_header = self.setting.request_id_header.clone();
+        let id_value = HeaderValue::from_str(&request_id.to_string()).unwrap();
+        if let Some(info) = info {
+            req.extensions_mut().insert(info);
+        }
+        // forwarded to upstream, and picked up by RequestContext
+        req.headers_mut().insert(id_header.clone(), id_value.clone());
+        req.extensions_mut().insert(request_id);
+
+        // continue incoming trace or start a new one
+        let parent = TraceContext::from_headers(req.headers());
+        let trace = match parent {
+            Some(parent) => parent.child(),
+            None => TraceContext::new_root(should_sample(None)),
+        };
+        let mut server_span = SpanRecord::start(&format!("HTTP {}", req.method()), SpanKind::Server, &trace);
+        server_span.attr("http.method", req.method().as_str());
+        server_span.attr("http.target", redact::path(req.uri().path()));
+        server_span.attr("request_id", request_id.to_string());
+        req.extensions_mut().insert(trace.clone());
+
         if self.ready == 0 {  // starting
-            return Box::pin(async {
-                Ok(Response::new("Server is initializing...".into()))
+            return Box::pin(async move {
+                let mut resp = Response::new("Server is initializing...".into());
+                resp.headers_mut().insert(id_header, id_value);
+                Ok(CountingBody::response(resp))
             })
         }
 
         if self.ready == 2 {  // closing
-            return Box::pin(async {
-                Ok(Response::new("Server is closing...".into()))
+            return Box::pin(async move {
+                let mut resp = Response::new("Server is closing...".into());
+                resp.headers_mut().insert(id_header, id_value);
+                Ok(CountingBody::response(resp))
             })
         }
 
@@ -58,79 +120,131 @@ impl Service<Request<Body>> for RequestHandler {
 
         let auth = self.auth.clone();
 
-        let span = span!(Level::DEBUG, "request");
-        event!(Level::DEBUG, "{:?} {:?}", req.method(), req.uri());
+        // responses produced by gateway itself are logged here, Logger only sees responses of the chain
+        let mut access_log = AccessLogEntry::gateway_response(&req, &request_id);
+        // and get CORS headers here, browsers would hide their status otherwise
+        let origin = req.headers().get(header::ORIGIN).cloned();
+        let (service_path, _api_path) = RequestContext::split_path(req.uri().path());
+
+        let span = span!(Level::DEBUG, "request", request_id=request_id.to_string().as_str());
+        event!(Level::DEBUG, "{:?} {} {:?}", req.method(), redact::uri(req.uri()), redact::headers(req.headers()));
         Box::pin(async move {
-            // auth
-            let (tx, rx) = oneshot::channel();
-            let (head, body) = req.into_parts();
-            let auth_request = AuthRequest {
-                head: head,
-                result: tx,
-            };
-            let _ = auth.send(auth_request).await;
-            let auth_result = rx.await?;
-
-            // handle request
-            match auth_result {
-                Ok((head_part, auth_resp)) => {
-                    let req = Request::from_parts(head_part, body);
-                    let context = RequestContext::new(&req, &auth_resp);
+            let result: Result<Response<Body>, Self::Error> = async move {
+                // auth
+                let mut auth_span = SpanRecord::start("auth", SpanKind::Internal, &trace.child());
+                let (tx, rx) = oneshot::channel();
+                let (head, body) = req.into_parts();
+                let auth_request = AuthRequest {
+                    head: head,
+                    result: tx,
+                };
+                let _ = auth.send(auth_request).await;
+                let auth_result = rx.await?;
+                match &auth_result {
+                    Ok((_head, auth_resp)) => {
+                        auth_span.attr("service_id", auth_resp.service_id.as_str());
+                        auth_span.attr("client_id", auth_resp.client_id.as_str());
+                    },
+                    Err(_e) => auth_span.set_error(),
+                }
+                auth_span.finish();
+
+                // handle request
+                match auth_result {
+                    Ok((head_part, auth_resp)) => {
+                        if let Some(entry) = access_log.as_mut() {
+                            entry.set_client(&auth_resp);
+                        }
+                        let req = Request::from_parts(head_part, body);
+                        let context = RequestContext::new(&req, &auth_resp);
                     
-                    // prometheus endpoint
-                    if context.service_path.eq("/metrics") {
-                        let resp = Self::prometheus_endpoint(&req);
-                        return Ok(resp);
-                    }
+                        // prometheus endpoint
+                        if context.service_path.eq("/metrics") {
+                            let resp = Self::prometheus_endpoint(&req);
+                            return Ok(resp);
+                        }
                     
-                    // apply middleware chain
-                    let resp = middleware_chain(req, context, stack).await;
-                    match resp {
-                        Ok(resp) => Ok(resp),
-                        Err(err) => {
-                            match err {
-                                GatewayError::AccessBlocked(_e) => {
-                                    let msg = format!("Not Found");
-                                    Ok(Response::builder().status(404).body(msg.into()).unwrap())
-                                },
-                                GatewayError::RateLimited(_e) => {
-                                    let msg = format!("Rate Limited");
-                                    Ok(Response::builder().status(429).body(msg.into()).unwrap())
-                                },
-                                GatewayError::GatewayInteralError(_e) => {
-                                    let msg = format!("Gateway Internal Error");
-                                    Ok(Response::builder().status(502).body(msg.into()).unwrap())
-                                },
-                                GatewayError::ServiceNotReady(_e) => {
-                                    let msg = format!("Gateway server not ready");
-                                    Ok(Response::builder().status(502).body(msg.into()).unwrap())
-                                },
-                                GatewayError::ServiceNotFound(_e) => {
-                                    let msg = format!("Service not found");
-                                    Ok(Response::builder().status(404).body(msg.into()).unwrap())
-                                },
-                                GatewayError::TimeoutError => {
-                                    let msg = format!("Request Timeout");
-                                    Ok(Response::builder().status(504).body(msg.into()).unwrap())
-                                },
-                                GatewayError::UpstreamError(msg) => {
-                                    Ok(Response::builder().status(502).body(msg.into()).unwrap())
-                                },
-                                GatewayError::ChannelRecvError(msg) => {
-                                    Ok(Response::builder().status(502).body(msg.into()).unwrap())
-                                },
-                                GatewayError::Unknown => {
-                                    Ok(Response::builder().status(502).body("Gateway Error".into()).unwrap())
+                        // apply middleware chain
+                        let resp = middleware_chain(req, context, stack).await;
+                        match resp {
+                            Ok(resp) => Ok(resp),
+                            Err(err) => {
+                                event!(Level::WARN, request_id=request_id.to_string().as_str(), "Gateway error: {:?}", err);
+                                let mut resp = match err {
+                                    GatewayError::AccessBlocked(_e) => {
+                                        let msg = format!("Not Found");
+                                        Self::error_response(404, &msg, &request_id)
+                                    },
+                                    GatewayError::PayloadTooLarge(_e) => {
+                                        let msg = String::from("Payload Too Large");
+                                        Self::error_response(413, &msg, &request_id)
+                                    },
+                                    GatewayError::RateLimited(_e) => {
+                                        let msg = format!("Rate Limited");
+                                        Self::error_response(429, &msg, &request_id)
+                                    },
+                                    GatewayError::GatewayInteralError(_e) => {
+                                        let msg = format!("Gateway Internal Error");
+                                        Self::error_response(502, &msg, &request_id)
+                                    },
+                                    GatewayError::ServiceNotReady(_e) => {
+                                        let msg = format!("Gateway server not ready");
+                                        Self::error_response(502, &msg, &request_id)
+                                    },
+                                    GatewayError::ServiceNotFound(_e) => {
+                                        let msg = format!("Service not found");
+                                        Self::error_response(404, &msg, &request_id)
+                                    },
+                                    GatewayError::TimeoutError => {
+                                        let msg = format!("Request Timeout");
+                                        Self::error_response(504, &msg, &request_id)
+                                    },
+                                    GatewayError::UpstreamError(msg) => {
+                                        Self::error_response(502, &msg, &request_id)
+                                    },
+                                    GatewayError::ChannelRecvError(msg) => {
+                                        Self::error_response(502, &msg, &request_id)
+                                    },
+                                    GatewayError::Unknown => {
+                                        Self::error_response(502, "Gateway Error", &request_id)
+                                    }
+                                };
+                                decorate_gateway_response(&service_path, origin.as_ref(), resp.headers_mut());
+                                if let Some(entry) = access_log {
+                                    entry.finish(&resp);
                                 }
+                                Ok(resp)
                             }
                         }
+                    },
+                    Err(err) => {
+                        event!(Level::WARN, request_id=request_id.to_string().as_str(), "Auth error: {:?}", err);
+                        let msg = format!("Auth Error: {:?}", err);
+                        let mut resp = Self::error_response(502, &msg, &request_id);
+                        decorate_gateway_response(&service_path, origin.as_ref(), resp.headers_mut());
+                        if let Some(entry) = access_log {
+                            entry.finish(&resp);
+                        }
+                        Ok(resp)
                     }
-                },
-                Err(err) => {
-                    let msg = format!("Auth Error: {:?}", err);
-                    Ok(Response::builder().status(502).body(msg.into()).unwrap())
                 }
+            }.await;
+            match &result {
+                Ok(resp) => {
+                    server_span.attr("http.status_code", resp.status().as_u16());
+                    if resp.status().is_server_error() {
+                        server_span.set_error();
+                    }
+                },
+                Err(_e) => server_span.set_error(),
             }
+            server_span.finish();
+
+            // echo request id to client, access log of the chain is written once body is sent
+            result.map(|mut resp| {
+                resp.headers_mut().insert(id_header, id_value);
+                CountingBody::response(resp)
+            })
         }.instrument(span))
     }
 }
@@ -1,40 +1,88 @@
 use tokio::sync::{mpsc, broadcast};
 use tracing::{event, Level};
 use crate::middleware::{MiddlewareHandle, Middleware, HeaderMiddleware, RateLimitMiddleware, 
-    UpstreamMiddleware, LoggerMiddleware, ACLMiddleware};
+    UpstreamMiddleware, LoggerMiddleware, ACLMiddleware, CORSMiddleware, CacheMiddleware,
+    CompressionMiddleware, BodyLimitMiddleware, RewriteMiddleware};
 use crate::config::{ConfigSource, ConfigUpdate};
 use super::RequestHandler;
 use crate::auth::{AuthService, AuthRequest};
 use futures::StreamExt;
 use std::sync::{Arc, Mutex};
+use std::net::SocketAddr;
+use ipnet::IpNet;
+use hyper::header::HeaderName;
 use crate::start_middleware_macro;
 
 
+lazy_static::lazy_static! {
+    static ref CONFIG_UPDATE_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_config_updates_total",
+        "Number of config updates applied, by update type.",
+        &["type"]
+    ).unwrap();
+
+    static ref CONFIG_APPLIED_TIME: prometheus::Gauge = prometheus::register_gauge!(
+        "gateway_config_last_applied_timestamp_seconds",
+        "Unix time of last applied config update."
+    ).unwrap();
+}
+
+
+// server wide settings from command line
+#[derive(Debug, Clone)]
+pub struct ServerSetting {
+    pub trusted_proxies: Vec<IpNet>,   // peers allowed to pass X-Forwarded-* and Forwarded headers
+    pub request_id_header: HeaderName,  // request id is accepted from trusted proxies, forwarded and echoed
+}
+
+
+impl Default for ServerSetting {
+    fn default() -> Self {
+        ServerSetting {
+            trusted_proxies: Vec::new(),
+            request_id_header: HeaderName::from_static("x-request-id"),
+        }
+    }
+}
+
+
 
 pub struct GatewayServer {
     pub service_stack: Vec<MiddlewareHandle>,
     pub auth_channel: mpsc::Sender<AuthRequest>,
     pub config_channel: broadcast::Sender<ConfigUpdate>,
     pub status: Arc<Mutex<u8>>,
+    pub setting: Arc<ServerSetting>,
 }
 
 
 impl GatewayServer {
 
-    pub fn new(mut config: ConfigSource) -> Self {
+    pub fn new(mut config: ConfigSource, setting: ServerSetting) -> Self {
 
         let mut stack = Vec::new();
-        let (conf_tx, conf_rx) = broadcast::channel(16);
+        // file config sends every service and client at once, receivers lagging behind lose updates
+        let (conf_tx, conf_rx) = broadcast::channel(1024);
         let config_channel = conf_tx.clone();
 
         // start upstream middleware, last in stack run first
         start_middleware_macro!(UpstreamMiddleware, stack, conf_tx);
+        // start rewrite middleware, runs right before upstream so other middlewares see original path
+        start_middleware_macro!(RewriteMiddleware, stack, conf_tx);
         // start header middleware
         start_middleware_macro!(HeaderMiddleware, stack, conf_tx);
+        // start cache middleware, hits are served after acl and ratelimit
+        start_middleware_macro!(CacheMiddleware, stack, conf_tx);
         // start ratelimit middleware
         start_middleware_macro!(RateLimitMiddleware, stack, conf_tx);
         // start acl middleware
         start_middleware_macro!(ACLMiddleware, stack, conf_tx);
+        // start body limit middleware, limits apply to decompressed request body
+        start_middleware_macro!(BodyLimitMiddleware, stack, conf_tx);
+        // start compression middleware, compress responses outside of cache
+        start_middleware_macro!(CompressionMiddleware, stack, conf_tx);
+        // start cors middleware, answers preflight before acl and ratelimit
+        start_middleware_macro!(CORSMiddleware, stack, conf_tx);
         // start log middleware
         start_middleware_macro!(LoggerMiddleware, stack, conf_tx);
 
@@ -48,7 +96,18 @@ impl GatewayServer {
                     let mut lock = init_status.lock().unwrap();
                     *lock = 1;
                 }
+                let update_type = match &config_update {
+                    ConfigUpdate::ServiceUpdate(_) => "service_update",
+                    ConfigUpdate::ServiceRemove(_) => "service_remove",
+                    ConfigUpdate::ClientUpdate(_) => "client_update",
+                    ConfigUpdate::ClientRemove(_) => "client_remove",
+                    ConfigUpdate::ConfigReady(_) => "config_ready",
+                    ConfigUpdate::CachePurge(_) => "cache_purge",
+                };
                 let _ = conf_tx.send(config_update);
+                CONFIG_UPDATE_COUNTER.with_label_values(&[update_type]).inc();
+                let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+                CONFIG_APPLIED_TIME.set(now.as_secs_f64());
             }
         });
         
@@ -64,18 +123,20 @@ impl GatewayServer {
             auth_channel: auth_tx,
             status: server_status,
             config_channel,
+            setting: Arc::new(setting),
         }
     }
 
 
-    pub fn make_service(&self) -> RequestHandler {
+    pub fn make_service(&self, remote_addr: Option<SocketAddr>, scheme: &'static str) -> RequestHandler {
         let lock = self.status.clone();
         let ready = {
             lock.lock().unwrap().clone()
         };
         let stack = self.service_stack.clone();
         let auth = self.auth_channel.clone();
-        RequestHandler { stack, auth, ready }
+        let setting = self.setting.clone();
+        RequestHandler { stack, auth, ready, setting, remote_addr, scheme }
     }
 
 }
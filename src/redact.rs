@@ -0,0 +1,240 @@
+use hyper::{HeaderMap, Uri};
+use hyper::header::{HeaderName, HeaderValue};
+use serde_json::Value;
+use std::sync::RwLock;
+
+
+pub const REDACTED: &str = "REDACTED";
+
+
+lazy_static::lazy_static! {
+    static ref POLICY: RwLock<RedactPolicy> = RwLock::new(RedactPolicy::default());
+}
+
+
+// what must never reach logs, traces or captured traffic
+#[derive(Debug, Clone)]
+pub struct RedactPolicy {
+    pub headers: Vec<String>,       // lowercase header names
+    pub query_params: Vec<String>,
+    pub body_fields: Vec<String>,   // JSON object keys, at any depth
+}
+
+
+impl Default for RedactPolicy {
+    fn default() -> Self {
+        RedactPolicy {
+            headers: vec!["authorization".into(), "proxy-authorization".into(), "x-app-key".into()],
+            query_params: vec!["_app_key".into()],
+            body_fields: vec!["app_key".into()],
+        }
+    }
+}
+
+
+impl RedactPolicy {
+
+    // add to built-in rules, app keys are always redacted
+    pub fn extend(mut self, headers: &[String], query_params: &[String], body_fields: &[String]) -> Self {
+        self.headers.extend(headers.iter().map(|h| h.to_lowercase()));
+        self.query_params.extend(query_params.iter().cloned());
+        self.body_fields.extend(body_fields.iter().cloned());
+        self
+    }
+}
+
+
+pub fn init_redact(policy: RedactPolicy) {
+    *POLICY.write().unwrap() = policy;
+}
+
+
+pub fn uri(uri: &Uri) -> String {
+    let mut s = String::new();
+    if let (Some(scheme), Some(authority)) = (uri.scheme_str(), uri.authority()) {
+        s.push_str(scheme);
+        s.push_str("://");
+        s.push_str(authority.as_str());
+    }
+    s.push_str(&path(uri.path()));
+    if let Some(q) = uri.query() {
+        s.push('?');
+        s.push_str(&query(q));
+    }
+    s
+}
+
+
+// path with optional query, e.g. from Uri::path_and_query or a Referer header
+pub fn path_and_query(pq: &str) -> String {
+    if pq.is_empty() {
+        return String::new();
+    }
+    match pq.parse::<Uri>() {
+        Ok(u) => uri(&u),
+        Err(_) => String::from(REDACTED),
+    }
+}
+
+
+// app key path segment, /service/~appkey/api
+pub fn path(path: &str) -> String {
+    path.split('/')
+        .map(|seg| if seg.starts_with('~') && seg.len() > 1 { format!("~{}", REDACTED) } else { String::from(seg) })
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+
+// keep query layout, only replace values of sensitive params
+pub fn query(query: &str) -> String {
+    let policy = POLICY.read().unwrap();
+    query.split('&')
+        .map(|pair| {
+            let key = pair.split('=').next().unwrap_or("");
+            let decoded = serde_urlencoded::from_str::<Vec<(String, String)>>(pair).ok()
+                .and_then(|p| p.into_iter().next())
+                .map(|(k, _v)| k)
+                .unwrap_or_else(|| String::from(key));
+            if policy.query_params.contains(&decoded) {
+                format!("{}={}", key, REDACTED)
+            } else {
+                String::from(pair)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+
+pub fn headers(headers: &HeaderMap) -> HeaderMap {
+    let policy = POLICY.read().unwrap();
+    let mut redacted = headers.clone();
+    for name in &policy.headers {
+        if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+            if redacted.contains_key(&name) {
+                redacted.insert(name, HeaderValue::from_static(REDACTED));
+            }
+        }
+    }
+    redacted
+}
+
+
+// drop sensitive headers from copied traffic, e.g. shadow requests
+pub fn strip_headers(headers: &mut HeaderMap) {
+    let policy = POLICY.read().unwrap();
+    for name in &policy.headers {
+        if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+            headers.remove(name);
+        }
+    }
+}
+
+
+// JSON body with sensitive fields replaced, None if body is not JSON
+pub fn json_body(body: &[u8]) -> Option<Vec<u8>> {
+    let mut value = serde_json::from_slice::<Value>(body).ok()?;
+    json_value(&mut value);
+    serde_json::to_vec(&value).ok()
+}
+
+
+pub fn json_value(value: &mut Value) {
+    let policy = POLICY.read().unwrap();
+    redact_fields(value, &policy.body_fields);
+}
+
+
+fn redact_fields(value: &mut Value, fields: &[String]) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                if fields.contains(k) {
+                    *v = Value::String(String::from(REDACTED));
+                } else {
+                    redact_fields(v, fields);
+                }
+            }
+        },
+        Value::Array(items) => {
+            for v in items.iter_mut() {
+                redact_fields(v, fields);
+            }
+        },
+        _ => {},
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // tests only rely on the default policy, init_redact would leak into other tests
+
+    #[test]
+    fn uri_redacts_app_key_segment_and_query() {
+        let u: Uri = "http://gw.example.com/svc/~secret/api/items?_app_key=secret&page=2".parse().unwrap();
+        assert_eq!(uri(&u), "http://gw.example.com/svc/~REDACTED/api/items?_app_key=REDACTED&page=2");
+        let u: Uri = "/svc/api/items".parse().unwrap();
+        assert_eq!(uri(&u), "/svc/api/items");
+    }
+
+    #[test]
+    fn path_only_touches_app_key_segments() {
+        assert_eq!(path("/svc/~secret/api"), "/svc/~REDACTED/api");
+        assert_eq!(path("/svc/~/api"), "/svc/~/api");
+        assert_eq!(path("/svc/a~b/api"), "/svc/a~b/api");
+    }
+
+    #[test]
+    fn query_keeps_layout() {
+        assert_eq!(query("a=1&_app_key=secret&b"), "a=1&_app_key=REDACTED&b");
+        assert_eq!(query("_app%5Fkey=secret"), "_app%5Fkey=REDACTED");
+        assert_eq!(query("app_key=visible"), "app_key=visible");
+    }
+
+    #[test]
+    fn path_and_query_handles_referers() {
+        assert_eq!(path_and_query(""), "");
+        assert_eq!(path_and_query("/svc/api?_app_key=secret"), "/svc/api?_app_key=REDACTED");
+        assert_eq!(path_and_query("https://app.example.com/~secret/"), "https://app.example.com/~REDACTED/");
+        assert_eq!(path_and_query("not a uri"), REDACTED);
+    }
+
+    #[test]
+    fn headers_replaces_values() {
+        let mut h = HeaderMap::new();
+        h.insert("authorization", HeaderValue::from_static("Bearer token"));
+        h.insert("x-app-key", HeaderValue::from_static("secret"));
+        h.insert("accept", HeaderValue::from_static("*/*"));
+        let redacted = headers(&h);
+        assert_eq!(redacted.get("authorization").unwrap(), REDACTED);
+        assert_eq!(redacted.get("x-app-key").unwrap(), REDACTED);
+        assert_eq!(redacted.get("accept").unwrap(), "*/*");
+        assert_eq!(h.get("x-app-key").unwrap(), "secret");
+    }
+
+    #[test]
+    fn strip_headers_removes_credentials() {
+        let mut h = HeaderMap::new();
+        h.insert("authorization", HeaderValue::from_static("Bearer token"));
+        h.append("x-app-key", HeaderValue::from_static("a"));
+        h.append("x-app-key", HeaderValue::from_static("b"));
+        h.insert("accept", HeaderValue::from_static("*/*"));
+        strip_headers(&mut h);
+        assert_eq!(h.len(), 1);
+        assert_eq!(h.get("accept").unwrap(), "*/*");
+    }
+
+    #[test]
+    fn json_body_redacts_fields_at_any_depth() {
+        let body = json!({"app_key": "secret", "client": {"app_key": "nested", "name": "a"}, "items": [{"app_key": 1}]});
+        let redacted = json_body(&serde_json::to_vec(&body).unwrap()).unwrap();
+        let value: Value = serde_json::from_slice(&redacted).unwrap();
+        assert_eq!(value, json!({"app_key": "REDACTED", "client": {"app_key": "REDACTED", "name": "a"}, "items": [{"app_key": "REDACTED"}]}));
+        assert_eq!(json_body(b"app_key=secret"), None);
+    }
+}
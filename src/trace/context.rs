@@ -0,0 +1,135 @@
+use hyper::HeaderMap;
+use hyper::header::HeaderValue;
+use rand::RngCore;
+
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+
+
+// W3C Trace Context, https://www.w3.org/TR/trace-context/
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent_span_id: Option<[u8; 8]>,
+    pub sampled: bool,
+    pub tracestate: Option<String>,
+}
+
+
+impl TraceContext {
+
+    pub fn new_root(sampled: bool) -> Self {
+        let mut trace_id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut trace_id);
+        TraceContext {
+            trace_id,
+            span_id: new_span_id(),
+            parent_span_id: None,
+            sampled,
+            tracestate: None,
+        }
+    }
+
+    // remote parent context from incoming traceparent and tracestate headers
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let traceparent = headers.get(TRACEPARENT)?.to_str().ok()?;
+        let (trace_id, span_id, flags) = parse_traceparent(traceparent)?;
+        let tracestate: Vec<&str> = headers.get_all(TRACESTATE).iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            parent_span_id: None,
+            sampled: flags & 0x01 == 0x01,
+            tracestate: if tracestate.is_empty() { None } else { Some(tracestate.join(",")) },
+        })
+    }
+
+    // new span in the same trace, parented to this one
+    pub fn child(&self) -> Self {
+        TraceContext {
+            trace_id: self.trace_id,
+            span_id: new_span_id(),
+            parent_span_id: Some(self.span_id),
+            sampled: self.sampled,
+            tracestate: self.tracestate.clone(),
+        }
+    }
+
+    pub fn trace_id_hex(&self) -> String {
+        to_hex(&self.trace_id)
+    }
+
+    pub fn span_id_hex(&self) -> String {
+        to_hex(&self.span_id)
+    }
+
+    pub fn traceparent(&self) -> String {
+        let flags = if self.sampled { "01" } else { "00" };
+        format!("00-{}-{}-{}", self.trace_id_hex(), self.span_id_hex(), flags)
+    }
+
+    // set traceparent and tracestate for outgoing request, this span becomes the remote parent
+    pub fn inject(&self, headers: &mut HeaderMap) {
+        if let Ok(v) = HeaderValue::from_str(&self.traceparent()) {
+            headers.insert(TRACEPARENT, v);
+        }
+        headers.remove(TRACESTATE);
+        if let Some(state) = &self.tracestate {
+            if let Ok(v) = HeaderValue::from_str(state) {
+                headers.insert(TRACESTATE, v);
+            }
+        }
+    }
+}
+
+
+// version-traceid-parentid-flags, returns None for invalid or all-zero ids
+pub fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8], u8)> {
+    let parts: Vec<&str> = value.trim().split('-').collect();
+    if parts.len() < 4 {
+        return None;
+    }
+    let version = from_hex::<1>(parts[0])?[0];
+    // version ff is invalid, version 00 has exactly 4 fields, future versions may append fields
+    if version == 0xff || (version == 0 && parts.len() != 4) {
+        return None;
+    }
+    let trace_id = from_hex::<16>(parts[1])?;
+    let span_id = from_hex::<8>(parts[2])?;
+    let flags = from_hex::<1>(parts[3])?[0];
+    if trace_id.iter().all(|b| *b == 0) || span_id.iter().all(|b| *b == 0) {
+        return None;
+    }
+    Some((trace_id, span_id, flags))
+}
+
+
+fn new_span_id() -> [u8; 8] {
+    let mut span_id = [0u8; 8];
+    while span_id.iter().all(|b| *b == 0) {
+        rand::thread_rng().fill_bytes(&mut span_id);
+    }
+    span_id
+}
+
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+// lowercase hex only, as required by the spec
+fn from_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 || !s.bytes().all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c)) {
+        return None;
+    }
+    let mut out = [0u8; N];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
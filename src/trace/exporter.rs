@@ -0,0 +1,207 @@
+use hyper::{Body, Request, Client};
+use hyper::header;
+use hyper_rustls::HttpsConnector;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tracing::{event, Level};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use super::TraceContext;
+use super::context::to_hex;
+
+
+const EXPORT_BATCH_SIZE: usize = 512;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const EXPORT_QUEUE_SIZE: usize = 4096;
+
+
+lazy_static::lazy_static! {
+    static ref TRACER: RwLock<Option<Tracer>> = RwLock::new(None);
+}
+
+
+#[derive(Debug, Clone)]
+pub struct TraceSetting {
+    pub otlp_endpoint: String,   // OTLP/HTTP collector, e.g. http://127.0.0.1:4318/v1/traces
+    pub sample_ratio: f64,       // sampling ratio of traces started by gateway
+    pub service_name: String,
+}
+
+
+#[derive(Debug, Clone)]
+struct Tracer {
+    sample_ratio: f64,
+    spans: mpsc::Sender<SpanRecord>,
+}
+
+
+// start the exporter task, spans are dropped unless tracer is initialized
+pub fn init_tracer(setting: TraceSetting) {
+    let (tx, rx) = mpsc::channel(EXPORT_QUEUE_SIZE);
+    let tracer = Tracer {
+        sample_ratio: setting.sample_ratio.clamp(0.0, 1.0),
+        spans: tx,
+    };
+    *TRACER.write().unwrap() = Some(tracer);
+    tokio::spawn(async move {
+        event!(Level::INFO, "Start OTLP exporter {}", setting.otlp_endpoint);
+        export_spans(setting, rx).await
+    });
+}
+
+
+// spans are only recorded and propagated when an exporter is running
+pub fn tracer_enabled() -> bool {
+    TRACER.read().unwrap().is_some()
+}
+
+
+// parent based sampling, new traces are sampled by configured ratio
+pub fn should_sample(parent: Option<&TraceContext>) -> bool {
+    if let Some(parent) = parent {
+        return parent.sampled;
+    }
+    match TRACER.read().unwrap().as_ref() {
+        Some(tracer) => tracer.sample_ratio >= 1.0 || rand::random::<f64>() < tracer.sample_ratio,
+        None => false,
+    }
+}
+
+
+#[derive(Debug, Clone, Copy)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+
+#[derive(Debug, Clone)]
+pub struct SpanRecord {
+    name: String,
+    kind: SpanKind,
+    context: TraceContext,
+    start: SystemTime,
+    attributes: Vec<(String, Value)>,
+    error: bool,
+    end: Option<SystemTime>,
+}
+
+
+impl SpanRecord {
+
+    pub fn start(name: &str, kind: SpanKind, context: &TraceContext) -> Self {
+        SpanRecord {
+            name: String::from(name),
+            kind,
+            context: context.clone(),
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            error: false,
+            end: None,
+        }
+    }
+
+    pub fn attr<V: Into<Value>>(&mut self, key: &str, value: V) {
+        self.attributes.push((String::from(key), value.into()));
+    }
+
+    pub fn set_error(&mut self) {
+        self.error = true;
+    }
+
+    // queue span for export, unsampled spans and spans without tracer are dropped
+    pub fn finish(mut self) {
+        if !self.context.sampled {
+            return;
+        }
+        if let Some(tracer) = TRACER.read().unwrap().as_ref() {
+            self.end = Some(SystemTime::now());
+            if tracer.spans.try_send(self).is_err() {
+                event!(Level::WARN, "Trace export queue is full, span dropped");
+            }
+        }
+    }
+
+    fn to_otlp(&self) -> Value {
+        let nanos = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string();
+        let attributes: Vec<Value> = self.attributes.iter().map(|(k, v)| {
+            let value = match v {
+                Value::Bool(b) => json!({"boolValue": b}),
+                Value::Number(n) if n.is_i64() || n.is_u64() => json!({"intValue": n.to_string()}),
+                Value::Number(n) => json!({"doubleValue": n}),
+                Value::String(s) => json!({"stringValue": s}),
+                other => json!({"stringValue": other.to_string()}),
+            };
+            json!({"key": k, "value": value})
+        }).collect();
+        let mut span = json!({
+            "traceId": self.context.trace_id_hex(),
+            "spanId": self.context.span_id_hex(),
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": nanos(self.start),
+            "endTimeUnixNano": nanos(self.end.unwrap_or(self.start)),
+            "attributes": attributes,
+            "status": {"code": if self.error { 2 } else { 0 }},
+        });
+        if let Some(parent) = &self.context.parent_span_id {
+            span["parentSpanId"] = json!(to_hex(parent));
+        }
+        if let Some(state) = &self.context.tracestate {
+            span["traceState"] = json!(state);
+        }
+        span
+    }
+}
+
+
+async fn export_spans(setting: TraceSetting, mut rx: mpsc::Receiver<SpanRecord>) {
+    let client = Client::builder().build::<_, Body>(HttpsConnector::with_native_roots());
+    let mut batch: Vec<SpanRecord> = Vec::new();
+    let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+    loop {
+        let flush = tokio::select! {
+            span = rx.recv() => {
+                match span {
+                    Some(span) => {
+                        batch.push(span);
+                        batch.len() >= EXPORT_BATCH_SIZE
+                    },
+                    None => break,
+                }
+            },
+            _ = interval.tick() => !batch.is_empty(),
+        };
+        if flush {
+            let spans = std::mem::take(&mut batch);
+            let payload = otlp_payload(&setting.service_name, &spans);
+            let req = Request::post(&setting.otlp_endpoint)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(payload.to_string()))
+                .unwrap();
+            match client.request(req).await {
+                Ok(resp) if resp.status().is_success() => {},
+                Ok(resp) => event!(Level::WARN, "OTLP export failed: {}", resp.status()),
+                Err(e) => event!(Level::WARN, "OTLP export failed: {:?}", e),
+            }
+        }
+    }
+}
+
+
+// ExportTraceServiceRequest in OTLP/HTTP JSON encoding
+fn otlp_payload(service_name: &str, spans: &[SpanRecord]) -> Value {
+    let spans: Vec<Value> = spans.iter().map(|s| s.to_otlp()).collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": service_name}}],
+            },
+            "scopeSpans": [{
+                "scope": {"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")},
+                "spans": spans,
+            }],
+        }],
+    })
+}
@@ -0,0 +1,5 @@
+mod context;
+mod exporter;
+
+pub use context::{TraceContext, parse_traceparent, TRACEPARENT, TRACESTATE};
+pub use exporter::{TraceSetting, SpanRecord, SpanKind, init_tracer, should_sample, tracer_enabled};
@@ -2,11 +2,20 @@ import httpx
 import jwt
 from collections import defaultdict
 from datetime import datetime
-from mock_server import app, queue
+from mock_server import app, queue, traces, zone, dns_port
 import asyncio
+import base64
+import hashlib
+import hmac
+import json
+import os
 
 gateway_port = 54321
 mock_port = 54320
+backup_port = 54322
+trusted_proxy = "127.0.0.2"  # clients bound to this address are trusted proxies of the gateway
+access_log = "access.log"
+uds_path = "/tmp/hyperapi_test.sock"
 
 
 @app.get("/test1")
@@ -30,19 +39,50 @@ async def test_appkey_service():
         assert request_header.get('Authorization') is None
         queue.task_done()
 
+        # test cors
+        url = "/mws/api/user/hello"
+        preflight = {
+            'Origin': "https://app.example.com",
+            'Access-Control-Request-Method': "POST",
+            'Access-Control-Request-Headers': "X-APP-KEY",
+        }
+        resp = await ac.options(url, headers=preflight)  # no app key, answered by gateway
+        assert resp.status_code == 204
+        assert resp.headers.get('access-control-allow-origin') == 'https://app.example.com'
+        assert resp.headers.get('access-control-max-age') == '600'
+        assert queue.empty()
+        preflight['Origin'] = "https://evil.example.com"
+        resp = await ac.options(url, headers=preflight)
+        assert resp.status_code == 403
+        resp = await ac.get(url, headers={**headers, 'Origin': "https://api.example.org"})
+        assert resp.status_code == 200
+        assert resp.headers.get('access-control-allow-origin') == 'https://api.example.org'
+        assert resp.headers.get('access-control-allow-credentials') == 'true'
+        await queue.get()
+        queue.task_done()
+        print("auth errors carry CORS headers")
+        resp = await ac.get(url, headers={'Origin': "https://app.example.com"})
+        assert resp.status_code == 502
+        assert resp.headers.get('access-control-allow-origin') == 'https://app.example.com'
+        assert queue.empty()
+
         # test acl
         url = "/mws/api/not-found"
         resp = await ac.get(url, headers=headers)
         assert resp.status_code == 404
         assert queue.empty()  # no request received, blocked by gateway
+        resp = await ac.get(url, headers={**headers, 'Origin': "https://app.example.com"})
+        assert resp.status_code == 404
+        assert resp.headers.get('access-control-allow-origin') == 'https://app.example.com'
 
         # test rate limit 
         url = "/mws/error/200"
         print("drain token bucket")
         for i in range(10):
             resp = await ac.get(url, headers=headers)
-        resp = await ac.get(url, headers=headers)
+        resp = await ac.get(url, headers={**headers, 'Origin': "https://app.example.com"})
         assert resp.status_code == 429
+        assert resp.headers.get('access-control-allow-origin') == 'https://app.example.com'
         print("wait token refill")
         await asyncio.sleep(3)
         for i in range(5):
@@ -192,6 +232,554 @@ async def test_load_balance():
     return {"result": "Pass"}
 
 
+@app.get("/test4")
+async def test_cache():
+    print("=============TESTING CACHE=========================")
+    headers = {
+        'X-APP-KEY': "9cf3319cbd254202cf882a79a755ba6e",
+    }
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}") as ac:
+        url = "/cache/cached/2"
+        resp = await ac.get(url, headers=headers)
+        assert resp.headers.get('x-cache') == 'MISS'
+        await queue.get()
+        queue.task_done()
+        resp = await ac.get(url, headers=headers)
+        assert resp.headers.get('x-cache') == 'HIT'
+        assert queue.empty()  # served by gateway
+
+        print('wait entry stale, served while revalidating')
+        await asyncio.sleep(2.5)
+        resp = await ac.get(url, headers=headers)
+        assert resp.headers.get('x-cache') == 'STALE'
+        await queue.get()
+        queue.task_done()
+        await asyncio.sleep(0.5)
+        resp = await ac.get(url, headers=headers)
+        assert resp.headers.get('x-cache') == 'HIT'
+
+        resp = await ac.get(url, headers={**headers, 'Cache-Control': 'no-cache'})
+        assert resp.headers.get('x-cache') == 'MISS'
+        await queue.get()
+        queue.task_done()
+
+        print('responses setting cookies are not stored')
+        for i in range(2):
+            resp = await ac.get("/cache/session/60", headers=headers)
+            assert resp.headers.get('x-cache') == 'BYPASS'
+            assert resp.headers.get('set-cookie') == 'session=client-a'
+            await queue.get()
+            queue.task_done()
+
+        print('responses to credentialed requests are not shared unless marked public')
+        for i in range(2):
+            resp = await ac.get("/cache/private/60", headers=headers)
+            assert resp.headers.get('x-cache') == 'BYPASS'
+            await queue.get()
+            queue.task_done()
+
+    return {"result": "Pass"}
+
+
+@app.get("/test5")
+async def test_trace_context():
+    print("=============TESTING TRACE CONTEXT=========================")
+    trace_id = "4bf92f3577b34da6a3ce929d0e0e4736"
+    headers = {
+        'X-APP-KEY': "9cf3319cbd254202cf882a79a755ba6e",
+        'traceparent': f"00-{trace_id}-00f067aa0ba902b7-01",
+        'tracestate': "vendor=value",
+    }
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}") as ac:
+        resp = await ac.get("/mws/api/user/hello", headers=headers)
+        assert resp.status_code == 200
+        received = await queue.get()
+        traceparent = received.headers.get('traceparent')
+        assert traceparent.startswith(f"00-{trace_id}-")
+        assert traceparent != headers['traceparent']  # upstream call is a child span
+        assert received.headers.get('tracestate') == "vendor=value"
+        queue.task_done()
+
+    print('wait spans exported to collector')
+    spans = {}  # spans[(name, kind)] = span
+    while not {('auth', 1), ('HTTP GET', 2), ('HTTP GET', 3)}.issubset(spans):
+        span = await asyncio.wait_for(traces.get(), timeout=10)
+        if span['traceId'] == trace_id:
+            spans[(span['name'], span['kind'])] = span
+    server, client = spans[('HTTP GET', 2)], spans[('HTTP GET', 3)]
+    assert server['parentSpanId'] == "00f067aa0ba902b7"
+    assert spans[('auth', 1)]['parentSpanId'] == server['spanId']
+    assert client['parentSpanId'] == server['spanId']
+    assert traceparent.split('-')[2] == client['spanId']
+    return {"result": "Pass"}
+
+
+@app.get("/test6")
+async def test_dns_discovery():
+    print("=============TESTING DNS DISCOVERY=========================")
+    headers = {
+        'X-APP-KEY': "9cf3319cbd254202cf882a79a755ba6e",
+    }
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}") as ac:
+        print('------------test A records------------')
+        resp = await ac.get("/dns/error/200", headers=headers)
+        assert resp.status_code == 200
+        assert resp.headers.get('x-upstream-id') == '61@127.0.0.1'
+
+        print('------------test SRV records------------')
+        url = "/dns_srv/error/200"
+        counter = defaultdict(int)
+        for i in range(200):
+            resp = await ac.get(url, headers=headers)
+            assert resp.status_code == 200
+            counter[resp.headers.get('x-upstream-id')] += 1
+        print(counter)
+        print("lowest priority targets only, load distribution should be roughly 3:1")
+        assert (counter['62@a.api.test:54320'] + counter['62@b.api.test:54320']) == 200
+        assert 1.5 < (counter['62@a.api.test:54320'] / counter['62@b.api.test:54320']) < 6
+
+        print('------------test record changes------------')
+        zone['_http._tcp.api.test'] = [('SRV', (1, 10, 54320, 'b.api.test'))]
+        await asyncio.sleep(3)
+        counter = defaultdict(int)
+        for i in range(20):
+            resp = await ac.get(url, headers=headers)
+            assert resp.status_code == 200
+            counter[resp.headers.get('x-upstream-id')] += 1
+        assert counter['62@b.api.test:54320'] == 20
+
+        print('------------test lookup failure keeps endpoints------------')
+        del zone['_http._tcp.api.test']
+        await asyncio.sleep(3)
+        resp = await ac.get(url, headers=headers)
+        assert resp.status_code == 200
+        assert resp.headers.get('x-upstream-id') == '62@b.api.test:54320'
+
+    return {"result": "Pass"}
+
+
+@app.get("/test7")
+async def test_file_discovery():
+    print("=============TESTING FILE DISCOVERY=========================")
+    headers = {
+        'X-APP-KEY': "9cf3319cbd254202cf882a79a755ba6e",
+    }
+    endpoints_file = "endpoints.json"  # gateway runs in tests directory
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}") as ac:
+        try:
+            print('------------test endpoints from file------------')
+            with open(endpoints_file, 'w') as f:
+                json.dump([{"address": "127.0.0.1:54320", "weight": 100}, {"address": "localhost:54320", "weight": 10}], f)
+            await asyncio.sleep(2)
+            url = "/file/error/200"
+            counter = defaultdict(int)
+            for i in range(200):
+                resp = await ac.get(url, headers=headers)
+                assert resp.status_code == 200
+                counter[resp.headers.get('x-upstream-id')] += 1
+            print(counter)
+            print("load distribution should be roughly 10:1")
+            assert (counter['71@127.0.0.1:54320'] + counter['71@localhost:54320']) == 200
+            assert 5 < (counter['71@127.0.0.1:54320'] / counter['71@localhost:54320']) < 20
+
+            print('------------test file changes------------')
+            with open(endpoints_file, 'w') as f:
+                f.write("- address: localhost:54320\n")
+            await asyncio.sleep(2)
+            for i in range(20):
+                resp = await ac.get(url, headers=headers)
+                assert resp.headers.get('x-upstream-id') == '71@localhost:54320'
+
+            print('------------test invalid file keeps endpoints------------')
+            with open(endpoints_file, 'w') as f:
+                f.write("[{")
+            await asyncio.sleep(2)
+            resp = await ac.get(url, headers=headers)
+            assert resp.status_code == 200
+            assert resp.headers.get('x-upstream-id') == '71@localhost:54320'
+        finally:
+            os.remove(endpoints_file)
+
+    return {"result": "Pass"}
+
+
+@app.get("/test8")
+async def test_outlier_detection():
+    print("=============TESTING OUTLIER DETECTION=========================")
+    headers = {
+        'X-APP-KEY': "9cf3319cbd254202cf882a79a755ba6e",
+    }
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}") as ac:
+        url = "/outlier/error/200"
+        counter = defaultdict(int)
+        for i in range(50):
+            resp = await ac.get(url, headers=headers)
+            counter[resp.status_code] += 1
+        print(counter)
+        print("unreachable upstream is ejected after 3 consecutive failures")
+        assert counter[502] <= 3
+        for i in range(20):
+            resp = await ac.get(url, headers=headers)
+            assert resp.status_code == 200
+            assert resp.headers.get('x-upstream-id') == '81'
+
+    return {"result": "Pass"}
+
+
+@app.get("/test9")
+async def test_slow_start():
+    print("=============TESTING SLOW START=========================")
+    headers = {
+        'X-APP-KEY': "9cf3319cbd254202cf882a79a755ba6e",
+    }
+    endpoints_file = "slow_start_endpoints.json"
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}") as ac:
+        try:
+            with open(endpoints_file, 'w') as f:
+                json.dump([{"address": "127.0.0.1:54320"}], f)
+            await asyncio.sleep(2)
+            with open(endpoints_file, 'w') as f:
+                json.dump([{"address": "127.0.0.1:54320"}, {"address": "localhost:54320"}], f)
+            await asyncio.sleep(2)
+            url = "/slow_start/error/200"
+            counter = defaultdict(int)
+            for i in range(200):
+                resp = await ac.get(url, headers=headers)
+                assert resp.status_code == 200
+                counter[resp.headers.get('x-upstream-id')] += 1
+            print(counter)
+            print("added upstream gets much less than half of the load while warming up")
+            assert (counter['91@127.0.0.1:54320'] + counter['91@localhost:54320']) == 200
+            assert counter['91@localhost:54320'] < 60
+        finally:
+            os.remove(endpoints_file)
+
+    return {"result": "Pass"}
+
+
+@app.get("/test10")
+async def test_priority_failover():
+    print("=============TESTING PRIORITY FAILOVER=========================")
+    headers = {
+        'X-APP-KEY': "9cf3319cbd254202cf882a79a755ba6e",
+    }
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}") as ac:
+        url = "/priority/error/200"
+        print("backup upstream is idle while primary upstreams are healthy")
+        for i in range(50):
+            resp = await ac.get(url, headers=headers)
+            if resp.status_code != 200:
+                break
+            assert resp.headers.get('x-upstream-id') == '101'
+        print("half of primary weight is unhealthy after breaker of 102 opens, backup takes traffic")
+        counter = defaultdict(int)
+        for i in range(20):
+            resp = await ac.get(url, headers=headers)
+            assert resp.status_code == 200
+            counter[resp.headers.get('x-upstream-id')] += 1
+        print(counter)
+        assert counter['103'] == 20
+
+    return {"result": "Pass"}
+
+
+@app.get("/test11")
+async def test_hedged_requests():
+    print("=============TESTING HEDGED REQUESTS=========================")
+    headers = {
+        'X-APP-KEY': "9cf3319cbd254202cf882a79a755ba6e",
+    }
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}") as ac:
+        print("GET requests to the slow upstream are answered by the other one after hedge delay")
+        for i in range(20):
+            start = datetime.now().timestamp()
+            resp = await ac.get("/hedge", headers=headers)
+            assert resp.status_code == 200
+            assert resp.headers.get('x-upstream-id') == '112'
+            assert datetime.now().timestamp() - start < 0.8
+        print("POST requests are not hedged")
+        counter = defaultdict(int)
+        for i in range(10):
+            resp = await ac.post("/hedge", headers=headers, content=b"{}")
+            assert resp.status_code == 200
+            counter[resp.headers.get('x-upstream-id')] += 1
+        print(counter)
+        assert counter['111'] > 0
+
+    return {"result": "Pass"}
+
+
+@app.get("/test12")
+async def test_unix_socket_upstream():
+    print("=============TESTING UNIX SOCKET UPSTREAM=========================")
+    headers = {
+        'X-APP-KEY': "9cf3319cbd254202cf882a79a755ba6e",
+    }
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}") as ac:
+        print("requests are sent through the socket, path is appended to the target prefix")
+        for code in [200, 404, 200]:
+            resp = await ac.get(f"/uds/{code}", headers=headers)
+            assert resp.status_code == code
+            assert resp.headers.get('x-upstream-id') == '121'
+
+    return {"result": "Pass"}
+
+
+@app.get("/test13")
+async def test_forwarding_headers():
+    print("=============TESTING FORWARDING HEADERS=========================")
+    headers = {
+        'X-APP-KEY': "9cf3319cbd254202cf882a79a755ba6e",
+        'X-Forwarded-For': "203.0.113.7",
+        'X-Forwarded-Proto': "https",
+        'Forwarded': "for=203.0.113.7;proto=https",
+    }
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}") as ac:
+        print("forwarding headers of untrusted clients are replaced")
+        resp = await ac.get("/mws/api/user/forward", headers=headers)
+        assert resp.status_code == 200
+        received = (await queue.get()).headers
+        queue.task_done()
+        assert received.get('x-forwarded-for') == "127.0.0.1"
+        assert received.get('x-forwarded-proto') == "http"
+        assert received.get('x-forwarded-host') == f"localhost:{gateway_port}"
+        assert received.get('forwarded') == f'for=127.0.0.1;proto=http;host="localhost:{gateway_port}"'
+        print("Host of client is sent to upstream by default")
+        assert received.get('host') == f"localhost:{gateway_port}"
+
+        print("upstream with rewrite_host gets its target authority as Host")
+        resp = await ac.get("/forward/api/host", headers=headers)
+        assert resp.status_code == 200
+        received = (await queue.get()).headers
+        queue.task_done()
+        assert received.get('host') == f"127.0.0.1:{mock_port}"
+        assert received.get('x-forwarded-host') == f"localhost:{gateway_port}"
+
+    transport = httpx.AsyncHTTPTransport(local_address=trusted_proxy)
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}", transport=transport) as ac:
+        print("trusted proxies append to forwarding headers")
+        resp = await ac.get("/forward/api/trusted", headers=headers)
+        assert resp.status_code == 200
+        received = (await queue.get()).headers
+        queue.task_done()
+        assert received.get('x-forwarded-for') == f"203.0.113.7, {trusted_proxy}"
+        assert received.get('x-forwarded-proto') == "https"
+        assert received.get('forwarded') == f'for=203.0.113.7;proto=https, for={trusted_proxy};proto=http;host="localhost:{gateway_port}"'
+
+    return {"result": "Pass"}
+
+
+@app.get("/test14")
+async def test_request_id():
+    print("=============TESTING REQUEST ID=========================")
+    incoming = "0f8fad5b-d9cb-469f-a165-70867728950e"
+    headers = {
+        'X-APP-KEY': "9cf3319cbd254202cf882a79a755ba6e",
+        'X-Request-ID': incoming,
+    }
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}") as ac:
+        print("request id of untrusted clients is replaced")
+        resp = await ac.get("/forward/api/request-id", headers=headers)
+        assert resp.status_code == 200
+        generated = resp.headers.get('x-request-id')
+        assert generated and generated != incoming
+        received = (await queue.get()).headers
+        queue.task_done()
+        assert received.get('x-request-id') == generated
+
+        print("request id is echoed in error body")
+        resp = await ac.get("/not-a-service/api/request-id", headers=headers)
+        assert resp.status_code >= 400
+        request_id = resp.headers.get('x-request-id')
+        assert request_id and request_id != incoming
+        assert resp.text.endswith(f"(request id: {request_id})")
+
+    transport = httpx.AsyncHTTPTransport(local_address=trusted_proxy)
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}", transport=transport) as ac:
+        print("request id from trusted proxies is kept")
+        resp = await ac.get("/forward/api/request-id", headers=headers)
+        assert resp.status_code == 200
+        assert resp.headers.get('x-request-id') == incoming
+        received = (await queue.get()).headers
+        queue.task_done()
+        assert received.get('x-request-id') == incoming
+
+        print("malformed request id from trusted proxies is replaced")
+        resp = await ac.get("/forward/api/request-id", headers={**headers, 'X-Request-ID': "not-a-uuid"})
+        assert resp.status_code == 200
+        assert resp.headers.get('x-request-id') != "not-a-uuid"
+        await queue.get()
+        queue.task_done()
+
+        print("error body echoes request id of trusted proxies")
+        resp = await ac.get("/not-a-service/api/request-id", headers=headers)
+        assert resp.status_code >= 400
+        assert resp.headers.get('x-request-id') == incoming
+        assert resp.text.endswith(f"(request id: {incoming})")
+
+    return {"result": "Pass"}
+
+
+@app.get("/test15")
+async def test_access_log_errors():
+    print("=============TESTING ACCESS LOG OF ERRORS=========================")
+    headers = {
+        'X-APP-KEY': "9cf3319cbd254202cf882a79a755ba6e",
+    }
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}") as ac:
+        resp = await ac.get("/access_log/api/upstream-down", headers=headers)
+        assert resp.status_code == 502
+        upstream_error = resp.headers.get('x-request-id')
+        resp = await ac.get("/access_log/api/limited", headers=headers)
+        assert resp.status_code == 429
+        rate_limited = resp.headers.get('x-request-id')
+        resp = await ac.get("/not-a-service/api/auth", headers=headers)
+        assert resp.status_code == 502
+        auth_error = resp.headers.get('x-request-id')
+
+    await asyncio.sleep(1)  # access log is written by background worker
+    with open(access_log) as f:
+        entries = {e['request_id']: e for e in map(json.loads, f)}
+    print("gateway errors are logged with status and service")
+    assert entries[upstream_error]['status'] == 502
+    assert entries[upstream_error]['service'] == "test/access_log"
+    assert entries[upstream_error]['client_id'] == "test/client"
+    assert entries[upstream_error]['path'] == "/access_log/api/upstream-down"
+    assert entries[rate_limited]['status'] == 429
+    assert entries[rate_limited]['service'] == "test/access_log"
+    assert entries[auth_error]['status'] == 502
+    assert entries[auth_error]['service'] == ""
+
+    return {"result": "Pass"}
+
+
+@app.get("/test16")
+async def test_metrics_path_labels():
+    print("=============TESTING METRICS PATH LABELS=========================")
+    headers = {
+        'X-APP-KEY': "9cf3319cbd254202cf882a79a755ba6e",
+    }
+    paths = {
+        "/api/users/42/orders": "/api/users/{id}/orders",
+        "/api/users/43/orders": "/api/users/{id}/orders",
+        "/api/files/2021/report.txt": "/api/files/*",
+        "/api/items/123456": "/api/items/{id}",
+        "/api/items/0f8fad5b-d9cb-469f-a165-70867728950e/detail": "/api/items/{id}/detail",
+    }
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}") as ac:
+        for path in paths:
+            resp = await ac.get(f"/forward{path}", headers=headers)
+            assert resp.status_code == 200
+            await queue.get()
+            queue.task_done()
+
+        resp = await ac.get("/metrics/", headers=headers)
+        assert resp.status_code == 200
+        labels = set()
+        for line in resp.text.splitlines():
+            if line.startswith("gateway_requests_total{") and 'service="test/forward"' in line:
+                labels.add(line.split('path="')[1].split('"')[0])
+        print("request paths are collapsed to templates")
+        for label in paths.values():
+            assert label in labels
+        assert not any("42" in l or "123456" in l for l in labels)
+
+    return {"result": "Pass"}
+
+
+@app.get("/test17")
+async def test_hash_ring_stability():
+    print("=============TESTING HASH RING STABILITY=========================")
+    headers = {
+        'X-APP-KEY': "9cf3319cbd254202cf882a79a755ba6e",
+    }
+    endpoints_file = "hash_endpoints.json"  # gateway runs in tests directory
+    nodes = [f"127.0.0.1:{mock_port}", f"localhost:{mock_port}", f"127.0.0.1:{backup_port}", f"localhost:{backup_port}"]
+    keys = [f"user-{i}" for i in range(100)]
+
+    async def placement(ac, endpoints):
+        with open(endpoints_file, 'w') as f:
+            json.dump([{"address": n, "weight": 100} for n in endpoints], f)
+        await asyncio.sleep(2)
+        result = {}
+        for key in keys:
+            resp = await ac.get("/hash_ring/error/200", headers={**headers, 'x-lb-hash': key})
+            assert resp.status_code == 200
+            result[key] = resp.headers.get('x-upstream-id')
+        return result
+
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}") as ac:
+        try:
+            before = await placement(ac, nodes[:3])
+            assert set(before.values()) == {f"161@{n}" for n in nodes[:3]}
+
+            print("adding a node only moves keys to the new node")
+            added = await placement(ac, nodes)
+            moved = [k for k in keys if added[k] != before[k]]
+            assert moved
+            assert all(added[k] == f"161@{nodes[3]}" for k in moved)
+
+            print("removing a node only moves keys of the removed node")
+            removed_node = f"161@{nodes[1]}"
+            removed = await placement(ac, [n for n in nodes if n != nodes[1]])
+            for k in keys:
+                if added[k] == removed_node:
+                    assert removed[k] != removed_node
+                else:
+                    assert removed[k] == added[k]
+        finally:
+            os.remove(endpoints_file)
+
+    return {"result": "Pass"}
+
+
+def sticky_cookie(service_id, upstream_id, secret):
+    encode = lambda b: base64.urlsafe_b64encode(b).rstrip(b'=').decode()
+    tag = hmac.new(secret.encode(), f"{service_id}|{upstream_id}".encode(), hashlib.sha256).digest()
+    return f"{encode(upstream_id.encode())}.{encode(tag)}"
+
+
+@app.get("/test18")
+async def test_sticky_cookie():
+    print("=============TESTING STICKY COOKIE=========================")
+    headers = {
+        'X-APP-KEY': "9cf3319cbd254202cf882a79a755ba6e",
+    }
+    url = "/sticky/error/200"
+    sign = lambda upstream_id: sticky_cookie("test/sticky", upstream_id, "sticky-test-secret")
+    async with httpx.AsyncClient(base_url=f"http://localhost:{gateway_port}") as ac:
+        print("signed cookie pins the upstream, even a failing one")
+        resp = await ac.get(url, headers={**headers, 'Cookie': f"srv={sign('173')}"})
+        assert resp.status_code == 502
+
+        print("pinned upstream with open breaker falls back and cookie is replaced")
+        resp = await ac.get(url, headers={**headers, 'Cookie': f"srv={sign('173')}"})
+        assert resp.status_code == 200
+        upstream = resp.headers.get('x-upstream-id')
+        assert upstream in ('171', '172')
+        set_cookie = resp.headers.get('set-cookie')
+        assert set_cookie.startswith(f"srv={sign(upstream)}; Path=/sticky")
+
+        print("requests with cookie stay on the upstream")
+        for i in range(10):
+            resp = await ac.get(url, headers={**headers, 'Cookie': f"srv={sign(upstream)}"})
+            assert resp.status_code == 200
+            assert resp.headers.get('x-upstream-id') == upstream
+            assert resp.headers.get('set-cookie') is None
+
+        print("tampered cookies are ignored")
+        other = '172' if upstream == '171' else '171'
+        forged_id = base64.urlsafe_b64encode(other.encode()).rstrip(b'=').decode()
+        tag = sign(upstream).split('.')[1]
+        wrong_secret = sticky_cookie("test/sticky", other, "guessed-secret")
+        for value in (f"{forged_id}.{tag}", wrong_secret, sticky_cookie("test/hash_ring", other, "sticky-test-secret")):
+            resp = await ac.get(url, headers={**headers, 'Cookie': f"srv={value}"})
+            assert resp.status_code == 200
+            assert resp.headers.get('set-cookie') is not None
+
+    return {"result": "Pass"}
+
+
 async def runner(ac, url, headers, counts):
     counter = defaultdict(list)
     for i in range(counts):
@@ -207,8 +795,14 @@ def run_test():
     import subprocess
     import time
 
-    gateway = subprocess.Popen(["../target/debug/hyperapi", "--listen", f"127.0.0.1:{gateway_port}", "--config", "sample_config.yaml"])
+    gateway = subprocess.Popen(["../target/debug/hyperapi", "--listen", f"127.0.0.1:{gateway_port}", "--config", "sample_config.yaml",
+                                "--otlp_endpoint", f"http://127.0.0.1:{mock_port}/v1/traces",
+                                "--dns_server", f"127.0.0.1:{dns_port}",
+                                "--trusted_proxies", f"{trusted_proxy}/32",
+                                "--access_log", access_log, "--access_log_rotation", "never"])
     fastapi = subprocess.Popen(["uvicorn", "--port", f"{mock_port}", "gateway_test:app"])
+    backup = subprocess.Popen(["uvicorn", "--port", f"{backup_port}", "mock_server:backup_app"])
+    uds = subprocess.Popen(["uvicorn", "--uds", uds_path, "mock_server:backup_app"]) if os.name != 'nt' else None
     time.sleep(3)
     
     try:
@@ -223,9 +817,73 @@ def run_test():
         print("request test endpoint, load balance test, appkey auth")
         resp = httpx.get(f"http://localhost:{mock_port}/test3", timeout=None)
         assert resp.status_code == 200
+
+        print("request test endpoint, cache test, appkey auth")
+        resp = httpx.get(f"http://localhost:{mock_port}/test4", timeout=None)
+        assert resp.status_code == 200
+
+        print("request test endpoint, trace context test, appkey auth")
+        resp = httpx.get(f"http://localhost:{mock_port}/test5", timeout=None)
+        assert resp.status_code == 200
+
+        print("request test endpoint, dns discovery test, appkey auth")
+        resp = httpx.get(f"http://localhost:{mock_port}/test6", timeout=None)
+        assert resp.status_code == 200
+
+        print("request test endpoint, file discovery test, appkey auth")
+        resp = httpx.get(f"http://localhost:{mock_port}/test7", timeout=None)
+        assert resp.status_code == 200
+
+        print("request test endpoint, outlier detection test, appkey auth")
+        resp = httpx.get(f"http://localhost:{mock_port}/test8", timeout=None)
+        assert resp.status_code == 200
+
+        print("request test endpoint, slow start test, appkey auth")
+        resp = httpx.get(f"http://localhost:{mock_port}/test9", timeout=None)
+        assert resp.status_code == 200
+
+        print("request test endpoint, priority failover test, appkey auth")
+        resp = httpx.get(f"http://localhost:{mock_port}/test10", timeout=None)
+        assert resp.status_code == 200
+
+        print("request test endpoint, hedged requests test, appkey auth")
+        resp = httpx.get(f"http://localhost:{mock_port}/test11", timeout=None)
+        assert resp.status_code == 200
+
+        print("request test endpoint, forwarding headers test, appkey auth")
+        resp = httpx.get(f"http://localhost:{mock_port}/test13", timeout=None)
+        assert resp.status_code == 200
+
+        print("request test endpoint, request id test, appkey auth")
+        resp = httpx.get(f"http://localhost:{mock_port}/test14", timeout=None)
+        assert resp.status_code == 200
+
+        print("request test endpoint, access log test, appkey auth")
+        resp = httpx.get(f"http://localhost:{mock_port}/test15", timeout=None)
+        assert resp.status_code == 200
+
+        print("request test endpoint, metrics path labels test, appkey auth")
+        resp = httpx.get(f"http://localhost:{mock_port}/test16", timeout=None)
+        assert resp.status_code == 200
+
+        print("request test endpoint, hash ring stability test, appkey auth")
+        resp = httpx.get(f"http://localhost:{mock_port}/test17", timeout=None)
+        assert resp.status_code == 200
+
+        print("request test endpoint, sticky cookie test, appkey auth")
+        resp = httpx.get(f"http://localhost:{mock_port}/test18", timeout=None)
+        assert resp.status_code == 200
+
+        if uds:
+            print("request test endpoint, unix socket upstream test, appkey auth")
+            resp = httpx.get(f"http://localhost:{mock_port}/test12", timeout=None)
+            assert resp.status_code == 200
     finally:
         gateway.kill()
         fastapi.kill()
+        backup.kill()
+        if uds:
+            uds.kill()
 
 
 if __name__ == '__main__':
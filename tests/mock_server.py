@@ -3,9 +3,22 @@ from asyncio import Queue
 import asyncio
 import json
 import random
+import socket
+import struct
 
 app = FastAPI(debug=True)
 queue = Queue(maxsize=10)
+traces = Queue()
+dns_port = 54353
+# resolver stand-in records, name -> [(type, value)], changed by tests to simulate DNS updates
+zone = {
+    'api.test': [('A', '127.0.0.1')],
+    '_http._tcp.api.test': [('SRV', (1, 30, 54320, 'a.api.test')), ('SRV', (1, 10, 54320, 'b.api.test')),
+                            ('SRV', (2, 10, 54320, 'c.api.test'))],
+    'a.api.test': [('A', '127.0.0.1')],
+    'b.api.test': [('A', '127.0.0.1')],
+    'c.api.test': [('A', '127.0.0.1')],
+}
 
 
 # @app.exception_handler(AssertionError)
@@ -29,14 +42,88 @@ async def error_endpoint(req: Request, code: int=Path(default=500)):
     return Response(status_code=int(code))
 
 
+# second upstream, served on a port and a unix socket, without the DNS stub of the main app
+backup_app = FastAPI()
+backup_app.add_api_route("/error/{code}", error_endpoint, methods=['POST', 'GET', 'PUT', 'DELETE'])
+
+
 @app.api_route("/timeout/{seconds}", methods=['POST', 'GET', 'PUT', 'DELETE'])
 async def timeout_endpoint(req: Request, seconds: float=Path(default=1.0)):
     await asyncio.sleep(seconds)
     return {"sleep": seconds}
 
 
+@app.api_route("/cached/{seconds}", methods=['GET'])
+async def cached_endpoint(req: Request, seconds: int=Path(default=1)):
+    queue.put_nowait(req)
+    return Response(content=json.dumps({"max-age": seconds}),
+                    headers={"Cache-Control": f"public, max-age={seconds}"})
+
+
+@app.api_route("/private/{seconds}", methods=['GET'])
+async def private_endpoint(req: Request, seconds: int=Path(default=1)):
+    queue.put_nowait(req)
+    return Response(content=json.dumps({"max-age": seconds}),
+                    headers={"Cache-Control": f"max-age={seconds}"})
+
+
+@app.api_route("/session/{seconds}", methods=['GET'])
+async def session_endpoint(req: Request, seconds: int=Path(default=1)):
+    queue.put_nowait(req)
+    return Response(content=json.dumps({"max-age": seconds}),
+                    headers={"Cache-Control": f"max-age={seconds}", "Set-Cookie": "session=client-a"})
+
+
 @app.api_route("/random/{seconds}", methods=['POST', 'GET', 'PUT', 'DELETE'])
 async def random_delay_endpoint(req: Request, seconds: float=Path(default=1.0)):
     delay = random.random() * seconds
     await asyncio.sleep(delay)
     return {"sleep": delay}
+
+
+@app.post("/v1/traces")
+async def otlp_collector(req: Request):
+    # OTLP/HTTP JSON collector stub, exported spans are verified on the other side
+    payload = await req.json()
+    for resource_spans in payload['resourceSpans']:
+        for scope_spans in resource_spans['scopeSpans']:
+            for span in scope_spans['spans']:
+                traces.put_nowait(span)
+    return {}
+
+
+
+class DnsStub(asyncio.DatagramProtocol):
+    # answers A and SRV questions from zone, NXDOMAIN for unknown names
+
+    def connection_made(self, transport):
+        self.transport = transport
+
+    def datagram_received(self, data, addr):
+        i, labels = 12, []
+        while data[i]:
+            labels.append(data[i + 1:i + 1 + data[i]].decode())
+            i += data[i] + 1
+        name = '.'.join(labels).lower()
+        qtype, = struct.unpack('>H', data[i + 1:i + 3])
+        answers = []
+        for rtype, value in zone.get(name, []):
+            if rtype == 'A' and qtype == 1:
+                answers.append((1, socket.inet_aton(value)))
+            elif rtype == 'SRV' and qtype == 33:
+                priority, weight, port, target = value
+                rdata = struct.pack('>HHH', priority, weight, port)
+                rdata += b''.join(bytes([len(l)]) + l.encode() for l in target.split('.')) + b'\0'
+                answers.append((33, rdata))
+        rcode = 0 if name in zone else 3
+        resp = struct.pack('>HHHHHH', struct.unpack('>H', data[:2])[0], 0x8180 | rcode, 1, len(answers), 0, 0)
+        resp += data[12:i + 5]
+        for rtype, rdata in answers:
+            resp += b'\xc0\x0c' + struct.pack('>HHIH', rtype, 1, 1, len(rdata)) + rdata
+        self.transport.sendto(resp, addr)
+
+
+@app.on_event("startup")
+async def start_dns_stub():
+    loop = asyncio.get_running_loop()
+    await loop.create_datagram_endpoint(DnsStub, local_addr=('127.0.0.1', dns_port))